@@ -0,0 +1,137 @@
+//! A flow step must cost the same whatever the size of the shared state: flows hand it to their
+//! nodes by reference. The state used here has no serde impls, so it can't be serialized at all.
+//! Synchronous nodes in an `AsyncFlow` included ("AsyncFlow of Nodes").
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use orichalcum::core::Executable;
+use orichalcum::core::async_impl::async_flow::AsyncFlow;
+use orichalcum::core::async_impl::async_node::{AsyncNode, AsyncNodeLogic};
+use orichalcum::core::error::NodeError;
+use orichalcum::core::sync_impl::NodeValue;
+use orichalcum::core::sync_impl::flow::Flow;
+use orichalcum::core::sync_impl::node::{Node, NodeLogic};
+use std::collections::HashMap;
+use std::hint::black_box;
+
+const STEPS: usize = 10;
+
+#[derive(Clone)]
+struct Corpus {
+    embeddings: Vec<f32>,
+    visited: usize,
+}
+
+impl Corpus {
+    fn with_megabytes(megabytes: usize) -> Self {
+        Corpus {
+            embeddings: vec![0.5; megabytes * 1024 * 1024 / size_of::<f32>()],
+            visited: 0,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Visit;
+
+impl NodeLogic<Corpus> for Visit {
+    fn post(
+        &self,
+        shared: &mut Corpus,
+        _prep_res: NodeValue,
+        _exec_res: NodeValue,
+    ) -> Result<Option<String>, NodeError> {
+        shared.visited += 1;
+        Ok(None)
+    }
+
+    fn clone_box(&self) -> Box<dyn NodeLogic<Corpus>> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Clone)]
+struct AsyncVisit;
+
+#[async_trait::async_trait]
+impl AsyncNodeLogic<Corpus> for AsyncVisit {
+    async fn prep(
+        &self,
+        _params: &HashMap<String, NodeValue>,
+        _shared: &Corpus,
+    ) -> Result<NodeValue, NodeError> {
+        Ok(NodeValue::Null)
+    }
+
+    async fn exec(&self, input: NodeValue) -> Result<NodeValue, NodeError> {
+        Ok(input)
+    }
+
+    async fn post(
+        &self,
+        shared: &mut Corpus,
+        _prep_res: NodeValue,
+        _exec_res: NodeValue,
+    ) -> Result<Option<String>, NodeError> {
+        shared.visited += 1;
+        Ok(None)
+    }
+
+    fn clone_box(&self) -> Box<dyn AsyncNodeLogic<Corpus>> {
+        Box::new(self.clone())
+    }
+}
+
+fn sync_flow() -> Flow<Corpus> {
+    let chain = (1..STEPS).fold(Node::new(Visit), |next, _| {
+        Node::new(Visit).next(Executable::Sync(next))
+    });
+    Flow::new(chain)
+}
+
+fn async_flow() -> AsyncFlow<Corpus> {
+    let chain = (1..STEPS).fold(AsyncNode::new(AsyncVisit), |next, _| {
+        AsyncNode::new(AsyncVisit).next(Executable::Async(next))
+    });
+    AsyncFlow::new(Executable::Async(chain))
+}
+
+fn async_flow_of_sync_nodes() -> AsyncFlow<Corpus> {
+    let chain = (1..STEPS).fold(Node::new(Visit), |next, _| {
+        Node::new(Visit).next(Executable::Sync(next))
+    });
+    AsyncFlow::new(Executable::Sync(chain))
+}
+
+fn flow_steps(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("failed to build the runtime");
+    let flow = sync_flow();
+    let async_flow = async_flow();
+    let async_flow_of_sync_nodes = async_flow_of_sync_nodes();
+
+    let mut group = c.benchmark_group("flow_steps");
+    for megabytes in [0, 8, 64] {
+        let mut shared = Corpus::with_megabytes(megabytes);
+        group.bench_with_input(BenchmarkId::new("Flow", megabytes), &megabytes, |b, _| {
+            b.iter(|| black_box(flow.run(&mut shared)))
+        });
+        group.bench_with_input(
+            BenchmarkId::new("AsyncFlow", megabytes),
+            &megabytes,
+            |b, _| b.iter(|| black_box(runtime.block_on(async_flow.run(&mut shared)))),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("AsyncFlow of Nodes", megabytes),
+            &megabytes,
+            |b, _| {
+                b.iter(|| black_box(runtime.block_on(async_flow_of_sync_nodes.run(&mut shared))))
+            },
+        );
+        black_box(shared.embeddings.len());
+    }
+    group.finish();
+}
+
+criterion_group!(benches, flow_steps);
+criterion_main!(benches);
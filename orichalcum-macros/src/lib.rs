@@ -0,0 +1,317 @@
+//! Attribute macros for `orichalcum` (enabled by its `macros` feature), placed on an
+//! `impl NodeLogic for ...` (or `AsyncNodeLogic`) block so it only holds the interesting methods.
+//!
+//! They write what is missing:
+//! - `clone_box`, through the type's `Clone` implementation
+//! - with `#[async_node_logic]`, the phases which were left out (`prep` and `exec` return `Null`,
+//!   `post` returns `None`), and the `async_trait` attribute
+//!
+//! They also convert typed phases: `prep` can return, `exec` can take and return, and `post` can
+//! take any `serde` type instead of `NodeValue`. Values are (de)serialized on the way in and out,
+//! a failure being a `NodeError::SerdeError`. Types named `NodeValue` or `serde_json::Value` (or
+//! by their full paths) are passed as they are.
+//!
+//! ```
+//! use orichalcum::core::error::NodeError;
+//! use orichalcum::core::sync_impl::node::{Node, NodeLogic};
+//! use orichalcum::core::sync_impl::{NodeValue, SharedStore};
+//! use orichalcum::node_logic;
+//! use serde_json::json;
+//! use std::collections::HashMap;
+//!
+//! #[derive(Clone)]
+//! struct Double;
+//!
+//! #[node_logic]
+//! impl NodeLogic for Double {
+//!     fn prep(
+//!         &self,
+//!         _params: &HashMap<String, NodeValue>,
+//!         shared: &SharedStore,
+//!     ) -> Result<NodeValue, NodeError> {
+//!         Ok(shared["numbers"].clone())
+//!     }
+//!
+//!     fn exec(&self, input: Vec<i64>) -> Result<Vec<i64>, NodeError> {
+//!         Ok(input.into_iter().map(|n| n * 2).collect())
+//!     }
+//!
+//!     fn post(
+//!         &self,
+//!         shared: &mut SharedStore,
+//!         _prep_res: NodeValue,
+//!         doubled: Vec<i64>,
+//!     ) -> Result<Option<String>, NodeError> {
+//!         shared.insert("doubled".into(), json!(doubled));
+//!         Ok(None)
+//!     }
+//! }
+//!
+//! let mut shared = SharedStore::from([("numbers".to_string(), json!([1, 2, 3]))]);
+//! Node::new(Double).run(&mut shared).unwrap();
+//! assert_eq!(shared["doubled"], json!([2, 4, 6]));
+//! ```
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    FnArg, GenericArgument, ImplItem, ImplItemFn, ItemImpl, Path, PathArguments, ReturnType, Type,
+    parse_macro_input, parse_quote,
+};
+
+/// Completes an `impl NodeLogic for ...` block, see the crate's documentation
+#[proc_macro_attribute]
+pub fn node_logic(args: TokenStream, item: TokenStream) -> TokenStream {
+    expand(args, item, Flavour::Sync)
+}
+
+/// Completes an `impl AsyncNodeLogic for ...` block, see the crate's documentation
+#[proc_macro_attribute]
+pub fn async_node_logic(args: TokenStream, item: TokenStream) -> TokenStream {
+    expand(args, item, Flavour::Async)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Flavour {
+    Sync,
+    Async,
+}
+
+fn expand(args: TokenStream, item: TokenStream, flavour: Flavour) -> TokenStream {
+    if !args.is_empty() {
+        let args = TokenStream2::from(args);
+        return syn::Error::new_spanned(args, "the node logic macros take no arguments")
+            .to_compile_error()
+            .into();
+    }
+    let mut item = parse_macro_input!(item as ItemImpl);
+    match complete(&mut item, flavour) {
+        Ok(()) => quote!(#item).into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+fn complete(item: &mut ItemImpl, flavour: Flavour) -> syn::Result<()> {
+    let Some((_, trait_path, _)) = &item.trait_ else {
+        return Err(syn::Error::new_spanned(
+            &item.self_ty,
+            "expected the implementation of a node logic trait (`impl NodeLogic for ...`)",
+        ));
+    };
+    let trait_path = trait_path.clone();
+
+    for impl_item in &mut item.items {
+        if let ImplItem::Fn(method) = impl_item {
+            // Which arguments (after `self`) and whether the output are `NodeValue`s in the trait
+            match method.sig.ident.to_string().as_str() {
+                "prep" => convert(method, &[], true, flavour)?,
+                "exec" => convert(method, &[0], true, flavour)?,
+                "post" => convert(method, &[1, 2], false, flavour)?,
+                _ => {}
+            }
+        }
+    }
+
+    let defined = |name: &str| {
+        item.items
+            .iter()
+            .any(|impl_item| matches!(impl_item, ImplItem::Fn(method) if method.sig.ident == name))
+    };
+    let value = quote!(::orichalcum::core::sync_impl::NodeValue);
+    let error = quote!(::orichalcum::core::error::NodeError);
+    let mut missing: Vec<ImplItem> = Vec::new();
+
+    if !defined("clone_box") {
+        missing.push(parse_quote! {
+            fn clone_box(&self) -> ::std::boxed::Box<dyn #trait_path> {
+                ::std::boxed::Box::new(::std::clone::Clone::clone(self))
+            }
+        });
+    }
+    if flavour == Flavour::Async {
+        let shared = shared_state(&trait_path);
+        if !defined("prep") {
+            missing.push(parse_quote! {
+                async fn prep(
+                    &self,
+                    _params: &::std::collections::HashMap<::std::string::String, #value>,
+                    _shared: &#shared,
+                ) -> ::std::result::Result<#value, #error> {
+                    ::std::result::Result::Ok(#value::Null)
+                }
+            });
+        }
+        if !defined("exec") {
+            missing.push(parse_quote! {
+                async fn exec(&self, _input: #value) -> ::std::result::Result<#value, #error> {
+                    ::std::result::Result::Ok(#value::Null)
+                }
+            });
+        }
+        if !defined("post") {
+            missing.push(parse_quote! {
+                async fn post(
+                    &self,
+                    _shared: &mut #shared,
+                    _prep_res: #value,
+                    _exec_res: #value,
+                ) -> ::std::result::Result<::std::option::Option<::std::string::String>, #error> {
+                    ::std::result::Result::Ok(::std::option::Option::None)
+                }
+            });
+        }
+        let has_async_trait = item.attrs.iter().any(|attr| {
+            attr.path()
+                .segments
+                .last()
+                .is_some_and(|segment| segment.ident == "async_trait")
+        });
+        if !has_async_trait {
+            item.attrs
+                .push(parse_quote!(#[::orichalcum::__private::async_trait]));
+        }
+    }
+    item.items.extend(missing);
+    Ok(())
+}
+
+/// Rewrites a phase written with typed arguments (the ones at `typed_args`) or a typed output
+/// into the trait's signature, (de)serializing around the original body
+fn convert(
+    method: &mut ImplItemFn,
+    typed_args: &[usize],
+    typed_output: bool,
+    flavour: Flavour,
+) -> syn::Result<()> {
+    let value = quote!(::orichalcum::core::sync_impl::NodeValue);
+    let error = quote!(::orichalcum::core::error::NodeError);
+    let serde_json = quote!(::orichalcum::__private::serde_json);
+
+    let mut bindings = Vec::new();
+    let arguments = method.sig.inputs.iter_mut().filter_map(|arg| match arg {
+        FnArg::Typed(arg) => Some(arg),
+        FnArg::Receiver(_) => None,
+    });
+    for (index, arg) in arguments.enumerate() {
+        if typed_args.contains(&index) && !is_value(&arg.ty) {
+            let raw = format_ident!("__orichalcum_arg_{}", index);
+            let (pat, ty) = (&arg.pat, &arg.ty);
+            bindings.push(quote!(let #pat: #ty = #serde_json::from_value(#raw)?;));
+            *arg.pat = parse_quote!(#raw);
+            *arg.ty = parse_quote!(#value);
+        }
+    }
+    let output = match &method.sig.output {
+        ReturnType::Type(_, ty) => Some((**ty).clone()),
+        ReturnType::Default => None,
+    };
+    let typed_ok = typed_output
+        && output
+            .as_ref()
+            .and_then(ok_type)
+            .is_some_and(|ok| !is_value(ok));
+    if bindings.is_empty() && !typed_ok {
+        return Ok(());
+    }
+    let Some(output) = output else {
+        return Err(syn::Error::new_spanned(
+            &method.sig,
+            "expected the phase to return a `Result`",
+        ));
+    };
+
+    // `return`s and `?`s of the body keep their meaning inside a closure (or an async block)
+    let body = &method.block;
+    let run = match flavour {
+        Flavour::Sync => quote!((move || -> #output #body)()),
+        Flavour::Async => quote!(async move #body.await),
+    };
+    let block = if typed_ok {
+        method.sig.output = parse_quote!(-> ::std::result::Result<#value, #error>);
+        quote!({
+            #(#bindings)*
+            let __orichalcum_output: #output = #run;
+            ::std::result::Result::Ok(#serde_json::to_value(__orichalcum_output?)?)
+        })
+    } else {
+        quote!({
+            #(#bindings)*
+            let __orichalcum_output: #output = #run;
+            __orichalcum_output
+        })
+    };
+    method.block = syn::parse2(block)?;
+    Ok(())
+}
+
+/// The paths naming `NodeValue`, which needs no conversion. A bare `Value` isn't one of them:
+/// it may be any type, and converting a `serde_json::Value` only costs a move.
+const VALUE_PATHS: &[&str] = &[
+    "NodeValue",
+    "sync_impl::NodeValue",
+    "core::sync_impl::NodeValue",
+    "orichalcum::core::sync_impl::NodeValue",
+    "serde_json::Value",
+    "orichalcum::__private::serde_json::Value",
+];
+
+/// Whether `ty` is `NodeValue` (or `serde_json::Value`), named by one of the `VALUE_PATHS`
+fn is_value(ty: &Type) -> bool {
+    let Type::Path(path) = ty else {
+        return false;
+    };
+    if path.qself.is_some()
+        || path
+            .path
+            .segments
+            .iter()
+            .any(|segment| !segment.arguments.is_none())
+    {
+        return false;
+    }
+    let name = path
+        .path
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect::<Vec<_>>()
+        .join("::");
+    VALUE_PATHS.contains(&name.as_str())
+}
+
+/// `T` in `Result<T, E>`
+fn ok_type(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Result" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+/// The shared state of `AsyncNodeLogic<S>`, the default one if it is left out
+fn shared_state(trait_path: &Path) -> TokenStream2 {
+    let shared = trait_path
+        .segments
+        .last()
+        .and_then(|segment| match &segment.arguments {
+            PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+                GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            }),
+            _ => None,
+        });
+    match shared {
+        Some(shared) => quote!(#shared),
+        None => quote!(::orichalcum::core::sync_impl::SharedStore),
+    }
+}
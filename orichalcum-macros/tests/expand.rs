@@ -0,0 +1,8 @@
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/typed_phases.rs");
+    t.pass("tests/ui/value_paths.rs");
+    t.compile_fail("tests/ui/not_a_trait_impl.rs");
+    t.compile_fail("tests/ui/arguments.rs");
+}
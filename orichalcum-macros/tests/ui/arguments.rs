@@ -0,0 +1,9 @@
+use orichalcum::node_logic;
+
+#[derive(Clone)]
+struct Double;
+
+#[node_logic(exec)]
+impl orichalcum::core::sync_impl::node::NodeLogic for Double {}
+
+fn main() {}
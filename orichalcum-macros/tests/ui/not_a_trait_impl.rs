@@ -0,0 +1,13 @@
+use orichalcum::node_logic;
+
+#[derive(Clone)]
+struct Double;
+
+#[node_logic]
+impl Double {
+    fn exec(&self, input: Vec<i64>) -> Vec<i64> {
+        input.into_iter().map(|n| n * 2).collect()
+    }
+}
+
+fn main() {}
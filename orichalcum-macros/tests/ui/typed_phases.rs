@@ -0,0 +1,63 @@
+use orichalcum::core::async_impl::async_node::{AsyncNode, AsyncNodeLogic};
+use orichalcum::core::error::NodeError;
+use orichalcum::core::sync_impl::node::{Node, NodeLogic};
+use orichalcum::core::sync_impl::{NodeValue, SharedStore};
+use orichalcum::{async_node_logic, node_logic};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+
+#[derive(Clone)]
+struct Double;
+
+#[node_logic]
+impl NodeLogic for Double {
+    fn prep(
+        &self,
+        _params: &HashMap<String, NodeValue>,
+        shared: &SharedStore,
+    ) -> Result<Vec<i64>, NodeError> {
+        Ok(serde_json::from_value(shared["numbers"].clone())?)
+    }
+
+    fn exec(&self, input: Vec<i64>) -> Result<Vec<i64>, NodeError> {
+        Ok(input.into_iter().map(|n| n * 2).collect())
+    }
+
+    fn post(
+        &self,
+        shared: &mut SharedStore,
+        _prep_res: NodeValue,
+        exec_res: Vec<i64>,
+    ) -> Result<Option<String>, NodeError> {
+        shared.insert("doubled".into(), json!(exec_res));
+        Ok(None)
+    }
+}
+
+/// A type of the user's which happens to be named like `serde_json::Value`
+#[derive(Serialize, Deserialize)]
+struct Value {
+    count: u32,
+}
+
+#[derive(Clone)]
+struct Count;
+
+// Only `exec` is written, the other phases and `clone_box` are generated
+#[async_node_logic]
+impl AsyncNodeLogic for Count {
+    async fn exec(&self, _input: NodeValue) -> Result<Value, NodeError> {
+        Ok(Value { count: 3 })
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let mut shared = SharedStore::from([("numbers".to_string(), json!([1, 2, 3]))]);
+    Node::new(Double).run(&mut shared).unwrap();
+    assert_eq!(shared["doubled"], json!([2, 4, 6]));
+
+    let outcome = AsyncNode::new(Count).run(&mut shared).await.unwrap();
+    assert_eq!(outcome, None);
+}
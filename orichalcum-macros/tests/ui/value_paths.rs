@@ -0,0 +1,52 @@
+use orichalcum::core::error::NodeError;
+use orichalcum::core::sync_impl::SharedStore;
+use orichalcum::core::sync_impl::node::{Node, NodeLogic};
+use orichalcum::node_logic;
+use serde_json::json;
+
+#[derive(Clone)]
+struct Passthrough;
+
+// Values named by these paths are passed as they are
+#[node_logic]
+impl NodeLogic for Passthrough {
+    fn exec(
+        &self,
+        input: serde_json::Value,
+    ) -> Result<orichalcum::core::sync_impl::NodeValue, NodeError> {
+        Ok(input)
+    }
+
+    fn post(
+        &self,
+        shared: &mut SharedStore,
+        prep_res: ::orichalcum::core::sync_impl::NodeValue,
+        exec_res: serde_json::Value,
+    ) -> Result<Option<String>, NodeError> {
+        shared.insert("prep".into(), prep_res);
+        shared.insert("exec".into(), exec_res);
+        Ok(Some("done".into()))
+    }
+}
+
+/// Converted like any other type, which leaves the value as it is
+type Value = serde_json::Value;
+
+#[derive(Clone)]
+struct Aliased;
+
+#[node_logic]
+impl NodeLogic for Aliased {
+    fn exec(&self, input: Value) -> Result<Value, NodeError> {
+        Ok(input)
+    }
+}
+
+fn main() {
+    let mut shared = SharedStore::new();
+    let action = Node::new(Passthrough).run(&mut shared).unwrap();
+    assert_eq!(action, Some("done".into()));
+    assert_eq!(shared["prep"], json!(null));
+    assert_eq!(shared["exec"], json!(null));
+    assert_eq!(Node::new(Aliased).run(&mut shared).unwrap(), None);
+}
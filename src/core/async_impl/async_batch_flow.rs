@@ -0,0 +1,311 @@
+use crate::core::Executable;
+use crate::core::async_impl::async_node::{AsyncNode, AsyncNodeLogic};
+use crate::core::async_impl::cancellation;
+use crate::core::async_impl::human_node;
+use crate::core::diagram::{DiagramKind, DiagramNode};
+use crate::core::error::NodeError;
+use crate::core::graph::ValidationIssue;
+use crate::core::observer;
+use crate::core::spans;
+use crate::core::sync_impl::node::NodeCore;
+use crate::core::sync_impl::{NodeValue, SharedStore};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// The closure shared by the async batch flows, it follows `BatchFlow`'s `prep_fn` contract:
+/// given the params and the shared state, it returns an array of param objects, the batched
+/// flow being run once per param object.
+pub type BatchParamsFn<S = SharedStore> =
+    Arc<dyn Fn(&HashMap<String, NodeValue>, &S) -> NodeValue + Send + Sync>;
+
+/// Turns what `prep_fn` returned into the param sets to run
+pub(crate) fn parse_param_sets(
+    param_sets: NodeValue,
+) -> Result<Vec<HashMap<String, NodeValue>>, NodeError> {
+    serde_json::from_value(param_sets).map_err(|e| {
+        NodeError::ExecError(format!(
+            "Batch flows expect prep_fn to return an array of param objects: {}",
+            e
+        ))
+    })
+}
+
+/// The async counterpart of `BatchFlow`: an `AsyncNode` which runs a flow once per param set,
+/// one run after the other, every run seeing the shared state left by the previous one.
+/// Its runs can't be suspended by a human node, see `new_human_node`.
+pub struct AsyncBatchFlow<S = SharedStore>(AsyncNode<S>);
+
+impl<S: 'static> Clone for AsyncBatchFlow<S> {
+    fn clone(&self) -> Self {
+        AsyncBatchFlow(self.0.clone())
+    }
+}
+
+/// The Derefs are needed to be able to access the inside `AsyncNode` easily
+impl<S> std::ops::Deref for AsyncBatchFlow<S> {
+    type Target = AsyncNode<S>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<S> From<AsyncBatchFlow<S>> for Executable<S> {
+    fn from(flow: AsyncBatchFlow<S>) -> Self {
+        Executable::Async(flow.0)
+    }
+}
+
+impl<S> std::ops::DerefMut for AsyncBatchFlow<S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+pub struct AsyncBatchFlowLogic<S = SharedStore> {
+    // An `AsyncNode` so that batch flows can be nested, like `BatchFlowLogic`
+    flow: AsyncNode<S>,
+    prep_fn: BatchParamsFn<S>,
+}
+
+impl<S: 'static> Clone for AsyncBatchFlowLogic<S> {
+    fn clone(&self) -> Self {
+        AsyncBatchFlowLogic {
+            flow: self.flow.clone(),
+            prep_fn: self.prep_fn.clone(),
+        }
+    }
+}
+
+impl<S> AsyncBatchFlow<S>
+where
+    S: Send + Sync + 'static,
+{
+    pub fn new<F>(flow: AsyncNode<S>, prep_fn: F) -> Self
+    where
+        F: Fn(&HashMap<String, NodeValue>, &S) -> NodeValue + Send + Sync + 'static,
+    {
+        AsyncBatchFlow(
+            AsyncNode::new(AsyncBatchFlowLogic {
+                flow,
+                prep_fn: Arc::new(prep_fn),
+            })
+            .with_name("async batch flow"),
+        )
+    }
+
+    pub fn with_id(self, id: &str) -> Self {
+        AsyncBatchFlow(self.0.with_id(id))
+    }
+
+    pub fn with_name(self, name: &str) -> Self {
+        AsyncBatchFlow(self.0.with_name(name))
+    }
+
+    pub fn with_description(self, description: &str) -> Self {
+        AsyncBatchFlow(self.0.with_description(description))
+    }
+}
+
+impl<S> AsyncBatchFlowLogic<S>
+where
+    S: Send + Sync + 'static,
+{
+    async fn run_batch(
+        &self,
+        params: &HashMap<String, NodeValue>,
+        shared: &mut S,
+    ) -> Result<Option<String>, NodeError> {
+        // Call the user-defined closure
+        let params_array = parse_param_sets((self.prep_fn)(params, shared))?;
+
+        let total = params_array.len();
+        for (index, params) in params_array.into_iter().enumerate() {
+            // Stop between two runs, like `AsyncFlow` between two nodes
+            if cancellation::is_cancelled() {
+                log::info!("AsyncBatchFlow cancelled before param set {}.", index);
+                return Err(NodeError::Cancelled);
+            }
+            let mut combined_params: HashMap<String, NodeValue> = params;
+            combined_params.extend(self.flow.data.params.clone());
+            let started = Instant::now();
+            let outcome = spans::batch_item(index)
+                .instrument(self.flow.run_with_params(shared, &combined_params))
+                .await;
+            observer::batch_item(
+                index,
+                total,
+                outcome.as_ref().map(|_| ()),
+                started.elapsed(),
+            );
+            // One failing param set stops the whole batch
+            outcome.map_err(|source| NodeError::BatchError {
+                index,
+                source: Box::new(human_node::refuse_in_batch(source)),
+            })?;
+        }
+
+        // Same as `BatchFlowLogic`, this allows basic chaining
+        Ok(Some("default".into()))
+    }
+}
+
+#[async_trait]
+impl<S> AsyncNodeLogic<S> for AsyncBatchFlowLogic<S>
+where
+    S: Send + Sync + 'static,
+{
+    async fn orchestrate(
+        &self,
+        node: &NodeCore<S>,
+        params: &HashMap<String, NodeValue>,
+        shared: &mut S,
+    ) -> Option<Result<Option<String>, NodeError>> {
+        let span = spans::batch_flow(node);
+        let outcome = span.instrument(self.run_batch(params, shared)).await;
+        span.record(&outcome);
+        Some(outcome)
+    }
+
+    // `orchestrate` takes over the whole run, so the lifecycle is never called by `AsyncNode::run`
+    async fn prep(
+        &self,
+        _params: &HashMap<String, NodeValue>,
+        _shared: &S,
+    ) -> Result<NodeValue, NodeError> {
+        Ok(NodeValue::Null)
+    }
+
+    async fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+        Err(NodeError::ExecError(
+            "Batch flows are run through orchestrate, not exec".into(),
+        ))
+    }
+
+    async fn post(
+        &self,
+        _shared: &mut S,
+        _prep_res: NodeValue,
+        _exec_res: NodeValue,
+    ) -> Result<Option<String>, NodeError> {
+        Ok(None)
+    }
+
+    // The batched flow is the one holding a graph
+    fn validate(&self) -> Vec<ValidationIssue> {
+        self.flow.behaviour.validate()
+    }
+
+    fn diagram_kind(&self) -> DiagramKind {
+        DiagramKind::BatchFlow(DiagramNode::from(&self.flow).into())
+    }
+
+    fn clone_box(&self) -> Box<dyn AsyncNodeLogic<S>> {
+        Box::new((*self).clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::async_impl::async_flow::AsyncFlow;
+    use crate::core::async_impl::cancellation::{CANCELLED_ACTION, CancellationToken};
+    use crate::core::async_impl::human_node::new_human_node;
+    use serde_json::json;
+    use std::time::Duration;
+
+    /// Takes 50ms, then appends its `n` param to `shared["done"]`
+    #[derive(Clone)]
+    struct SlowItem;
+
+    #[async_trait]
+    impl AsyncNodeLogic for SlowItem {
+        async fn prep(
+            &self,
+            params: &HashMap<String, NodeValue>,
+            _shared: &HashMap<String, NodeValue>,
+        ) -> Result<NodeValue, NodeError> {
+            Ok(params["n"].clone())
+        }
+
+        async fn exec(&self, n: NodeValue) -> Result<NodeValue, NodeError> {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(n)
+        }
+
+        async fn post(
+            &self,
+            shared: &mut HashMap<String, NodeValue>,
+            _prep_res: NodeValue,
+            n: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            let done = shared.entry("done".into()).or_insert(json!([]));
+            done.as_array_mut().unwrap().push(n);
+            Ok(None)
+        }
+
+        fn clone_box(&self) -> Box<dyn AsyncNodeLogic> {
+            Box::new(self.clone())
+        }
+    }
+
+    fn five_items(_: &HashMap<String, NodeValue>, _: &HashMap<String, NodeValue>) -> NodeValue {
+        json!([{ "n": 0 }, { "n": 1 }, { "n": 2 }, { "n": 3 }, { "n": 4 }])
+    }
+
+    #[tokio::test]
+    async fn runs_every_param_set_in_order() {
+        let flow = AsyncBatchFlow::new(AsyncNode::new(SlowItem), five_items);
+        let mut shared = HashMap::new();
+        assert_eq!(flow.run(&mut shared).await.unwrap(), Some("default".into()));
+        assert_eq!(shared["done"], json!([0, 1, 2, 3, 4]));
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_batch_reports_it() {
+        let flow = AsyncBatchFlow::new(AsyncNode::new(SlowItem), five_items);
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(75)).await;
+            canceller.cancel();
+        });
+        let mut shared = HashMap::new();
+        let outcome = flow.run_with_cancellation(&mut shared, &token).await;
+        assert_eq!(outcome.unwrap().as_deref(), Some(CANCELLED_ACTION));
+        // The param set running when the token was cancelled is completed, the next ones are not
+        assert_eq!(shared["done"], json!([0, 1]));
+    }
+
+    #[tokio::test]
+    async fn human_nodes_fail_the_batch() {
+        let review = new_human_node(SlowItem).with_id("review");
+        let flow = AsyncBatchFlow::new(review, five_items);
+        match flow.run(&mut HashMap::new()).await {
+            Err(NodeError::BatchError { index: 0, source }) => {
+                assert!(matches!(*source, NodeError::CheckpointError(_)))
+            }
+            outcome => panic!("expected the first param set to fail, got {:?}", outcome),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_batch_stops_its_flow() {
+        let batch = AsyncBatchFlow::new(AsyncNode::new(SlowItem), five_items);
+        let after = AsyncBatchFlow::new(AsyncNode::new(SlowItem), |_, _| json!([{ "n": 9 }]));
+        let flow = AsyncFlow::new(batch.0.next(after));
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(75)).await;
+            canceller.cancel();
+        });
+        let mut shared = HashMap::new();
+        let outcome = flow.run_with_cancellation(&mut shared, &token).await;
+        // Same outcome as a cancelled flow or node, and the node after the batch never runs
+        assert_eq!(outcome.unwrap().as_deref(), Some(CANCELLED_ACTION));
+        assert_eq!(shared["done"], json!([0, 1]));
+    }
+}
@@ -1,60 +1,100 @@
 use crate::core::async_impl::async_node::{AsyncNode, AsyncNodeLogic};
+use crate::core::async_impl::cancellation;
+use crate::core::diagram::DiagramKind;
+use crate::core::error::NodeError;
+use crate::core::observer;
+use crate::core::spans;
 use crate::core::sync_impl::NodeValue;
 use async_trait::async_trait;
-use futures::stream::{self, StreamExt};
+use futures::stream::{self, StreamExt, TryStreamExt};
 use std::collections::HashMap;
+use std::time::Instant;
 
 #[derive(Clone)]
-pub struct AsyncBatchLogic<L: AsyncNodeLogic> {
+pub struct AsyncBatchLogic<L> {
     logic: L,
 }
 
-impl<L: AsyncNodeLogic> AsyncBatchLogic<L> {
+impl<L> AsyncBatchLogic<L> {
     pub fn new(logic: L) -> Self {
         AsyncBatchLogic { logic }
     }
 }
 
 #[async_trait]
-impl<L: AsyncNodeLogic + Clone> AsyncNodeLogic for AsyncBatchLogic<L> {
+impl<S, L> AsyncNodeLogic<S> for AsyncBatchLogic<L>
+where
+    S: Send + Sync + 'static,
+    L: AsyncNodeLogic<S> + Clone,
+{
     async fn prep(
         &self,
         params: &HashMap<String, NodeValue>,
-        shared: &HashMap<String, NodeValue>,
-    ) -> NodeValue {
+        shared: &S,
+    ) -> Result<NodeValue, NodeError> {
         self.logic.prep(params, shared).await
     }
 
-    async fn exec(&self, items: NodeValue) -> NodeValue {
+    async fn exec(&self, items: NodeValue) -> Result<NodeValue, NodeError> {
         // Check that input is indeed an array
         if let Some(arr) = items.as_array() {
-            let results: Vec<NodeValue> = stream::iter(arr)
-                .then(|item| self.logic.exec(item.clone()))
-                .collect()
-                .await;
+            // Items run one after the other, the first failing item stops the batch
+            // (and so does a cancellation of the run)
+            let results: Vec<NodeValue> = stream::iter(arr.iter().enumerate())
+                .then(|(index, item)| async move {
+                    if cancellation::is_cancelled() {
+                        return Err(NodeError::Cancelled);
+                    }
+                    let started = Instant::now();
+                    let outcome = spans::batch_item(index)
+                        .instrument(self.logic.exec(item.clone()))
+                        .await;
+                    observer::batch_item(
+                        index,
+                        arr.len(),
+                        outcome.as_ref().map(|_| ()),
+                        started.elapsed(),
+                    );
+                    outcome.map_err(|source| NodeError::BatchError {
+                        index,
+                        source: Box::new(source),
+                    })
+                })
+                .try_collect()
+                .await?;
 
-            results.into()
+            Ok(results.into())
         } else {
             log::error!("items is not an array");
-            NodeValue::Null
+            Err(NodeError::ExecError(
+                "AsyncBatchLogic expects prep to return an array of items".into(),
+            ))
         }
     }
 
     async fn post(
         &self,
-        shared: &mut HashMap<String, NodeValue>,
+        shared: &mut S,
         prep_res: NodeValue,
         exec_res: NodeValue,
-    ) -> Option<String> {
+    ) -> Result<Option<String>, NodeError> {
         self.logic.post(shared, prep_res, exec_res).await
     }
 
-    fn clone_box(&self) -> Box<dyn AsyncNodeLogic> {
+    fn diagram_kind(&self) -> DiagramKind {
+        DiagramKind::Batch
+    }
+
+    fn clone_box(&self) -> Box<dyn AsyncNodeLogic<S>> {
         Box::new((*self).clone())
     }
 }
 
 /// The `AsyncBatchNode` factory
-pub fn new_async_batch_node<L: AsyncNodeLogic + Clone>(logic: L) -> AsyncNode {
+pub fn new_async_batch_node<S, L>(logic: L) -> AsyncNode<S>
+where
+    S: Send + Sync + 'static,
+    L: AsyncNodeLogic<S> + Clone,
+{
     AsyncNode::new(AsyncBatchLogic { logic })
 }
@@ -1,167 +1,833 @@
 use crate::core::async_impl::async_node::{AsyncNode, AsyncNodeLogic};
-use crate::core::sync_impl::NodeValue;
+use crate::core::async_impl::cancellation;
+use crate::core::async_impl::human_node::{self, Resuming, RunStatus, SuspendedFlow, SuspendedRun};
+use crate::core::checkpoint::{self, CheckpointStore, Checkpointer, Position};
+use crate::core::diagram::{Diagram, DiagramKind, DiagramNode};
+use crate::core::error::NodeError;
+use crate::core::graph::{Graph, ValidationIssue};
+use crate::core::loop_guard::{LoopGuard, MAX_STEPS_EXCEEDED_ACTION};
+use crate::core::observer::{self, FlowEvent, FlowObserver, Observers};
+use crate::core::report::{Recorder, RunReport};
+use crate::core::spans;
+use crate::core::sync_impl::node::NodeCore;
+use crate::core::sync_impl::{NodeValue, SharedStore};
+use crate::core::timeout::TimeoutPolicy;
+use crate::core::unknown_action::UnknownActionPolicy;
 use crate::core::{Executable, Executable::Async, Executable::Sync};
 use async_trait::async_trait;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::RuntimeFlavor;
+use tokio::time::Instant;
 
-/// The logic that is specif
-#[derive(Clone)]
-pub struct AsyncFlowLogic {
-    start: Executable,
+tokio::task_local! {
+    /// The deadline of the innermost `AsyncFlow` being run.
+    /// Nested flows inherit it (and can only shorten it).
+    static FLOW_DEADLINE: Instant;
+}
+
+/// The orchestration behind an `AsyncFlow`: the graph it runs, and how a run is bounded
+/// (deadline, loop guard), routed (unknown actions), observed and checkpointed
+pub struct AsyncFlowLogic<S = SharedStore> {
+    graph: Arc<Graph<S>>,
+    deadline: Option<Duration>,
+    loop_guard: LoopGuard,
+    unknown_action: UnknownActionPolicy,
+    observers: Vec<Arc<dyn FlowObserver>>,
+    checkpoints: Option<Checkpointer<S>>,
+}
+
+// Cloning a flow only clones the `Arc`, the graph itself is shared
+impl<S> Clone for AsyncFlowLogic<S> {
+    fn clone(&self) -> Self {
+        AsyncFlowLogic {
+            graph: Arc::clone(&self.graph),
+            deadline: self.deadline,
+            loop_guard: self.loop_guard.clone(),
+            unknown_action: self.unknown_action.clone(),
+            observers: self.observers.clone(),
+            checkpoints: self.checkpoints.clone(),
+        }
+    }
 }
 
 /// A flow really, just is a Node with orchestration logic
 /// to enforce that, we will create a NewType with a "factory" which prebuilds it.
-#[derive(Clone)]
-pub struct AsyncFlow(AsyncNode);
+/// The nodes work on the caller's shared state directly, synchronous ones included: those block
+/// the flow's task while they run (a multi-threaded runtime hands its other tasks to other
+/// workers), so the synchronous nodes of concurrent sub-runs (`AsyncParallelBatchFlow`) run one
+/// at a time, and a deadline is only seen once they return.
+pub struct AsyncFlow<S = SharedStore>(AsyncNode<S>);
+
+impl<S: 'static> Clone for AsyncFlow<S> {
+    fn clone(&self) -> Self {
+        AsyncFlow(self.0.clone())
+    }
+}
+
+impl<S> From<AsyncFlow<S>> for Executable<S> {
+    fn from(flow: AsyncFlow<S>) -> Self {
+        Executable::Async(flow.0)
+    }
+}
 
 /// The Derefs are needed to be able to access the inside `Node` of the `Flow` easily
-impl std::ops::Deref for AsyncFlow {
-    type Target = AsyncNode;
+impl<S> std::ops::Deref for AsyncFlow<S> {
+    type Target = AsyncNode<S>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
     }
 }
 
-impl std::ops::DerefMut for AsyncFlow {
+impl<S> std::ops::DerefMut for AsyncFlow<S> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.0
     }
 }
 
-impl AsyncFlow {
-    pub fn new(start: Executable) -> AsyncFlow {
-        AsyncFlow(AsyncNode::new(AsyncFlowLogic { start }))
+impl<S> AsyncFlow<S>
+where
+    S: Send + std::marker::Sync + 'static,
+{
+    /// Builds the flow's graph from `start` and the successors chained to it
+    pub fn new(start: impl Into<Executable<S>>) -> AsyncFlow<S> {
+        Self::from_graph(Graph::from_start(start.into()))
+    }
+
+    /// Runs a graph built with a `GraphBuilder`, which can contain cycles
+    pub fn from_graph(graph: Arc<Graph<S>>) -> AsyncFlow<S> {
+        AsyncFlow(
+            AsyncNode::new(AsyncFlowLogic {
+                graph,
+                deadline: None,
+                loop_guard: LoopGuard::default(),
+                unknown_action: UnknownActionPolicy::default(),
+                observers: Vec::new(),
+                checkpoints: None,
+            })
+            .with_name("async flow"),
+        )
+    }
+
+    pub fn with_id(self, id: &str) -> Self {
+        AsyncFlow(self.0.with_id(id))
+    }
+
+    pub fn with_name(self, name: &str) -> Self {
+        AsyncFlow(self.0.with_name(name))
+    }
+
+    pub fn with_description(self, description: &str) -> Self {
+        AsyncFlow(self.0.with_description(description))
+    }
+
+    /// Gives the whole run (nested flows included) `budget` to complete.
+    /// Once it is exceeded, the node being run is abandoned (a synchronous one is waited for) and
+    /// the flow fails with `NodeError::DeadlineExceeded` (see `with_timeout` to route it to an
+    /// action instead). Like a node's time limits, abandoning an async node detaches the work it
+    /// handed to other threads (see `TimeoutPolicy`).
+    pub fn with_deadline(mut self, budget: Duration) -> Self {
+        let behaviour: &mut dyn AsyncNodeLogic<S> = &mut *self.behaviour;
+
+        if let Some(flow_logic) = behaviour.as_any_mut().downcast_mut::<AsyncFlowLogic<S>>() {
+            flow_logic.deadline = Some(budget);
+        } else {
+            panic!("Error: AsyncFlow's logic is not of type AsyncFlowLogic");
+        }
+        self
+    }
+
+    /// Stops runs which loop for too long, see `LoopGuard`
+    pub fn with_loop_guard(mut self, loop_guard: LoopGuard) -> Self {
+        let behaviour: &mut dyn AsyncNodeLogic<S> = &mut *self.behaviour;
+
+        if let Some(flow_logic) = behaviour.as_any_mut().downcast_mut::<AsyncFlowLogic<S>>() {
+            flow_logic.loop_guard = loop_guard;
+        } else {
+            panic!("Error: AsyncFlow's logic is not of type AsyncFlowLogic");
+        }
+        self
+    }
+
+    /// What the flow does when a node returns an action none of its edges match, see
+    /// `UnknownActionPolicy`
+    pub fn with_unknown_action(mut self, policy: UnknownActionPolicy) -> Self {
+        let behaviour: &mut dyn AsyncNodeLogic<S> = &mut *self.behaviour;
+
+        if let Some(flow_logic) = behaviour.as_any_mut().downcast_mut::<AsyncFlowLogic<S>>() {
+            flow_logic.unknown_action = policy;
+        } else {
+            panic!("Error: AsyncFlow's logic is not of type AsyncFlowLogic");
+        }
+        self
+    }
+
+    /// Reports the flow's events (the ones of nested flows included) to `observer`
+    pub fn with_observer<O: FlowObserver + 'static>(mut self, observer: O) -> Self {
+        let behaviour: &mut dyn AsyncNodeLogic<S> = &mut *self.behaviour;
+
+        if let Some(flow_logic) = behaviour.as_any_mut().downcast_mut::<AsyncFlowLogic<S>>() {
+            flow_logic.observers.push(Arc::new(observer));
+        } else {
+            panic!("Error: AsyncFlow's logic is not of type AsyncFlowLogic");
+        }
+        self
+    }
+
+    /// Saves a checkpoint (the last node completed, the params and the shared state) to `store`
+    /// after each node, so an interrupted run can be picked up with `resume`. Checkpoints are
+    /// kept by flow id and cleared once the flow reaches its end. The flow and its nodes must be
+    /// given ids (`with_id`), runs fail with a `CheckpointError` otherwise.
+    /// The store is called from the flow's task, it should be quick (like `FileStore`'s writes).
+    pub fn with_checkpoints<C: CheckpointStore + 'static>(mut self, store: C) -> Self
+    where
+        S: Serialize + DeserializeOwned,
+    {
+        let behaviour: &mut dyn AsyncNodeLogic<S> = &mut *self.behaviour;
+
+        if let Some(flow_logic) = behaviour.as_any_mut().downcast_mut::<AsyncFlowLogic<S>>() {
+            flow_logic.checkpoints = Some(Checkpointer::new(store));
+        } else {
+            panic!("Error: AsyncFlow's logic is not of type AsyncFlowLogic");
+        }
+        self
+    }
+
+    /// Continues the run saved in the flow's last checkpoint from the node after the one it was
+    /// saved for, with the params and shared state it holds (which replaces `shared`).
+    /// Without a checkpoint, this is `run`. Nested flows run again from their start.
+    pub async fn resume(&self, shared: &mut S) -> Result<Option<String>, NodeError> {
+        match self.behaviour.as_any().downcast_ref::<AsyncFlowLogic<S>>() {
+            Some(flow_logic) => flow_logic.resume(&self.data, shared).await,
+            None => panic!("Error: AsyncFlow's logic is not of type AsyncFlowLogic"),
+        }
+    }
+
+    /// Runs the flow like `run`, except that a human node (see `new_human_node`) suspends the
+    /// run rather than failing it: the `SuspendedRun` returned holds what the person is asked
+    /// and the shared state, so it can be stored and picked up later with `resume_suspended`.
+    /// The human node and the flows it is nested in must be given distinct ids (`with_id`), a
+    /// run suspended at generated or shared ones fails with a `CheckpointError`.
+    pub async fn run_until_suspended(&self, shared: &mut S) -> Result<RunStatus, NodeError>
+    where
+        S: Serialize,
+    {
+        let outcome = self.run(shared).await;
+        Self::status(outcome, shared)
+    }
+
+    /// Resumes a run suspended by a human node, whose `post` receives the person's `answer`.
+    /// The shared state saved with the run replaces `shared`. The run can be suspended again.
+    pub async fn resume_suspended(
+        &self,
+        run: SuspendedRun,
+        answer: NodeValue,
+        shared: &mut S,
+    ) -> Result<RunStatus, NodeError>
+    where
+        S: Serialize + DeserializeOwned,
+    {
+        run.require_stable_ids()?;
+        if run.flows.first().map(|suspended| suspended.flow.as_str()) != Some(&self.data.id) {
+            return Err(NodeError::CheckpointError(format!(
+                "The suspended run was not started by flow {}",
+                self.data
+            )));
+        }
+        *shared = serde_json::from_value(run.shared.clone())?;
+        let outcome = Resuming::new(run, answer).scope(self.run(shared)).await;
+        Self::status(outcome, shared)
+    }
+
+    fn status(
+        outcome: Result<Option<String>, NodeError>,
+        shared: &S,
+    ) -> Result<RunStatus, NodeError>
+    where
+        S: Serialize,
+    {
+        match outcome {
+            Ok(action) => Ok(RunStatus::Completed(action)),
+            Err(NodeError::Suspended(mut run)) => {
+                run.require_stable_ids()?;
+                run.shared = serde_json::to_value(shared)?;
+                Ok(RunStatus::Suspended(*run))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Sets the time limits of the flow (seen as a node), use `TimeoutPolicy::on_timeout` to
+    /// route an exceeded deadline to an action of the parent flow.
+    pub fn with_timeout(self, timeout: TimeoutPolicy) -> Self {
+        AsyncFlow(self.0.with_timeout(timeout))
+    }
+
+    /// Runs the flow like `run`, and reports the nodes it visited, the actions they took,
+    /// their durations, retries and errors
+    pub async fn run_with_report(&self, shared: &mut S) -> RunReport {
+        let recorder = Arc::new(Recorder::default());
+        let recording: Arc<dyn FlowObserver> = recorder.clone();
+        let observers = Observers::join(observer::current(), &[recording]);
+        let started = Instant::now();
+        let outcome = observer::scoped(observers, self.run(shared)).await;
+        recorder.report(&self.data.id, &outcome, started.elapsed())
+    }
+
+    /// Checks the flow's graph (and the ones of nested flows) before running it: unreachable
+    /// nodes, declared actions without a successor (or successors for actions that were not
+    /// declared), and a fallback node for unknown actions which is not part of the graph.
+    pub fn validate(&self) -> Result<(), NodeError> {
+        let issues = self.behaviour.validate();
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(NodeError::InvalidGraph(issues))
+        }
+    }
+
+    /// The graph the flow runs
+    pub fn graph(&self) -> &Arc<Graph<S>> {
+        match self.behaviour.as_any().downcast_ref::<AsyncFlowLogic<S>>() {
+            Some(flow_logic) => &flow_logic.graph,
+            None => panic!("Error: AsyncFlow's logic is not of type AsyncFlowLogic"),
+        }
+    }
+
+    /// The node of the flow's graph with the given id (nested flows are not searched)
+    pub fn find(&self, id: &str) -> Option<&Executable<S>> {
+        let graph = self.graph();
+        graph.find(id).map(|handle| graph.node(handle))
+    }
+
+    pub fn diagram(&self) -> Diagram {
+        DiagramNode::from(&self.0).into()
+    }
+
+    /// The flow's graph in Graphviz DOT, nested flows are drawn as clusters
+    pub fn to_dot(&self) -> String {
+        self.diagram().to_dot()
     }
 
-    pub fn start(&mut self, start: Executable) {
+    /// The flow's graph as a Mermaid flowchart, nested flows are drawn as subgraphs
+    pub fn to_mermaid(&self) -> String {
+        self.diagram().to_mermaid()
+    }
+
+    pub fn start(&mut self, start: impl Into<Executable<S>>) {
         // extract the `NodeLogic` from the Flow
-        let behaviour: &mut dyn AsyncNodeLogic = &mut *self.behaviour;
+        let behaviour: &mut dyn AsyncNodeLogic<S> = &mut *self.behaviour;
 
-        if let Some(flow_logic) = behaviour.as_any_mut().downcast_mut::<AsyncFlowLogic>() {
+        if let Some(flow_logic) = behaviour.as_any_mut().downcast_mut::<AsyncFlowLogic<S>>() {
             // Should always be possible if the Flow as created through the factory
-            flow_logic.start = start;
+            flow_logic.graph = Graph::from_start(start.into());
         } else {
             // This should never happen, but somehow it did
-            panic!("Error: Flow's logic is not of type FlowLogic");
+            panic!("Error: AsyncFlow's logic is not of type AsyncFlowLogic");
         }
     }
 }
 
-#[async_trait]
-impl AsyncNodeLogic for AsyncFlowLogic {
-    async fn prep(
+impl<S> AsyncFlowLogic<S>
+where
+    S: Send + std::marker::Sync + 'static,
+{
+    async fn resume(
         &self,
+        flow: &NodeCore<S>,
+        shared: &mut S,
+    ) -> Result<Option<String>, NodeError> {
+        let Some(checkpoints) = &self.checkpoints else {
+            return Err(NodeError::CheckpointError(format!(
+                "Flow {} has no checkpoint store to resume from, see `with_checkpoints`",
+                flow
+            )));
+        };
+        checkpoint::require_stable_ids(flow, &self.graph)?;
+        match checkpoints.load(&flow.id)? {
+            Some((checkpoint, state)) => {
+                log::info!(
+                    "Resuming flow {} after node \"{}\" (step {}).",
+                    flow,
+                    checkpoint.node,
+                    checkpoint.step
+                );
+                *shared = state;
+                let position =
+                    Position::after(&self.graph, &checkpoint, shared, &self.unknown_action)?;
+                self.run_flow(flow, &checkpoint.params, shared, position)
+                    .await
+            }
+            None => {
+                self.run_flow(flow, &flow.params, shared, Position::start(&self.graph))
+                    .await
+            }
+        }
+    }
+
+    /// Runs the flow's nodes from `position` within its deadline, for its observers, `flow`
+    /// being the node running it
+    async fn run_flow(
+        &self,
+        flow: &NodeCore<S>,
         params: &HashMap<String, NodeValue>,
-        shared: &HashMap<String, NodeValue>,
-    ) -> NodeValue {
-        serde_json::to_value((params, shared)).expect("If this works, I'll be so lit")
-    }
-
-    async fn exec(&self, input: NodeValue) -> NodeValue {
-        //  This is the init (Basically, we deserialize the value that was passed from the previous
-        //  step)
-        let (params, mut shared): (HashMap<String, NodeValue>, HashMap<String, NodeValue>) =
-            if let Some(arr) = input.as_array() {
-                if arr.len() != 2 {
-                    log::error!("serde_json::to_value() failed to convert the params and shared.");
-                    (HashMap::new(), HashMap::new())
-                } else {
-                    // We can proceed
-                    let params = serde_json::from_value(arr[0].clone()).unwrap_or_default();
-                    let shared = serde_json::from_value(arr[1].clone()).unwrap_or_default();
-                    (params, shared)
+        shared: &mut S,
+        position: Position,
+    ) -> Result<Option<String>, NodeError> {
+        // Our own deadline, bounded by the one of the flow we are nested in (if any)
+        let inherited = FLOW_DEADLINE.try_with(|deadline| *deadline).ok();
+        let own = self.deadline.map(|budget| Instant::now() + budget);
+        let deadline = match (own, inherited) {
+            (Some(own), Some(inherited)) => Some(own.min(inherited)),
+            (own, inherited) => own.or(inherited),
+        };
+
+        let observers = Observers::join(observer::current(), &self.observers);
+        let info = flow.info();
+        let span = spans::flow(flow);
+        let run = async {
+            observer::emit(observers.as_ref(), || FlowEvent::FlowStart {
+                flow: info,
+                params,
+            });
+            let started = Instant::now();
+            let run_nodes = async {
+                match deadline {
+                    Some(deadline) => {
+                        FLOW_DEADLINE
+                            .scope(
+                                deadline,
+                                self.run_nodes(flow, params, shared, position, Some(deadline)),
+                            )
+                            .await
+                    }
+                    None => self.run_nodes(flow, params, shared, position, None).await,
                 }
-            } else {
-                (HashMap::new(), HashMap::new())
             };
-        let mut current: Option<Executable> = Some(self.start.clone());
-        let mut last_action: String = "".into();
+            let outcome = span.instrument(run_nodes).await;
+            observer::emit(observers.as_ref(), || FlowEvent::FlowEnd {
+                flow: info,
+                outcome: outcome.as_ref().map(Option::as_deref),
+                duration: started.elapsed(),
+            });
+            outcome
+        };
+        let outcome = observer::scoped(observers.clone(), run).await;
+        span.record(&outcome);
+        outcome
+    }
+
+    /// The orchestration logic, `deadline` being the instant at which the run is abandoned
+    async fn run_nodes(
+        &self,
+        flow: &NodeCore<S>,
+        params: &HashMap<String, NodeValue>,
+        shared: &mut S,
+        position: Position,
+        deadline: Option<Instant>,
+    ) -> Result<Option<String>, NodeError> {
+        let observers = observer::current();
+        let graph = &self.graph;
+        let Position {
+            next: mut current,
+            mut step,
+            mut last_action,
+        } = position;
+        let mut tracker = self.loop_guard.track();
+        if self.checkpoints.is_some() {
+            checkpoint::require_stable_ids(flow, graph)?;
+        }
 
         // This is the orchestration logic
-        while let Some(mut curr) = current {
-            last_action = match curr {
-                Sync(ref mut sync_node) => {
-                    let mut sync_clone = sync_node.clone();
-                    sync_clone.set_params(params.clone());
-                    let mut shared_clone = shared.clone();
-
-                    // not ideal, but not cloning here would
-                    // require a significant refactoring
-                    // afaik (switching everything to use
-                    // Arc/Rc)
-                    // Will be next step if benchmarking shows me this is actually
-                    // worth the hassle
-                    match tokio::task::spawn_blocking(move || {
-                        let action = sync_clone.run(&mut shared_clone).unwrap_or("default".into());
-                        (action, shared_clone)
-                    })
-                    .await
-                    {
-                        Ok((next_action, modified_shared)) => {
-                            // Happy path: the task completed successfully
-                            shared = modified_shared;
-                            next_action
-                        }
-                        Err(join_error) => {
-                            // The background task panicked!
-                            log::error!("A synchronous node panicked: {:?}", join_error);
-                            // For now, just log it and go to the default action
-                            "default".into()
-                        }
+        while let Some(handle) = current {
+            // Cancellation is checked at node boundaries, the shared state is kept as is
+            if cancellation::is_cancelled() {
+                log::info!("Flow cancelled before step {}.", step);
+                return Err(NodeError::Cancelled);
+            }
+            if let Some(reason) = tracker.exceeded(step) {
+                log::warn!("Flow stopped before step {}: {}.", step, reason);
+                return Ok(Some(MAX_STEPS_EXCEEDED_ACTION.to_string()));
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(NodeError::DeadlineExceeded { step });
+            }
+
+            let run = async {
+                match graph.node(handle) {
+                    Sync(sync_node) => {
+                        // The node may run on another thread, which can't see our task-locals
+                        let observers = observer::current();
+                        let span = spans::current();
+                        run_blocking(|| {
+                            observer::scoped_sync(observers, || {
+                                span.in_scope(|| sync_node.run_with_params(shared, params))
+                            })
+                        })
+                        .unwrap_or_else(|panic| {
+                            log::error!("Synchronous node {} panicked: {}", sync_node.data, panic);
+                            Err(NodeError::PanicError(panic))
+                        })
                     }
-                }
-                Async(ref mut async_node) => {
-                    async_node.set_params(params.clone());
-                    async_node
-                        .run(&mut shared)
-                        .await
-                        .unwrap_or("default".into())
+                    // The flow's params replace the node's own
+                    Async(async_node) => async_node.run_with_params(shared, params).await,
                 }
             };
+            let started = Instant::now();
+            let outcome = match deadline {
+                // The node is abandoned (dropped) if the deadline is reached while it runs. A
+                // synchronous node can't be, it is only seen exceeding it once it returned.
+                Some(deadline) => match tokio::time::timeout_at(deadline, run).await {
+                    Ok(_) if Instant::now() >= deadline => {
+                        Err(NodeError::DeadlineExceeded { step })
+                    }
+                    Ok(outcome) => outcome,
+                    Err(_) => Err(NodeError::DeadlineExceeded { step }),
+                },
+                None => run.await,
+            };
+            observer::emit(observers.as_ref(), || FlowEvent::Step {
+                flow: flow.info(),
+                node: graph.node(handle).data().info(),
+                step,
+                outcome: outcome.as_ref().map(Option::as_deref),
+                duration: started.elapsed(),
+            });
+            // A nested flow hitting the deadline it inherited from us is our deadline too
+            if let Err(NodeError::DeadlineExceeded { .. }) = outcome
+                && deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                return Err(NodeError::DeadlineExceeded { step });
+            }
+
+            // Whatever the node's outcome, a cancelled run stops here. If the node was aborted,
+            // the shared state is the one from before it ran.
+            if cancellation::is_cancelled() {
+                log::info!("Flow cancelled during step {}.", step);
+                return Err(NodeError::Cancelled);
+            }
+
+            // A suspended run is not a failure, it records where we stand to be picked up there
+            if let Err(NodeError::Suspended(mut run)) = outcome {
+                // The run is resumed at the node with this id, which must be the one running
+                let node = &graph.node(handle).data().id;
+                if graph
+                    .handles()
+                    .filter(|&h| graph.node(h).id() == node)
+                    .count()
+                    > 1
+                {
+                    return Err(NodeError::CheckpointError(format!(
+                        "The run of flow {} was suspended at node \"{}\", which other nodes of the \
+                         flow share the id of, so it couldn't be resumed: give them distinct ids \
+                         with `with_id`",
+                        flow, node
+                    )));
+                }
+                run.flows.insert(
+                    0,
+                    SuspendedFlow {
+                        flow: flow.id.clone(),
+                        node: node.clone(),
+                        step,
+                        action: last_action,
+                        params: params.clone(),
+                    },
+                );
+                return Err(NodeError::Suspended(run));
+            }
+
+            // A failing node stops the flow, the error records where it happened
+            let action = outcome.map_err(|source| NodeError::FlowError {
+                node: graph.node(handle).data().id.clone(),
+                step,
+                action: last_action.clone(),
+                source: Box::new(source),
+            })?;
+            last_action = action.unwrap_or("default".into());
+            tracker.record(handle, &last_action);
+            step += 1;
+            current = self
+                .unknown_action
+                .route(graph, handle, &last_action, shared)?;
+            if let Some(checkpoints) = &self.checkpoints {
+                let node = &graph.node(handle).data().id;
+                checkpoints.save(&flow.id, node, &last_action, step, params, shared)?;
+            }
+        }
+        if let Some(checkpoints) = &self.checkpoints {
+            checkpoints.clear(&flow.id)?;
+        }
+        // return the final action (since Flow is also just a node)
+        Ok(Some(last_action))
+    }
+}
 
-            // Uses method implemented on Executable
-            let next_executable = &curr.successors().get(&last_action).cloned();
+/// Runs a synchronous node from the flow's task, on the shared state itself. On a multi-threaded
+/// runtime the worker hands its other tasks over while the node blocks (`block_in_place`), a
+/// current-thread runtime has no other thread to give them to and waits on a scoped thread. Either
+/// way the node is outside of the runtime, so it can block on one of its own (see
+/// `BlockingBridge`). A panic is returned with its message.
+fn run_blocking<R: Send>(f: impl FnOnce() -> R + Send) -> Result<R, String> {
+    let outcome = match tokio::runtime::Handle::current().runtime_flavor() {
+        RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| std::panic::catch_unwind(AssertUnwindSafe(f)))
+        }
+        _ => std::thread::scope(|scope| scope.spawn(f).join()),
+    };
+    outcome.map_err(|panic| {
+        panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "the node panicked".into())
+    })
+}
 
-            current = next_executable.clone();
+#[async_trait]
+impl<S> AsyncNodeLogic<S> for AsyncFlowLogic<S>
+where
+    S: Send + std::marker::Sync + 'static,
+{
+    async fn orchestrate(
+        &self,
+        node: &NodeCore<S>,
+        params: &HashMap<String, NodeValue>,
+        shared: &mut S,
+    ) -> Option<Result<Option<String>, NodeError>> {
+        // The run being resumed picks up where it was suspended
+        match human_node::take_flow(&node.id) {
+            Some(suspended) => match Position::at(&self.graph, &suspended) {
+                Ok(position) => Some(
+                    self.run_flow(node, &suspended.params, shared, position)
+                        .await,
+                ),
+                Err(error) => Some(Err(error)),
+            },
+            None => {
+                let position = Position::start(&self.graph);
+                Some(self.run_flow(node, params, shared, position).await)
+            }
         }
-        serde_json::to_value((last_action.to_string(), shared))
-            .expect("Serializing string and HashMap should be doable")
     }
+
+    // `orchestrate` takes over the whole run, so the lifecycle is never called by `AsyncNode::run`
+    async fn prep(
+        &self,
+        _params: &HashMap<String, NodeValue>,
+        _shared: &S,
+    ) -> Result<NodeValue, NodeError> {
+        Ok(NodeValue::Null)
+    }
+
+    async fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+        Err(NodeError::ExecError(
+            "Flows are run through orchestrate, not exec".into(),
+        ))
+    }
+
     async fn post(
         &self,
-        shared: &mut HashMap<String, NodeValue>,
+        _shared: &mut S,
         _prep_res: NodeValue,
-        exec_res: NodeValue,
-    ) -> Option<String> {
-        let (last_action, shared_post): (String, HashMap<String, NodeValue>) = if let Some(array) =
-            exec_res.as_array()
-        {
-            if array.len() != 2 {
-                log::error!(
-                    "Serialization into array succeeded, but got unexpected length for array: {}! Returning default values",
-                    array.len()
-                );
-                ("default".into(), shared.clone())
-            } else {
-                // happy path
-                let last_action = serde_json::from_value(array[0].clone()).unwrap_or_default();
-                let shared = serde_json::from_value(array[1].clone()).unwrap_or_default();
-                (last_action, shared)
-            }
-        } else {
-            log::error!("Serialization of last action and shared failed! Returning default values");
-            ("default".into(), shared.clone())
-        };
+        _exec_res: NodeValue,
+    ) -> Result<Option<String>, NodeError> {
+        Ok(None)
+    }
 
-        // modify the shared state
-        *shared = shared_post;
+    fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = self.graph.validate(false);
+        issues.extend(self.unknown_action.validate(&self.graph));
+        issues
+    }
 
-        // return the final action (since Flow is also just a node)
-        Some(last_action)
+    fn diagram_kind(&self) -> DiagramKind {
+        DiagramKind::Flow(self.graph.diagram())
     }
-    fn clone_box(&self) -> Box<dyn AsyncNodeLogic> {
+
+    fn clone_box(&self) -> Box<dyn AsyncNodeLogic<S>> {
         Box::new((*self).clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::async_impl::cancellation::{CANCELLED_ACTION, CancellationToken};
+    use crate::core::sync_impl::node::{Node, NodeLogic};
+    use serde_json::json;
+
+    /// Takes `duration` in `exec`, then appends `name` to `shared["log"]`
+    #[derive(Clone)]
+    struct Step {
+        name: &'static str,
+        duration: Duration,
+    }
+
+    #[async_trait]
+    impl AsyncNodeLogic for Step {
+        async fn prep(
+            &self,
+            _params: &HashMap<String, NodeValue>,
+            _shared: &HashMap<String, NodeValue>,
+        ) -> Result<NodeValue, NodeError> {
+            Ok(NodeValue::Null)
+        }
+
+        async fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+            tokio::time::sleep(self.duration).await;
+            Ok(NodeValue::Null)
+        }
+
+        async fn post(
+            &self,
+            shared: &mut HashMap<String, NodeValue>,
+            _prep_res: NodeValue,
+            _exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            let log = shared.entry("log".into()).or_insert(json!([]));
+            log.as_array_mut().unwrap().push(json!(self.name));
+            Ok(None)
+        }
+
+        fn clone_box(&self) -> Box<dyn AsyncNodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    const QUICK: Duration = Duration::ZERO;
+    const SLOW: Duration = Duration::from_secs(5);
+
+    fn step(name: &'static str, duration: Duration) -> AsyncNode {
+        AsyncNode::new(Step { name, duration })
+    }
+
+    #[tokio::test]
+    async fn a_timed_out_node_is_routed_by_its_action() {
+        let fetch = step("fetch", SLOW).with_timeout(
+            TimeoutPolicy::new()
+                .with_exec(Duration::from_millis(20))
+                .on_timeout("slow"),
+        );
+        let flow = AsyncFlow::new(Async(
+            fetch
+                .next(Async(step("parse", QUICK)))
+                .next_on(Async(step("cached", QUICK)), "slow"),
+        ));
+        let mut shared = HashMap::new();
+        assert_eq!(flow.run(&mut shared).await.unwrap(), Some("default".into()));
+        assert_eq!(shared["log"], json!(["cached"]));
+    }
+
+    #[tokio::test]
+    async fn the_deadline_abandons_the_running_node() {
+        let flow = AsyncFlow::new(Async(
+            step("a", QUICK).next(Async(step("b", SLOW).next(Async(step("c", QUICK))))),
+        ))
+        .with_deadline(Duration::from_millis(50));
+        let started = Instant::now();
+        let outcome = flow.run(&mut HashMap::new()).await;
+        assert!(matches!(
+            outcome,
+            Err(NodeError::DeadlineExceeded { step: 1 })
+        ));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn a_nested_flow_routes_its_exceeded_deadline() {
+        let inner = AsyncFlow::new(Async(step("draft", SLOW)))
+            .with_deadline(Duration::from_millis(30))
+            .with_timeout(TimeoutPolicy::new().on_timeout("late"));
+        let flow = AsyncFlow::new(Async(
+            (*inner)
+                .clone()
+                .next(Async(step("publish", QUICK)))
+                .next_on(Async(step("apologise", QUICK)), "late"),
+        ));
+        let mut shared = HashMap::new();
+        assert_eq!(flow.run(&mut shared).await.unwrap(), Some("default".into()));
+        assert_eq!(shared["log"], json!(["apologise"]));
+    }
+
+    #[tokio::test]
+    async fn cancelling_stops_the_flow_after_the_running_node() {
+        let flow = AsyncFlow::new(Async(step("a", QUICK).next(Async(
+            step("b", Duration::from_millis(100)).next(Async(step("c", QUICK))),
+        ))));
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            canceller.cancel();
+        });
+
+        let mut shared = HashMap::new();
+        let outcome = flow.run_with_cancellation(&mut shared, &token).await;
+        assert_eq!(outcome.unwrap().as_deref(), Some(CANCELLED_ACTION));
+        assert_eq!(shared["log"], json!(["a", "b"]));
+    }
+
+    /// A shared state which can't be cloned, `post` counts the visits
+    struct Visits(usize);
+
+    #[derive(Clone)]
+    struct Visit(Duration);
+
+    impl NodeLogic<Visits> for Visit {
+        fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+            std::thread::sleep(self.0);
+            Ok(NodeValue::Null)
+        }
+
+        fn post(
+            &self,
+            shared: &mut Visits,
+            _prep_res: NodeValue,
+            _exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            shared.0 += 1;
+            Ok(None)
+        }
+
+        fn clone_box(&self) -> Box<dyn NodeLogic<Visits>> {
+            Box::new((*self).clone())
+        }
+    }
+
+    fn visits(duration: Duration) -> AsyncFlow<Visits> {
+        let visit = || Node::new(Visit(duration));
+        AsyncFlow::new(Sync(visit().next(Sync(visit()))))
+    }
+
+    #[tokio::test]
+    async fn sync_nodes_run_on_the_shared_state() {
+        let mut shared = Visits(0);
+        visits(QUICK).run(&mut shared).await.unwrap();
+        assert_eq!(shared.0, 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn sync_nodes_hand_the_worker_over() {
+        let mut shared = Visits(0);
+        visits(QUICK).run(&mut shared).await.unwrap();
+        assert_eq!(shared.0, 2);
+    }
+
+    #[tokio::test]
+    async fn the_deadline_waits_for_a_sync_node() {
+        let flow = visits(Duration::from_millis(100)).with_deadline(Duration::from_millis(20));
+        let mut shared = Visits(0);
+        let outcome = flow.run(&mut shared).await;
+        assert!(matches!(
+            outcome,
+            Err(NodeError::DeadlineExceeded { step: 0 })
+        ));
+        assert_eq!(shared.0, 1);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::core::async_impl::async_node::{AsyncNode, AsyncNodeLogic};
+use crate::core::error::NodeError;
+use crate::core::sync_impl::{NodeValue, SharedStore};
+use async_trait::async_trait;
+use futures::future::{self, BoxFuture};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+/// What `prep` and `exec` return, borrowing the shared state for `prep`
+type PhaseFuture<'a> = BoxFuture<'a, Result<NodeValue, NodeError>>;
+/// What `post` returns, borrowing the shared state
+type PostFuture<'a> = BoxFuture<'a, Result<Option<String>, NodeError>>;
+
+type PrepFn<S> =
+    dyn for<'a> Fn(&'a HashMap<String, NodeValue>, &'a S) -> PhaseFuture<'a> + Send + Sync;
+type ExecFn = dyn Fn(NodeValue) -> PhaseFuture<'static> + Send + Sync;
+type PostFn<S> = dyn for<'a> Fn(&'a mut S, NodeValue, NodeValue) -> PostFuture<'a> + Send + Sync;
+
+/// ------- AsyncFnLogic -------------------------------------------------------------
+/// An `AsyncNodeLogic` made of closures, the async counterpart of `FnLogic`.
+/// `exec` is a closure returning a future which owns what it uses (`move |input| async move {..}`,
+/// or an async closure capturing nothing), so it can be awaited on any thread. `prep_async` and
+/// `post_async` take closures returning a boxed future, which borrows the shared state
+/// (`|params, shared| Box::pin(async move {..})`); `prep` and `post` take plain closures, for
+/// phases which don't await.
+/// The phases which are not set do nothing: `prep` and `exec` return `Null` and `post` `None`.
+/// A node needing more than its phases (an `exec_fallback`...) implements `AsyncNodeLogic`.
+pub struct AsyncFnLogic<S = SharedStore> {
+    prep: Option<Arc<PrepFn<S>>>,
+    exec: Option<Arc<ExecFn>>,
+    post: Option<Arc<PostFn<S>>>,
+}
+
+// The closures are shared between clones
+impl<S> Clone for AsyncFnLogic<S> {
+    fn clone(&self) -> Self {
+        AsyncFnLogic {
+            prep: self.prep.clone(),
+            exec: self.exec.clone(),
+            post: self.post.clone(),
+        }
+    }
+}
+
+impl<S> Default for AsyncFnLogic<S> {
+    fn default() -> Self {
+        AsyncFnLogic {
+            prep: None,
+            exec: None,
+            post: None,
+        }
+    }
+}
+
+impl<S: Send + Sync + 'static> AsyncFnLogic<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn prep<F>(self, prep: F) -> Self
+    where
+        F: Fn(&HashMap<String, NodeValue>, &S) -> Result<NodeValue, NodeError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.prep_async(move |params, shared| Box::pin(future::ready(prep(params, shared))))
+    }
+
+    /// Sets a `prep` which awaits, its future borrows the params and the shared state
+    pub fn prep_async<F>(mut self, prep: F) -> Self
+    where
+        F: for<'a> Fn(&'a HashMap<String, NodeValue>, &'a S) -> PhaseFuture<'a>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.prep = Some(Arc::new(prep));
+        self
+    }
+
+    pub fn exec<F, Fut>(mut self, exec: F) -> Self
+    where
+        F: Fn(NodeValue) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<NodeValue, NodeError>> + Send + 'static,
+    {
+        self.exec = Some(Arc::new(move |input| Box::pin(exec(input))));
+        self
+    }
+
+    pub fn post<F>(self, post: F) -> Self
+    where
+        F: Fn(&mut S, NodeValue, NodeValue) -> Result<Option<String>, NodeError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.post_async(move |shared, prep_res, exec_res| {
+            Box::pin(future::ready(post(shared, prep_res, exec_res)))
+        })
+    }
+
+    /// Sets a `post` which awaits, its future borrows the shared state
+    pub fn post_async<F>(mut self, post: F) -> Self
+    where
+        F: for<'a> Fn(&'a mut S, NodeValue, NodeValue) -> PostFuture<'a> + Send + Sync + 'static,
+    {
+        self.post = Some(Arc::new(post));
+        self
+    }
+
+    /// The node running this logic
+    pub fn build(self) -> AsyncNode<S> {
+        AsyncNode::new(self)
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync + 'static> AsyncNodeLogic<S> for AsyncFnLogic<S> {
+    async fn prep(
+        &self,
+        params: &HashMap<String, NodeValue>,
+        shared: &S,
+    ) -> Result<NodeValue, NodeError> {
+        match &self.prep {
+            Some(prep) => prep(params, shared).await,
+            None => Ok(NodeValue::default()),
+        }
+    }
+
+    async fn exec(&self, input: NodeValue) -> Result<NodeValue, NodeError> {
+        match &self.exec {
+            Some(exec) => exec(input).await,
+            None => Ok(NodeValue::default()),
+        }
+    }
+
+    async fn post(
+        &self,
+        shared: &mut S,
+        prep_res: NodeValue,
+        exec_res: NodeValue,
+    ) -> Result<Option<String>, NodeError> {
+        match &self.post {
+            Some(post) => post(shared, prep_res, exec_res).await,
+            None => Ok(None),
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn AsyncNodeLogic<S>> {
+        Box::new((*self).clone())
+    }
+}
+
+impl<S: Send + Sync + 'static> AsyncNode<S> {
+    /// A node running the given closures (`exec` being async), see `AsyncFnLogic` to only set
+    /// some of them, and `AsyncFnLogic::prep_async`/`post_async` for a `prep` or `post` which
+    /// awaits. Annotating the shared state (`|shared: &mut SharedStore, _, exec_res|`) tells
+    /// which one the node works on.
+    pub fn from_fns<P, E, Fut, Q>(prep: P, exec: E, post: Q) -> AsyncNode<S>
+    where
+        P: Fn(&HashMap<String, NodeValue>, &S) -> Result<NodeValue, NodeError>
+            + Send
+            + Sync
+            + 'static,
+        E: Fn(NodeValue) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<NodeValue, NodeError>> + Send + 'static,
+        Q: Fn(&mut S, NodeValue, NodeValue) -> Result<Option<String>, NodeError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        AsyncFnLogic::new().prep(prep).exec(exec).post(post).build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn every_phase_can_await() {
+        let node = AsyncFnLogic::new()
+            .prep_async(|_params, shared: &SharedStore| {
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    Ok(shared["n"].clone())
+                })
+            })
+            .exec(|n: NodeValue| async move {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+                Ok(json!(n.as_i64().unwrap() * 2))
+            })
+            .post_async(|shared, _prep_res, doubled| {
+                Box::pin(async move {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    shared.insert("doubled".into(), doubled);
+                    Ok(Some("done".into()))
+                })
+            })
+            .build();
+        let mut shared = SharedStore::from([("n".to_string(), json!(21))]);
+        assert_eq!(node.run(&mut shared).await.unwrap(), Some("done".into()));
+        assert_eq!(shared["doubled"], json!(42));
+    }
+
+    #[tokio::test]
+    async fn plain_closures_and_unset_phases_mix() {
+        let node = AsyncFnLogic::new()
+            .post(|shared: &mut SharedStore, prep_res, exec_res| {
+                shared.insert("phases".into(), json!([prep_res, exec_res]));
+                Ok(None)
+            })
+            .build();
+        let mut shared = SharedStore::new();
+        assert_eq!(node.run(&mut shared).await.unwrap(), None);
+        assert_eq!(shared["phases"], json!([null, null]));
+    }
+}
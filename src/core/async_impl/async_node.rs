@@ -1,42 +1,82 @@
 use std::collections::HashMap;
 
-use crate::core::Executable;
+use crate::core::async_impl::cancellation::{self, CANCELLED_ACTION, CancellationToken};
+use crate::core::diagram::{DiagramKind, short_type_name};
+use crate::core::error::NodeError;
+use crate::core::graph::{ANY_ACTION, Guard, ValidationIssue};
+use crate::core::observer::{self, FlowEvent, Observers};
+use crate::core::retry::RetryPolicy;
+use crate::core::spans;
 use crate::core::sync_impl::AsAny;
-use crate::core::sync_impl::NodeValue;
 use crate::core::sync_impl::node::NodeCore;
+use crate::core::sync_impl::{NodeValue, SharedStore};
+use crate::core::timeout::{self, TimeoutPolicy};
+use crate::core::{Executable, refuse_reserved_action};
 
 use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Instant;
 
 /// Async Node
-pub struct AsyncNode {
-    pub data: NodeCore,
-    pub behaviour: Box<dyn AsyncNodeLogic>,
+pub struct AsyncNode<S = SharedStore> {
+    pub data: NodeCore<S>,
+    pub behaviour: Box<dyn AsyncNodeLogic<S>>,
+    /// Only async nodes can be interrupted, so only they have time limits
+    pub timeout: TimeoutPolicy,
 }
 
-impl Clone for AsyncNode {
+impl<S: 'static> Clone for AsyncNode<S> {
     fn clone(&self) -> Self {
         Self {
             data: self.data.clone(),
             behaviour: self.behaviour.clone_box(),
+            timeout: self.timeout.clone(),
         }
     }
 }
 
-impl AsyncNode {
-    pub fn new<L: AsyncNodeLogic>(behaviour: L) -> Self {
+impl<S: 'static> AsyncNode<S> {
+    pub fn new<L: AsyncNodeLogic<S>>(behaviour: L) -> Self {
         AsyncNode {
-            data: NodeCore::default(),
+            data: NodeCore::named(short_type_name(behaviour.type_name())),
             behaviour: Box::new(behaviour),
+            timeout: TimeoutPolicy::default(),
         }
     }
+    /// Replaces the generated id, which should be unique within a flow (see `AsyncFlow::find`)
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.data.id = id.to_string();
+        self
+    }
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.data.name = name.to_string();
+        self
+    }
+    pub fn with_description(mut self, description: &str) -> Self {
+        self.data.description = Some(description.to_string());
+        self
+    }
 
     pub fn set_params(&mut self, params: HashMap<String, NodeValue>) {
         self.data.params = params;
     }
-    pub fn next(self, node: Executable) -> Self {
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.data.retry = retry;
+        self
+    }
+    pub fn with_timeout(mut self, timeout: TimeoutPolicy) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// Declares every action the node can return, so flows can check each one has a successor
+    pub fn with_actions(mut self, actions: &[&str]) -> Self {
+        self.data.actions = actions.iter().map(|action| action.to_string()).collect();
+        self
+    }
+    pub fn next(self, node: impl Into<Executable<S>>) -> Self {
         self.next_on(node, "default")
     }
-    pub fn next_on(mut self, node: Executable, action: &str) -> Self {
+    pub fn next_on(mut self, node: impl Into<Executable<S>>, action: &str) -> Self {
         if self.data.successors.contains_key(action) {
             log::warn!(
                 "Warning: Action {} was found in successors, Overwriting key {}.",
@@ -44,43 +84,405 @@ impl AsyncNode {
                 &action
             );
         }
-        self.data.successors.insert(action.to_string(), node);
+        self.data.successors.insert(action.to_string(), node.into());
         self
     }
+    /// The wildcard successor, reached for any action the node has no successor for
+    pub fn next_on_any(self, node: impl Into<Executable<S>>) -> Self {
+        self.next_on(node, ANY_ACTION)
+    }
+    /// Routes to `node` when `predicate` holds on the shared state once `post` ran, whatever
+    /// the action. Guards are checked in the order they were added, before the action's
+    /// successor, so routing decisions can live in the graph rather than in `post`.
+    pub fn next_when<P>(self, predicate: P, node: impl Into<Executable<S>>) -> Self
+    where
+        P: Fn(&S) -> bool + Send + Sync + 'static,
+    {
+        self.guard(None, predicate, node)
+    }
+    /// Routes to `node` when the node returned `action` and `predicate` holds
+    pub fn next_on_when<P>(self, predicate: P, node: impl Into<Executable<S>>, action: &str) -> Self
+    where
+        P: Fn(&S) -> bool + Send + Sync + 'static,
+    {
+        self.guard(Some(action.to_string()), predicate, node)
+    }
+    fn guard<P>(
+        mut self,
+        action: Option<String>,
+        predicate: P,
+        node: impl Into<Executable<S>>,
+    ) -> Self
+    where
+        P: Fn(&S) -> bool + Send + Sync + 'static,
+    {
+        self.data.guards.push(Guard {
+            action,
+            predicate: Arc::new(predicate),
+            node: node.into(),
+        });
+        self
+    }
+}
 
-    pub async fn run(&self, shared: &mut HashMap<String, NodeValue>) -> Option<String> {
-        let p = self.behaviour.prep(&self.data.params, shared).await;
-        let e = self.behaviour.exec(p.clone()).await;
-        self.behaviour.post(shared, p, e).await
+impl<S: Send + Sync + 'static> AsyncNode<S> {
+    /// Runs `prep` -> `exec` -> `post`, stopping at the first phase that fails (or times out).
+    pub async fn run(&self, shared: &mut S) -> Result<Option<String>, NodeError> {
+        self.run_with_params(shared, &self.data.params).await
+    }
+
+    /// Same as `run`, but the run can be stopped through `token`.
+    /// A cancelled run returns the `"cancelled"` action, whatever was run (a node, a flow or a
+    /// batch flow): flows stop at the next node boundary and keep the shared state as it was at
+    /// that point. Within the run, cancellation is a `NodeError::Cancelled`, which is neither
+    /// retried nor routed.
+    pub async fn run_with_cancellation(
+        &self,
+        shared: &mut S,
+        token: &CancellationToken,
+    ) -> Result<Option<String>, NodeError> {
+        if token.is_cancelled() {
+            return Ok(Some(CANCELLED_ACTION.to_string()));
+        }
+        match token.scope(self.run(shared)).await {
+            Err(NodeError::Cancelled) => Ok(Some(CANCELLED_ACTION.to_string())),
+            outcome => outcome,
+        }
     }
 
     pub async fn run_with_params(
         &self,
-        shared: &mut HashMap<String, NodeValue>,
+        shared: &mut S,
         param: &HashMap<String, NodeValue>,
-    ) -> Option<String> {
-        let p = self.behaviour.prep(param, shared).await;
-        let e = self.behaviour.exec(p.clone()).await;
-        self.behaviour.post(shared, p, e).await
+    ) -> Result<Option<String>, NodeError> {
+        let limits = &self.timeout;
+        // Inside an observed flow, the node reports its lifecycle to the flow's observers
+        let observers = observer::current().map(|observers| observers.at(&self.data));
+        let node = self.data.info();
+        let lifecycle = async {
+            if let Some(outcome) = self.behaviour.orchestrate(&self.data, param, shared).await {
+                return outcome;
+            }
+
+            let phases = async {
+                observer::emit(observers.as_ref(), || FlowEvent::PrepStart {
+                    node,
+                    params: param,
+                });
+                let started = Instant::now();
+                let prep = self.behaviour.prep(param, shared);
+                let p =
+                    timeout::within(limits.prep(), "prep", spans::prep().instrument(prep)).await;
+                observer::emit(observers.as_ref(), || FlowEvent::PrepEnd {
+                    node,
+                    outcome: p.as_ref(),
+                    duration: started.elapsed(),
+                });
+
+                let p = p?;
+                let e = self.exec_with_retry(&p, observers.as_ref()).await?;
+
+                observer::emit(observers.as_ref(), || FlowEvent::PostStart {
+                    node,
+                    exec_res: &e,
+                });
+                let started = Instant::now();
+                let post = self.behaviour.post(shared, p, e);
+                let action =
+                    timeout::within(limits.post(), "post", spans::post().instrument(post)).await;
+                let action = refuse_reserved_action(action);
+                observer::emit(observers.as_ref(), || FlowEvent::PostEnd {
+                    node,
+                    outcome: action.as_ref().map(Option::as_deref),
+                    duration: started.elapsed(),
+                });
+                action
+            };
+            let span = spans::node(&self.data);
+            let action = span.instrument(phases).await;
+            span.record(&action);
+            action
+        };
+        let lifecycle = observer::scoped(observers.clone(), lifecycle);
+
+        match timeout::within(limits.run(), "run", lifecycle).await {
+            // Route the timeout instead of failing if the node was told to
+            Err(error @ (NodeError::TimeoutError(_) | NodeError::DeadlineExceeded { .. })) => {
+                match limits.timeout_action() {
+                    Some(action) => {
+                        log::warn!("Node {}: {}, taking action {}.", self.data, error, action);
+                        Ok(Some(action.to_string()))
+                    }
+                    None => Err(error),
+                }
+            }
+            outcome => outcome,
+        }
+    }
+
+    /// Calls `exec` according to the node's `RetryPolicy`, handing the last error to
+    /// `exec_fallback` once no attempts are left.
+    async fn exec_with_retry(
+        &self,
+        prep_res: &NodeValue,
+        observers: Option<&Observers>,
+    ) -> Result<NodeValue, NodeError> {
+        let retry = &self.data.retry;
+        let node = self.data.info();
+        let mut attempt = 1;
+        loop {
+            observer::emit(observers, || FlowEvent::ExecStart {
+                node,
+                input: prep_res,
+                attempt,
+            });
+            let started = Instant::now();
+            let attempt_res = timeout::within(
+                self.timeout.exec(),
+                "exec",
+                spans::exec(attempt).instrument(self.behaviour.exec(prep_res.clone())),
+            )
+            .await;
+            observer::emit(observers, || FlowEvent::ExecEnd {
+                node,
+                attempt,
+                outcome: attempt_res.as_ref(),
+                duration: started.elapsed(),
+            });
+            match attempt_res {
+                Ok(exec_res) => return Ok(exec_res),
+                Err(error) if retry.should_retry(attempt, &error) => {
+                    let delay = retry.delay(attempt);
+                    log::warn!(
+                        "Attempt {}/{} of exec failed for node {}: {}. Retrying in {:?}.",
+                        attempt,
+                        retry.max_attempts(),
+                        self.data,
+                        error,
+                        delay
+                    );
+                    // A run cancelled during the wait doesn't sit through it
+                    cancellation::until_cancelled(async {
+                        tokio::time::sleep(delay).await;
+                        Ok(())
+                    })
+                    .await?;
+                    attempt += 1;
+                }
+                Err(error) => {
+                    return self.behaviour.exec_fallback(prep_res.clone(), error).await;
+                }
+            }
+        }
     }
 }
 
 // More or less the same logic as NodeLogic
 #[async_trait]
-pub trait AsyncNodeLogic: AsAny + Send + Sync + 'static {
+pub trait AsyncNodeLogic<S = SharedStore>: AsAny + Send + Sync + 'static {
     // Required method
-    fn clone_box(&self) -> Box<dyn AsyncNodeLogic>;
+    fn clone_box(&self) -> Box<dyn AsyncNodeLogic<S>>;
 
     async fn prep(
         &self,
         _params: &HashMap<String, NodeValue>,
-        _shared: &HashMap<String, NodeValue>,
-    ) -> NodeValue;
-    async fn exec(&self, _input: NodeValue) -> NodeValue;
+        _shared: &S,
+    ) -> Result<NodeValue, NodeError>;
+    async fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError>;
+    /// Called with the last error once `exec` ran out of attempts.
+    /// Returning `Ok` lets the node carry on to `post` as if `exec` had succeeded.
+    async fn exec_fallback(
+        &self,
+        _prep_res: NodeValue,
+        error: NodeError,
+    ) -> Result<NodeValue, NodeError> {
+        Err(error)
+    }
     async fn post(
         &self,
-        _shared: &mut HashMap<String, NodeValue>,
+        _shared: &mut S,
         _prep_res: NodeValue,
         _exec_res: NodeValue,
-    ) -> Option<String>;
+    ) -> Result<Option<String>, NodeError>;
+    /// Flows drive their nodes on the shared state directly, in place of `prep` -> `exec` ->
+    /// `post` (which could only hand the shared state to `exec` as a `NodeValue`).
+    /// Regular nodes keep the default, `None`, which runs the lifecycle.
+    /// `node` is the node running this logic, which flows report their events as.
+    async fn orchestrate(
+        &self,
+        _node: &NodeCore<S>,
+        _params: &HashMap<String, NodeValue>,
+        _shared: &mut S,
+    ) -> Option<Result<Option<String>, NodeError>> {
+        None
+    }
+    /// The problems found in the graphs the node runs (flows override this, see `Flow::validate`)
+    fn validate(&self) -> Vec<ValidationIssue> {
+        Vec::new()
+    }
+    /// How the node is drawn by `to_dot`/`to_mermaid` (flows and batches override this)
+    fn diagram_kind(&self) -> DiagramKind {
+        DiagramKind::Node
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::async_impl::async_fn_node::AsyncFnLogic;
+    use crate::core::retry::Backoff;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    /// A logic whose `exec` always fails (after `duration`), counting its attempts.
+    /// It fails as a cancelled run would if `cancelled`.
+    #[derive(Clone, Default)]
+    struct Failing {
+        attempts: Arc<AtomicUsize>,
+        duration: Duration,
+        cancelled: bool,
+    }
+
+    #[async_trait]
+    impl AsyncNodeLogic for Failing {
+        fn clone_box(&self) -> Box<dyn AsyncNodeLogic> {
+            Box::new((*self).clone())
+        }
+
+        async fn prep(
+            &self,
+            _params: &HashMap<String, NodeValue>,
+            _shared: &HashMap<String, NodeValue>,
+        ) -> Result<NodeValue, NodeError> {
+            Ok(NodeValue::Null)
+        }
+
+        async fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.duration).await;
+            if self.cancelled {
+                return Err(NodeError::Cancelled);
+            }
+            Err(NodeError::ExecError("down".into()))
+        }
+
+        async fn post(
+            &self,
+            _shared: &mut HashMap<String, NodeValue>,
+            _prep_res: NodeValue,
+            _exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn exec_is_retried_up_to_max_attempts() {
+        let logic = Failing::default();
+        let attempts = logic.attempts.clone();
+        let node = AsyncNode::new(logic).with_retry(RetryPolicy::new(3));
+        let outcome = node.run(&mut HashMap::new()).await;
+        assert!(matches!(outcome, Err(NodeError::ExecError(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    /// A logic whose `exec` takes 5s, counting its attempts
+    fn sleeping() -> Failing {
+        Failing {
+            duration: Duration::from_secs(5),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn a_timed_out_attempt_is_retried() {
+        let logic = sleeping();
+        let attempts = logic.attempts.clone();
+        let node = AsyncNode::new(logic)
+            .with_timeout(TimeoutPolicy::new().with_exec(Duration::from_millis(20)))
+            .with_retry(RetryPolicy::new(2));
+        let outcome = node.run(&mut HashMap::new()).await;
+        assert!(matches!(outcome, Err(NodeError::TimeoutError(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn a_timeout_takes_the_on_timeout_action() {
+        let logic = sleeping();
+        let attempts = logic.attempts.clone();
+        let node = AsyncNode::new(logic)
+            .with_retry(RetryPolicy::new(3))
+            .with_timeout(
+                TimeoutPolicy::new()
+                    .with_run(Duration::from_millis(30))
+                    .on_timeout("slow"),
+            );
+        let started = Instant::now();
+        let outcome = node.run(&mut HashMap::new()).await;
+        assert_eq!(outcome.unwrap().as_deref(), Some("slow"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn cancelling_stops_the_backoff() {
+        let logic = Failing::default();
+        let attempts = logic.attempts.clone();
+        let node = AsyncNode::new(logic)
+            .with_retry(RetryPolicy::new(4).with_backoff(Backoff::fixed(Duration::from_secs(2))));
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            canceller.cancel();
+        });
+
+        let started = Instant::now();
+        let outcome = node
+            .run_with_cancellation(&mut HashMap::new(), &token)
+            .await;
+        assert_eq!(outcome.unwrap().as_deref(), Some(CANCELLED_ACTION));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_exec_is_not_retried() {
+        let logic = Failing {
+            cancelled: true,
+            ..Default::default()
+        };
+        let attempts = logic.attempts.clone();
+        let node = AsyncNode::new(logic).with_retry(RetryPolicy::new(4).retry_on(|_| true));
+        let outcome = node.run(&mut HashMap::new()).await;
+        assert!(matches!(outcome, Err(NodeError::Cancelled)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn a_timed_out_exec_detaches_its_blocking_work() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let finished = done.clone();
+        let node: AsyncNode = AsyncFnLogic::new()
+            .exec(move |_input| {
+                let done = done.clone();
+                async move {
+                    tokio::task::spawn_blocking(move || {
+                        std::thread::sleep(Duration::from_millis(50));
+                        done.fetch_add(1, Ordering::SeqCst);
+                    })
+                    .await
+                    .unwrap();
+                    Ok(NodeValue::Null)
+                }
+            })
+            .build()
+            .with_timeout(TimeoutPolicy::new().with_exec(Duration::from_millis(10)));
+        let outcome = node.run(&mut HashMap::new()).await;
+        assert!(matches!(outcome, Err(NodeError::TimeoutError(_))));
+        assert_eq!(finished.load(Ordering::SeqCst), 0);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+    }
 }
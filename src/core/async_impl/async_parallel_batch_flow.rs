@@ -0,0 +1,529 @@
+use crate::core::Executable;
+use crate::core::async_impl::async_batch_flow::{BatchParamsFn, parse_param_sets};
+use crate::core::async_impl::async_node::{AsyncNode, AsyncNodeLogic};
+use crate::core::async_impl::cancellation;
+use crate::core::async_impl::human_node;
+use crate::core::diagram::{DiagramKind, DiagramNode};
+use crate::core::error::NodeError;
+use crate::core::graph::ValidationIssue;
+use crate::core::observer;
+use crate::core::spans;
+use crate::core::sync_impl::node::NodeCore;
+use crate::core::sync_impl::{NodeValue, SharedStore};
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+const DEFAULT_MAX_CONCURRENCY: usize = 50;
+
+/// A custom merge: receives the shared state being built, the shared state produced by one
+/// sub-run and the index of its param set.
+pub type MergeFn<S = SharedStore> = Arc<dyn Fn(&mut S, S, usize) + Send + Sync>;
+
+/// How the shared states produced by the concurrent sub-runs are folded back into one.
+/// Sub-runs are always merged in param-set order, whatever order they complete in.
+pub struct MergeStrategy<S = SharedStore> {
+    merge: Merge<S>,
+}
+
+enum Merge<S> {
+    /// Only constructible for `SharedStore`, see `MergeStrategy::changed_keys`
+    ChangedKeys(fn(&S, &mut S, S)),
+    Custom(MergeFn<S>),
+}
+
+impl<S> Clone for MergeStrategy<S> {
+    fn clone(&self) -> Self {
+        let merge = match &self.merge {
+            Merge::ChangedKeys(merge) => Merge::ChangedKeys(*merge),
+            Merge::Custom(merge) => Merge::Custom(merge.clone()),
+        };
+        MergeStrategy { merge }
+    }
+}
+
+impl MergeStrategy {
+    /// Every key a sub-run inserted, modified or removed (compared to the shared state the batch
+    /// started from) is applied to the result. When several sub-runs wrote the same key, the one
+    /// with the last param set wins.
+    /// Only available for the default `SharedStore`, typed states need a `custom` merge:
+    /// ```compile_fail
+    /// use orichalcum::core::async_impl::async_parallel_batch_flow::MergeStrategy;
+    ///
+    /// #[derive(Clone)]
+    /// struct Typed;
+    /// let merge: MergeStrategy<Typed> = MergeStrategy::changed_keys();
+    /// ```
+    pub fn changed_keys() -> Self {
+        MergeStrategy {
+            merge: Merge::ChangedKeys(merge_changed_keys),
+        }
+    }
+}
+
+impl<S> MergeStrategy<S> {
+    /// Hands every sub-run's shared state to `merge`
+    pub fn custom<M>(merge: M) -> Self
+    where
+        M: Fn(&mut S, S, usize) + Send + Sync + 'static,
+    {
+        MergeStrategy {
+            merge: Merge::Custom(Arc::new(merge)),
+        }
+    }
+
+    fn merge(&self, original: &S, merged: &mut S, sub_shared: S, index: usize) {
+        match &self.merge {
+            Merge::ChangedKeys(merge) => merge(original, merged, sub_shared),
+            Merge::Custom(merge) => merge(merged, sub_shared, index),
+        }
+    }
+}
+
+impl<S: 'static> MergeStrategy<S> {
+    /// `changed_keys` if `S` is the default `SharedStore`
+    pub(crate) fn changed_keys_if_shared_store() -> Option<Self> {
+        let changed_keys: Box<dyn Any> = Box::new(MergeStrategy::changed_keys());
+        changed_keys.downcast::<Self>().ok().map(|merge| *merge)
+    }
+}
+
+fn merge_changed_keys(original: &SharedStore, merged: &mut SharedStore, sub_shared: SharedStore) {
+    for key in original.keys() {
+        if !sub_shared.contains_key(key) {
+            merged.remove(key);
+        }
+    }
+    for (key, value) in sub_shared {
+        if original.get(&key) != Some(&value) {
+            merged.insert(key, value);
+        }
+    }
+}
+
+/// Runs a flow once per param set (like `AsyncBatchFlow`), but concurrently.
+/// Every sub-run gets its own copy of the shared state, the copies are then folded back
+/// according to the `MergeStrategy` (`MergeStrategy::changed_keys` by default).
+/// Synchronous nodes block the batch while they run, use `AsyncNode`s for work to overlap.
+/// Its sub-runs can't be suspended by a human node, see `new_human_node`.
+pub struct AsyncParallelBatchFlow<S = SharedStore>(AsyncNode<S>);
+
+impl<S: 'static> Clone for AsyncParallelBatchFlow<S> {
+    fn clone(&self) -> Self {
+        AsyncParallelBatchFlow(self.0.clone())
+    }
+}
+
+/// The Derefs are needed to be able to access the inside `AsyncNode` easily
+impl<S> std::ops::Deref for AsyncParallelBatchFlow<S> {
+    type Target = AsyncNode<S>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<S> From<AsyncParallelBatchFlow<S>> for Executable<S> {
+    fn from(flow: AsyncParallelBatchFlow<S>) -> Self {
+        Executable::Async(flow.0)
+    }
+}
+
+impl<S> std::ops::DerefMut for AsyncParallelBatchFlow<S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+pub struct AsyncParallelBatchFlowLogic<S = SharedStore> {
+    flow: AsyncNode<S>,
+    prep_fn: BatchParamsFn<S>,
+    max_concurrency: usize,
+    merge: MergeStrategy<S>,
+}
+
+impl<S: 'static> Clone for AsyncParallelBatchFlowLogic<S> {
+    fn clone(&self) -> Self {
+        AsyncParallelBatchFlowLogic {
+            flow: self.flow.clone(),
+            prep_fn: self.prep_fn.clone(),
+            max_concurrency: self.max_concurrency,
+            merge: self.merge.clone(),
+        }
+    }
+}
+
+impl AsyncParallelBatchFlow {
+    pub fn new<F>(flow: AsyncNode, prep_fn: F) -> Self
+    where
+        F: Fn(&HashMap<String, NodeValue>, &SharedStore) -> NodeValue + Send + Sync + 'static,
+    {
+        Self::new_with_merge(flow, prep_fn, MergeStrategy::changed_keys())
+    }
+}
+
+impl<S> AsyncParallelBatchFlow<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    /// Typed shared states can't be merged key by key, so they must say how to merge sub-runs
+    pub fn new_with_merge<F>(flow: AsyncNode<S>, prep_fn: F, merge: MergeStrategy<S>) -> Self
+    where
+        F: Fn(&HashMap<String, NodeValue>, &S) -> NodeValue + Send + Sync + 'static,
+    {
+        AsyncParallelBatchFlow(
+            AsyncNode::new(AsyncParallelBatchFlowLogic {
+                flow,
+                prep_fn: Arc::new(prep_fn),
+                max_concurrency: DEFAULT_MAX_CONCURRENCY,
+                merge,
+            })
+            .with_name("async parallel batch flow"),
+        )
+    }
+
+    pub fn with_id(self, id: &str) -> Self {
+        AsyncParallelBatchFlow(self.0.with_id(id))
+    }
+
+    pub fn with_name(self, name: &str) -> Self {
+        AsyncParallelBatchFlow(self.0.with_name(name))
+    }
+
+    pub fn with_description(self, description: &str) -> Self {
+        AsyncParallelBatchFlow(self.0.with_description(description))
+    }
+
+    /// At most `max_concurrency` sub-runs are in flight at the same time
+    pub fn with_concurrency(mut self, max_concurrency: usize) -> Self {
+        assert!(
+            max_concurrency > 0,
+            "Max concurrency must be greater than 0"
+        );
+        self.logic_mut().max_concurrency = max_concurrency;
+        self
+    }
+
+    pub fn with_merge(mut self, merge: MergeStrategy<S>) -> Self {
+        self.logic_mut().merge = merge;
+        self
+    }
+
+    fn logic_mut(&mut self) -> &mut AsyncParallelBatchFlowLogic<S> {
+        let behaviour: &mut dyn AsyncNodeLogic<S> = &mut *self.behaviour;
+
+        if let Some(logic) = behaviour
+            .as_any_mut()
+            .downcast_mut::<AsyncParallelBatchFlowLogic<S>>()
+        {
+            logic
+        } else {
+            // This should never happen, the factory is the only way to build the newtype
+            panic!(
+                "Error: AsyncParallelBatchFlow's logic is not of type AsyncParallelBatchFlowLogic"
+            );
+        }
+    }
+}
+
+impl<S> AsyncParallelBatchFlowLogic<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    async fn run_batch(
+        &self,
+        params: &HashMap<String, NodeValue>,
+        shared: &mut S,
+    ) -> Result<Option<String>, NodeError> {
+        // Call the user-defined closure
+        let params_array = parse_param_sets((self.prep_fn)(params, shared))?;
+        let original: &S = shared;
+        let total = params_array.len();
+
+        let run_all = async {
+            let mut sub_runs = stream::iter(params_array.into_iter().enumerate())
+                .map(|(index, params)| {
+                    let mut combined_params: HashMap<String, NodeValue> = params;
+                    combined_params.extend(self.flow.data.params.clone());
+                    let mut sub_shared = original.clone();
+                    async move {
+                        let started = Instant::now();
+                        let sub_run = self.flow.run_with_params(&mut sub_shared, &combined_params);
+                        let outcome = spans::batch_item(index).instrument(sub_run).await;
+                        observer::batch_item(
+                            index,
+                            total,
+                            outcome.as_ref().map(|_| ()),
+                            started.elapsed(),
+                        );
+                        outcome.map(|_| (index, sub_shared)).map_err(|source| {
+                            NodeError::BatchError {
+                                index,
+                                source: Box::new(human_node::refuse_in_batch(source)),
+                            }
+                        })
+                    }
+                })
+                .buffered(self.max_concurrency);
+
+            // `buffered` hands the sub-runs back in param-set order, so merging is deterministic.
+            // The first failing sub-run fails the batch (dropping the ones still in flight).
+            let mut merged = original.clone();
+            while let Some(sub_run) = sub_runs.next().await {
+                let (index, sub_shared) = sub_run?;
+                self.merge.merge(original, &mut merged, sub_shared, index);
+            }
+            Ok(merged)
+        };
+        // Cancelling the run drops (aborts) every sub-run still in flight
+        *shared = cancellation::until_cancelled(run_all).await?;
+
+        // Same as `BatchFlowLogic`, this allows basic chaining
+        Ok(Some("default".into()))
+    }
+}
+
+#[async_trait]
+impl<S> AsyncNodeLogic<S> for AsyncParallelBatchFlowLogic<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    async fn orchestrate(
+        &self,
+        node: &NodeCore<S>,
+        params: &HashMap<String, NodeValue>,
+        shared: &mut S,
+    ) -> Option<Result<Option<String>, NodeError>> {
+        let span = spans::batch_flow(node);
+        let outcome = span.instrument(self.run_batch(params, shared)).await;
+        span.record(&outcome);
+        Some(outcome)
+    }
+
+    // `orchestrate` takes over the whole run, so the lifecycle is never called by `AsyncNode::run`
+    async fn prep(
+        &self,
+        _params: &HashMap<String, NodeValue>,
+        _shared: &S,
+    ) -> Result<NodeValue, NodeError> {
+        Ok(NodeValue::Null)
+    }
+
+    async fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+        Err(NodeError::ExecError(
+            "Batch flows are run through orchestrate, not exec".into(),
+        ))
+    }
+
+    async fn post(
+        &self,
+        _shared: &mut S,
+        _prep_res: NodeValue,
+        _exec_res: NodeValue,
+    ) -> Result<Option<String>, NodeError> {
+        Ok(None)
+    }
+
+    // The batched flow is the one holding a graph
+    fn validate(&self) -> Vec<ValidationIssue> {
+        self.flow.behaviour.validate()
+    }
+
+    fn diagram_kind(&self) -> DiagramKind {
+        DiagramKind::ParallelBatchFlow(DiagramNode::from(&self.flow).into())
+    }
+
+    fn clone_box(&self) -> Box<dyn AsyncNodeLogic<S>> {
+        Box::new((*self).clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::async_impl::async_flow::AsyncFlow;
+    use crate::core::async_impl::cancellation::{CANCELLED_ACTION, CancellationToken};
+    use crate::core::async_impl::human_node::new_human_node;
+    use serde_json::json;
+    use std::time::Duration;
+
+    /// Waits `50 - 10 * n` ms (so the later param sets finish first), then writes
+    /// `shared["item<n>"]` and `shared["last"]`
+    #[derive(Clone)]
+    struct Item;
+
+    #[async_trait]
+    impl AsyncNodeLogic for Item {
+        async fn prep(
+            &self,
+            params: &HashMap<String, NodeValue>,
+            _shared: &HashMap<String, NodeValue>,
+        ) -> Result<NodeValue, NodeError> {
+            Ok(params["n"].clone())
+        }
+
+        async fn exec(&self, n: NodeValue) -> Result<NodeValue, NodeError> {
+            let wait = 50 - 10 * n.as_u64().unwrap();
+            tokio::time::sleep(Duration::from_millis(wait)).await;
+            Ok(n)
+        }
+
+        async fn post(
+            &self,
+            shared: &mut HashMap<String, NodeValue>,
+            _prep_res: NodeValue,
+            n: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            shared.insert(format!("item{}", n), n.clone());
+            shared.insert("last".into(), n);
+            Ok(None)
+        }
+
+        fn clone_box(&self) -> Box<dyn AsyncNodeLogic> {
+            Box::new(self.clone())
+        }
+    }
+
+    fn three_items(_: &HashMap<String, NodeValue>, _: &HashMap<String, NodeValue>) -> NodeValue {
+        json!([{ "n": 0 }, { "n": 1 }, { "n": 2 }])
+    }
+
+    #[tokio::test]
+    async fn sub_runs_are_merged_in_param_set_order() {
+        let flow = AsyncParallelBatchFlow::new(AsyncNode::new(Item), three_items);
+        let mut shared = HashMap::from([("kept".to_string(), json!(true))]);
+        flow.run(&mut shared).await.unwrap();
+
+        assert_eq!(shared["kept"], json!(true));
+        assert_eq!(shared["item0"], json!(0));
+        assert_eq!(shared["item1"], json!(1));
+        assert_eq!(shared["item2"], json!(2));
+        // The last param set wins, although it completed first
+        assert_eq!(shared["last"], json!(2));
+    }
+
+    #[tokio::test]
+    async fn a_custom_merge_sees_every_sub_run() {
+        let flow = AsyncParallelBatchFlow::new(AsyncNode::new(Item), three_items).with_merge(
+            MergeStrategy::custom(|merged: &mut SharedStore, sub_shared: SharedStore, index| {
+                let order = merged.entry("order".into()).or_insert(json!([]));
+                order.as_array_mut().unwrap().push(json!(index));
+                assert_eq!(sub_shared["last"], json!(index));
+            }),
+        );
+        let mut shared = HashMap::new();
+        flow.run(&mut shared).await.unwrap();
+        assert_eq!(shared["order"], json!([0, 1, 2]));
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_batch_reports_it() {
+        let flow = AsyncParallelBatchFlow::new(AsyncNode::new(Item), three_items);
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            canceller.cancel();
+        });
+        let mut shared = HashMap::new();
+        let outcome = flow.run_with_cancellation(&mut shared, &token).await;
+        assert_eq!(outcome.unwrap().as_deref(), Some(CANCELLED_ACTION));
+        assert!(shared.is_empty());
+    }
+
+    #[tokio::test]
+    async fn custom_merges_fold_typed_states() {
+        #[derive(Clone, Default)]
+        struct Sum {
+            total: i64,
+            seen: Vec<usize>,
+        }
+
+        /// Adds its `n` param to the total
+        #[derive(Clone)]
+        struct Add;
+
+        #[async_trait]
+        impl AsyncNodeLogic<Sum> for Add {
+            async fn prep(
+                &self,
+                params: &HashMap<String, NodeValue>,
+                _sum: &Sum,
+            ) -> Result<NodeValue, NodeError> {
+                Ok(params["n"].clone())
+            }
+
+            async fn exec(&self, n: NodeValue) -> Result<NodeValue, NodeError> {
+                Ok(n)
+            }
+
+            async fn post(
+                &self,
+                sum: &mut Sum,
+                _prep_res: NodeValue,
+                n: NodeValue,
+            ) -> Result<Option<String>, NodeError> {
+                sum.total += n.as_i64().unwrap();
+                Ok(None)
+            }
+
+            fn clone_box(&self) -> Box<dyn AsyncNodeLogic<Sum>> {
+                Box::new(self.clone())
+            }
+        }
+
+        let merge = MergeStrategy::custom(|merged: &mut Sum, sub_run: Sum, index| {
+            merged.total += sub_run.total;
+            merged.seen.push(index);
+        });
+        let flow = AsyncParallelBatchFlow::new_with_merge(
+            AsyncNode::new(Add),
+            |_, _: &Sum| json!([{ "n": 1 }, { "n": 2 }, { "n": 3 }]),
+            merge,
+        )
+        .with_concurrency(2);
+        let mut sum = Sum::default();
+        flow.run(&mut sum).await.unwrap();
+        assert_eq!(sum.total, 6);
+        assert_eq!(sum.seen, [0, 1, 2]);
+    }
+
+    #[test]
+    fn only_the_shared_store_has_a_key_by_key_merge() {
+        assert!(MergeStrategy::<SharedStore>::changed_keys_if_shared_store().is_some());
+        assert!(MergeStrategy::<Vec<i64>>::changed_keys_if_shared_store().is_none());
+    }
+
+    #[tokio::test]
+    async fn human_nodes_fail_the_batch() {
+        let review = new_human_node(Item).with_id("review");
+        let flow = AsyncParallelBatchFlow::new(review, three_items);
+        match flow.run(&mut HashMap::new()).await {
+            Err(NodeError::BatchError { index: 0, source }) => {
+                assert!(matches!(*source, NodeError::CheckpointError(_)))
+            }
+            outcome => panic!("expected the first param set to fail, got {:?}", outcome),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_batch_stops_its_flow() {
+        let batch = AsyncParallelBatchFlow::new(AsyncNode::new(Item), three_items);
+        let after = AsyncParallelBatchFlow::new(AsyncNode::new(Item), |_, _| json!([{ "n": 4 }]));
+        let flow = AsyncFlow::new(batch.0.next(after));
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            canceller.cancel();
+        });
+        let mut shared = HashMap::new();
+        let outcome = flow.run_with_cancellation(&mut shared, &token).await;
+        // Same outcome as a cancelled flow or node, and the node after the batch never runs
+        assert_eq!(outcome.unwrap().as_deref(), Some(CANCELLED_ACTION));
+        assert!(shared.is_empty());
+    }
+}
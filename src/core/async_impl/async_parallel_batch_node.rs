@@ -1,82 +1,286 @@
 use crate::core::async_impl::async_node::{AsyncNode, AsyncNodeLogic};
+use crate::core::async_impl::cancellation;
+use crate::core::async_impl::rate_limit::RateLimiter;
+use crate::core::diagram::DiagramKind;
+use crate::core::error::NodeError;
+use crate::core::observer;
+use crate::core::spans;
 use crate::core::sync_impl::NodeValue;
 use async_trait::async_trait;
-use futures::stream::{FuturesOrdered, StreamExt};
+use futures::stream::{self, StreamExt};
+use serde_json::json;
 use std::collections::HashMap;
+use std::time::Instant;
 
 const DEFAULT_MAX_CONCURRENCY: usize = 50;
 
 #[derive(Clone)]
-pub struct AsyncParallelBatchLogic<L: AsyncNodeLogic> {
+pub struct AsyncParallelBatchLogic<L> {
     logic: L,
     max_concurrency: usize,
+    rate_limit: Option<RateLimiter>,
+    collect_outcomes: bool,
 }
 
-impl<L: AsyncNodeLogic> AsyncParallelBatchLogic<L> {
+impl<L> AsyncParallelBatchLogic<L> {
     pub fn new(logic: L) -> Self {
         AsyncParallelBatchLogic {
             logic,
             max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            rate_limit: None,
+            collect_outcomes: false,
         }
     }
 
+    /// At most `max_concurrency` items are executed at the same time
     pub fn with_concurrency(self, max_concurrency: usize) -> Self {
         assert!(
             max_concurrency > 0,
             "Max concurrency must be greater than 0"
         );
         AsyncParallelBatchLogic {
-            logic: self.logic,
             max_concurrency,
+            ..self
         }
     }
+
+    /// Every item waits for `limiter` before being executed
+    pub fn with_rate_limit(self, limiter: RateLimiter) -> Self {
+        AsyncParallelBatchLogic {
+            rate_limit: Some(limiter),
+            ..self
+        }
+    }
+
+    /// By default, the first failing item fails the whole batch.
+    /// With this, every item runs to completion and `post` receives one outcome per item
+    /// (`{"ok": <exec result>}` or `{"error": "<message>"}`, see `item_outcome`).
+    pub fn collect_outcomes(self) -> Self {
+        AsyncParallelBatchLogic {
+            collect_outcomes: true,
+            ..self
+        }
+    }
+}
+
+/// Reads back one of the outcomes handed to `post` by a batch created with `collect_outcomes`
+pub fn item_outcome(outcome: &NodeValue) -> Result<&NodeValue, &str> {
+    match (outcome.get("ok"), outcome.get("error")) {
+        (Some(value), _) => Ok(value),
+        (None, Some(error)) => Err(error.as_str().unwrap_or("unknown error")),
+        (None, None) => Err("not a batch item outcome"),
+    }
 }
 
 #[async_trait]
-impl<L: AsyncNodeLogic + Clone> AsyncNodeLogic for AsyncParallelBatchLogic<L> {
+impl<S, L> AsyncNodeLogic<S> for AsyncParallelBatchLogic<L>
+where
+    S: Send + Sync + 'static,
+    L: AsyncNodeLogic<S> + Clone,
+{
     async fn prep(
         &self,
         params: &HashMap<String, NodeValue>,
-        shared: &HashMap<String, NodeValue>,
-    ) -> NodeValue {
+        shared: &S,
+    ) -> Result<NodeValue, NodeError> {
         self.logic.prep(params, shared).await
     }
 
-    async fn exec(&self, items: NodeValue) -> NodeValue {
+    async fn exec(&self, items: NodeValue) -> Result<NodeValue, NodeError> {
         // Check that input is indeed an array
         if let Some(arr) = items.as_array() {
-            let mut results: Vec<NodeValue> = Vec::new();
+            let collect = async {
+                let mut results: Vec<NodeValue> = Vec::with_capacity(arr.len());
 
-            let futures = arr.iter().map(|item| self.logic.exec(item.clone()));
-            let mut futures_ordered : FuturesOrdered<_>= futures.collect();
+                // `buffered` only polls `max_concurrency` items at once, and hands the results
+                // back in order
+                let mut outcomes = stream::iter(arr.iter().cloned().enumerate())
+                    .map(|(index, item)| async move {
+                        if let Some(limiter) = &self.rate_limit {
+                            limiter.acquire().await;
+                        }
+                        let started = Instant::now();
+                        let outcome = spans::batch_item(index)
+                            .instrument(self.logic.exec(item))
+                            .await;
+                        (outcome, started.elapsed())
+                    })
+                    .buffered(self.max_concurrency);
 
-            while let Some(result) = futures_ordered.next().await {
-                results.push(result);
-            }
-            results.into()
+                // Results come back in order, so the position is the item's index
+                while let Some((result, duration)) = outcomes.next().await {
+                    let index = results.len();
+                    observer::batch_item(index, arr.len(), result.as_ref().map(|_| ()), duration);
+                    let value = match result {
+                        Ok(value) if self.collect_outcomes => json!({ "ok": value }),
+                        Err(error) if self.collect_outcomes => {
+                            log::warn!("Batch item {} failed: {}", index, error);
+                            json!({ "error": error.to_string() })
+                        }
+                        // Dropping `outcomes` aborts the items still in flight
+                        result => result.map_err(|source| NodeError::BatchError {
+                            index,
+                            source: Box::new(source),
+                        })?,
+                    };
+                    results.push(value);
+                }
+                Ok(results.into())
+            };
+            // Cancelling the run drops (aborts) every item still in flight
+            cancellation::until_cancelled(collect).await
         } else {
             log::error!("items is not an array");
-            NodeValue::Null
+            Err(NodeError::ExecError(
+                "AsyncParallelBatchLogic expects prep to return an array of items".into(),
+            ))
         }
     }
 
     async fn post(
         &self,
-        shared: &mut HashMap<String, NodeValue>,
+        shared: &mut S,
         prep_res: NodeValue,
         exec_res: NodeValue,
-    ) -> Option<String> {
+    ) -> Result<Option<String>, NodeError> {
         self.logic.post(shared, prep_res, exec_res).await
     }
 
-    fn clone_box(&self) -> Box<dyn AsyncNodeLogic> {
+    fn diagram_kind(&self) -> DiagramKind {
+        DiagramKind::ParallelBatch
+    }
+
+    fn clone_box(&self) -> Box<dyn AsyncNodeLogic<S>> {
         Box::new((*self).clone())
     }
 }
 
 /// The `AsyncBatchNode` factory
-pub fn new_async_parallel_batch_node<L: AsyncNodeLogic + Clone>(
-    logic: AsyncParallelBatchLogic<L>,
-) -> AsyncNode {
+pub fn new_async_parallel_batch_node<S, L>(logic: AsyncParallelBatchLogic<L>) -> AsyncNode<S>
+where
+    S: Send + Sync + 'static,
+    L: AsyncNodeLogic<S> + Clone,
+{
     AsyncNode::new(logic)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Doubles every item after a short wait, keeping track of how many items run at once.
+    /// Odd items fail if `fail_odd`.
+    #[derive(Clone, Default)]
+    struct Doubler {
+        running: Arc<AtomicUsize>,
+        most_running: Arc<AtomicUsize>,
+        fail_odd: bool,
+    }
+
+    #[async_trait]
+    impl AsyncNodeLogic for Doubler {
+        async fn prep(
+            &self,
+            _params: &HashMap<String, NodeValue>,
+            shared: &HashMap<String, NodeValue>,
+        ) -> Result<NodeValue, NodeError> {
+            Ok(shared["items"].clone())
+        }
+
+        async fn exec(&self, item: NodeValue) -> Result<NodeValue, NodeError> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_running.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+
+            let n = item.as_i64().unwrap();
+            if self.fail_odd && n % 2 == 1 {
+                return Err(NodeError::ExecError(format!("odd {}", n)));
+            }
+            Ok(json!(n * 2))
+        }
+
+        async fn post(
+            &self,
+            shared: &mut HashMap<String, NodeValue>,
+            _prep_res: NodeValue,
+            exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            shared.insert("results".to_string(), exec_res);
+            Ok(None)
+        }
+
+        fn clone_box(&self) -> Box<dyn AsyncNodeLogic> {
+            Box::new(self.clone())
+        }
+    }
+
+    fn shared_with_items(count: i64) -> HashMap<String, NodeValue> {
+        HashMap::from([("items".to_string(), json!((0..count).collect::<Vec<_>>()))])
+    }
+
+    #[tokio::test]
+    async fn never_runs_more_items_than_the_concurrency_cap() {
+        let logic = Doubler::default();
+        let most_running = logic.most_running.clone();
+        let node =
+            new_async_parallel_batch_node(AsyncParallelBatchLogic::new(logic).with_concurrency(3));
+
+        let mut shared = shared_with_items(12);
+        node.run(&mut shared).await.unwrap();
+        assert_eq!(most_running.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            shared["results"],
+            json!((0..12).map(|n| n * 2).collect::<Vec<_>>())
+        );
+    }
+
+    #[tokio::test]
+    async fn collected_outcomes_keep_successes_and_report_failures_in_order() {
+        let logic = Doubler {
+            fail_odd: true,
+            ..Default::default()
+        };
+        let node = new_async_parallel_batch_node(
+            AsyncParallelBatchLogic::new(logic)
+                .with_concurrency(2)
+                .collect_outcomes(),
+        );
+
+        let mut shared = shared_with_items(4);
+        node.run(&mut shared).await.unwrap();
+        let outcomes: Vec<_> = shared["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(item_outcome)
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                Ok(&json!(0)),
+                Err("Error occurred during exec: odd 1"),
+                Ok(&json!(4)),
+                Err("Error occurred during exec: odd 3"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn without_collect_outcomes_the_first_failure_fails_the_batch() {
+        let logic = Doubler {
+            fail_odd: true,
+            ..Default::default()
+        };
+        let node = new_async_parallel_batch_node(AsyncParallelBatchLogic::new(logic));
+
+        let outcome = node.run(&mut shared_with_items(4)).await;
+        assert!(matches!(
+            outcome,
+            Err(NodeError::BatchError { index: 1, .. })
+        ));
+    }
+}
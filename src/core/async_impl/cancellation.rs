@@ -0,0 +1,92 @@
+use crate::core::error::NodeError;
+use futures::future::{self, Either};
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+
+/// The action returned by a run which was stopped through its `CancellationToken`, nodes can't
+/// return it themselves (see `RESERVED_ACTIONS`)
+pub const CANCELLED_ACTION: &str = "cancelled";
+
+tokio::task_local! {
+    /// The token of the run in progress, so nested flows and batch nodes can observe it
+    static CANCELLATION_TOKEN: CancellationToken;
+}
+
+/// A cheap to clone handle used to stop an `AsyncFlow` (or `AsyncNode`) run from the outside.
+/// Cancellation is cooperative: flows stop at the next node boundary and
+/// `AsyncParallelBatchLogic` aborts the items still in flight.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<TokenState>,
+}
+
+#[derive(Default)]
+struct TokenState {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Completes once the token is cancelled
+    pub async fn cancelled(&self) {
+        loop {
+            let mut notified = pin!(self.inner.notify.notified());
+            // Register before checking the flag so a concurrent `cancel` can't be missed
+            notified.as_mut().enable();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Runs `fut` with this token visible to every flow and batch node it drives
+    pub(crate) async fn scope<F: Future>(&self, fut: F) -> F::Output {
+        CANCELLATION_TOKEN.scope(self.clone(), fut).await
+    }
+}
+
+/// The token of the run in progress, if it was started with one
+pub(crate) fn current_token() -> Option<CancellationToken> {
+    CANCELLATION_TOKEN.try_with(|token| token.clone()).ok()
+}
+
+/// Whether the run in progress was cancelled
+pub(crate) fn is_cancelled() -> bool {
+    CANCELLATION_TOKEN
+        .try_with(|token| token.is_cancelled())
+        .unwrap_or(false)
+}
+
+/// Awaits `fut`, abandoning it with `NodeError::Cancelled` if the run in progress gets cancelled
+pub(crate) async fn until_cancelled<T, F>(fut: F) -> Result<T, NodeError>
+where
+    F: Future<Output = Result<T, NodeError>>,
+{
+    match current_token() {
+        Some(token) => {
+            let cancelled = pin!(token.cancelled());
+            match future::select(pin!(fut), cancelled).await {
+                Either::Left((outcome, _)) => outcome,
+                Either::Right(_) => Err(NodeError::Cancelled),
+            }
+        }
+        None => fut.await,
+    }
+}
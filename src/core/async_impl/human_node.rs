@@ -0,0 +1,431 @@
+use crate::core::async_impl::async_node::{AsyncNode, AsyncNodeLogic};
+use crate::core::error::NodeError;
+use crate::core::refuse_reserved_action;
+use crate::core::sync_impl::NodeValue;
+use crate::core::sync_impl::node::{NodeCore, is_generated_id};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+tokio::task_local! {
+    /// The suspended run being resumed, consumed as its flows and its human node are reached
+    static RESUMING: Arc<Resuming>;
+}
+
+/// ------- HumanNode -------------------------------------------------------------
+/// A node waiting for a person (an approval, an answer...). When the run reaches it, `prep`
+/// builds what the person is asked and the `AsyncFlow` run is suspended (see
+/// `AsyncFlow::run_until_suspended`). Once the run is resumed with the person's answer, `post`
+/// receives it as its `exec_res`, merges it into the shared state and picks the action.
+/// `exec` is never called. Batch flows can't be suspended, a human node reached in one of their
+/// runs fails the batch with a `CheckpointError`.
+#[derive(Clone)]
+pub struct HumanLogic<L> {
+    logic: L,
+}
+
+impl<L> HumanLogic<L> {
+    pub fn new(logic: L) -> Self {
+        HumanLogic { logic }
+    }
+}
+
+/// An `AsyncFlow` run stopped by a human node, to be resumed (possibly by another process) with
+/// `AsyncFlow::resume_suspended`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SuspendedRun {
+    /// The id of the human node
+    pub node: String,
+    /// What the person is asked, built by the node's `prep`
+    pub payload: NodeValue,
+    /// Where the flows the node is nested in stood, the outermost first
+    pub flows: Vec<SuspendedFlow>,
+    /// The shared state when the run was suspended (`Null` if it was suspended through `run`)
+    pub shared: NodeValue,
+}
+
+impl SuspendedRun {
+    /// The run is picked up by the ids of the human node and of the flows it is nested in, so
+    /// they must be the same in the process resuming it and tell these apart. Generated ids are
+    /// not the same from one process to the next, and a flow (or the human node) sharing the id
+    /// of another one along the run could pick up where that one stood.
+    pub(crate) fn require_stable_ids(&self) -> Result<(), NodeError> {
+        let frames = self
+            .flows
+            .iter()
+            .flat_map(|suspended| [suspended.flow.as_str(), suspended.node.as_str()]);
+        let generated: BTreeSet<&str> = std::iter::once(self.node.as_str())
+            .chain(frames)
+            .filter(|id| is_generated_id(id))
+            .collect();
+        // Each frame's node is the next flow (or the human node), only the flows are counted
+        let mut seen = HashSet::new();
+        let duplicated: BTreeSet<&str> = std::iter::once(self.node.as_str())
+            .chain(self.flows.iter().map(|suspended| suspended.flow.as_str()))
+            .filter(|id| !seen.insert(*id))
+            .collect();
+
+        let quoted = |ids: BTreeSet<&str>| -> String {
+            let ids: Vec<String> = ids.iter().map(|id| format!("\"{}\"", id)).collect();
+            ids.join(", ")
+        };
+        let mut problems = Vec::new();
+        if !generated.is_empty() {
+            problems.push(format!(
+                "refers to ids generated for this process ({})",
+                quoted(generated)
+            ));
+        }
+        if !duplicated.is_empty() {
+            problems.push(format!(
+                "refers to the same id for several of the flows and the human node ({})",
+                quoted(duplicated)
+            ));
+        }
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(NodeError::CheckpointError(format!(
+            "The run suspended at node \"{}\" {}, so it couldn't be resumed: give the human node \
+             and the flows it is nested in distinct ids with `with_id`",
+            self.node,
+            problems.join(" and ")
+        )))
+    }
+}
+
+/// Where a flow stood when its run was suspended
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SuspendedFlow {
+    /// The id of the flow
+    pub flow: String,
+    /// The id of the node it was running: the human node, or the flow it is nested in
+    pub node: String,
+    pub step: usize,
+    /// The action which led to the node
+    pub action: String,
+    pub params: HashMap<String, NodeValue>,
+}
+
+/// How a run which can be suspended ended
+#[derive(Clone, Debug, PartialEq)]
+pub enum RunStatus {
+    /// The flow reached its end with this action
+    Completed(Option<String>),
+    Suspended(SuspendedRun),
+}
+
+/// What is left of the suspended run being resumed
+pub(crate) struct Resuming {
+    flows: Mutex<VecDeque<SuspendedFlow>>,
+    node: String,
+    payload: NodeValue,
+    answer: Mutex<Option<NodeValue>>,
+}
+
+impl Resuming {
+    pub(crate) fn new(run: SuspendedRun, answer: NodeValue) -> Self {
+        Resuming {
+            flows: Mutex::new(run.flows.into()),
+            node: run.node,
+            payload: run.payload,
+            answer: Mutex::new(Some(answer)),
+        }
+    }
+
+    /// Awaits `fut` with the run resumed as it runs its flows
+    pub(crate) async fn scope<F: Future>(self, fut: F) -> F::Output {
+        RESUMING.scope(Arc::new(self), fut).await
+    }
+}
+
+/// Where `flow` should pick its run up, if it is the next flow of the run being resumed
+pub(crate) fn take_flow(flow: &str) -> Option<SuspendedFlow> {
+    RESUMING
+        .try_with(|resuming| {
+            let mut flows = resuming.flows.lock().unwrap();
+            match flows.front() {
+                Some(suspended) if suspended.flow == flow => flows.pop_front(),
+                _ => None,
+            }
+        })
+        .ok()
+        .flatten()
+}
+
+/// A `SuspendedRun` records one place per flow to pick the run up at, which the runs of a batch
+/// flow don't have: a human node reached by one of them fails the batch instead of suspending it
+pub(crate) fn refuse_in_batch(error: NodeError) -> NodeError {
+    match error {
+        NodeError::Suspended(run) => NodeError::CheckpointError(format!(
+            "Human node \"{}\" can't suspend a run inside a batch flow, it couldn't be resumed: \
+             only nest human nodes in AsyncFlows",
+            run.node
+        )),
+        error => error,
+    }
+}
+
+/// The payload and the answer for `node`, if it is the human node of the run being resumed
+/// (once every flow it is nested in was picked up)
+fn take_answer(node: &str) -> Option<(NodeValue, NodeValue)> {
+    RESUMING
+        .try_with(|resuming| {
+            if resuming.node != node || !resuming.flows.lock().unwrap().is_empty() {
+                return None;
+            }
+            let answer = resuming.answer.lock().unwrap().take()?;
+            Some((resuming.payload.clone(), answer))
+        })
+        .ok()
+        .flatten()
+}
+
+#[async_trait]
+impl<S, L> AsyncNodeLogic<S> for HumanLogic<L>
+where
+    S: Send + Sync + 'static,
+    L: AsyncNodeLogic<S> + Clone,
+{
+    async fn orchestrate(
+        &self,
+        node: &NodeCore<S>,
+        params: &HashMap<String, NodeValue>,
+        shared: &mut S,
+    ) -> Option<Result<Option<String>, NodeError>> {
+        if let Some((payload, answer)) = take_answer(&node.id) {
+            return Some(refuse_reserved_action(
+                self.logic.post(shared, payload, answer).await,
+            ));
+        }
+        let payload = match self.logic.prep(params, shared).await {
+            Ok(payload) => payload,
+            Err(error) => return Some(Err(error)),
+        };
+        log::info!("Node {} suspends the run to wait for input.", node);
+        // The flows the node is nested in add where they stand as the error goes up
+        Some(Err(NodeError::Suspended(Box::new(SuspendedRun {
+            node: node.id.clone(),
+            payload,
+            flows: Vec::new(),
+            shared: NodeValue::Null,
+        }))))
+    }
+
+    // `orchestrate` takes over the whole run, so the lifecycle is never called by `AsyncNode::run`
+    async fn prep(
+        &self,
+        params: &HashMap<String, NodeValue>,
+        shared: &S,
+    ) -> Result<NodeValue, NodeError> {
+        self.logic.prep(params, shared).await
+    }
+
+    async fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+        Err(NodeError::ExecError(
+            "Human nodes wait for an answer, they don't exec".into(),
+        ))
+    }
+
+    async fn post(
+        &self,
+        shared: &mut S,
+        prep_res: NodeValue,
+        exec_res: NodeValue,
+    ) -> Result<Option<String>, NodeError> {
+        self.logic.post(shared, prep_res, exec_res).await
+    }
+
+    fn clone_box(&self) -> Box<dyn AsyncNodeLogic<S>> {
+        Box::new((*self).clone())
+    }
+}
+
+/// The `HumanNode` factory
+pub fn new_human_node<S, L>(logic: L) -> AsyncNode<S>
+where
+    S: Send + Sync + 'static,
+    L: AsyncNodeLogic<S> + Clone,
+{
+    AsyncNode::new(HumanLogic { logic })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Executable;
+    use crate::core::async_impl::async_flow::AsyncFlow;
+    use crate::core::sync_impl::SharedStore;
+    use serde_json::json;
+
+    /// Appends `id` to `shared["log"]`
+    #[derive(Clone)]
+    struct Step(String);
+
+    #[async_trait]
+    impl AsyncNodeLogic for Step {
+        async fn prep(
+            &self,
+            _params: &HashMap<String, NodeValue>,
+            _shared: &SharedStore,
+        ) -> Result<NodeValue, NodeError> {
+            Ok(NodeValue::Null)
+        }
+
+        async fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+            Ok(NodeValue::Null)
+        }
+
+        async fn post(
+            &self,
+            shared: &mut SharedStore,
+            _prep_res: NodeValue,
+            _exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            let log = shared.entry("log".into()).or_insert(json!([]));
+            log.as_array_mut().unwrap().push(json!(self.0));
+            Ok(None)
+        }
+
+        fn clone_box(&self) -> Box<dyn AsyncNodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    fn step(id: &str) -> Executable {
+        Executable::Async(AsyncNode::new(Step(id.to_string())).with_id(id))
+    }
+
+    /// Asks for a review of `shared["draft"]`, the answer being the action
+    #[derive(Clone)]
+    struct Review;
+
+    #[async_trait]
+    impl AsyncNodeLogic for Review {
+        async fn prep(
+            &self,
+            _params: &HashMap<String, NodeValue>,
+            shared: &SharedStore,
+        ) -> Result<NodeValue, NodeError> {
+            Ok(json!({ "draft": shared["draft"] }))
+        }
+
+        async fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+            Ok(NodeValue::Null)
+        }
+
+        async fn post(
+            &self,
+            _shared: &mut SharedStore,
+            _prep_res: NodeValue,
+            answer: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            Ok(answer.as_str().map(String::from))
+        }
+
+        fn clone_box(&self) -> Box<dyn AsyncNodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    /// `write` -> (`inner`: `review` -> `publish` | `archive`) -> `done`
+    fn publishing(review: AsyncNode) -> AsyncFlow {
+        let inner = AsyncFlow::new(Executable::Async(
+            review
+                .next_on(step("publish"), "approve")
+                .next_on(step("archive"), "reject"),
+        ))
+        .with_id("inner");
+        let Executable::Async(write) = step("write") else {
+            unreachable!()
+        };
+        AsyncFlow::new(Executable::Async(
+            write.next(Executable::Async((*inner).clone().next(step("done")))),
+        ))
+        .with_id("outer")
+    }
+
+    #[tokio::test]
+    async fn suspended_run_resumes_in_a_rebuilt_flow() {
+        let mut shared = SharedStore::from([("draft".to_string(), json!("hello"))]);
+        let status = publishing(new_human_node(Review).with_id("review"))
+            .run_until_suspended(&mut shared)
+            .await
+            .unwrap();
+        let RunStatus::Suspended(run) = status else {
+            panic!("the run should wait for the review");
+        };
+        assert_eq!(run.node, "review");
+        assert_eq!(run.payload, json!({ "draft": "hello" }));
+        let stored = serde_json::to_string(&run).unwrap();
+
+        // Another process creates a different number of nodes before rebuilding the flow
+        let _unrelated: Vec<AsyncNode> = (0..3).map(|_| AsyncNode::new(Review)).collect();
+        let flow = publishing(new_human_node(Review).with_id("review"));
+        let run: SuspendedRun = serde_json::from_str(&stored).unwrap();
+        let mut shared = SharedStore::new();
+        let status = flow
+            .resume_suspended(run, json!("approve"), &mut shared)
+            .await
+            .unwrap();
+        assert_eq!(status, RunStatus::Completed(Some("default".into())));
+        assert_eq!(shared["log"], json!(["write", "publish", "done"]));
+        assert_eq!(shared["draft"], json!("hello"));
+    }
+
+    #[tokio::test]
+    async fn runs_cant_be_suspended_at_generated_ids() {
+        let mut shared = SharedStore::from([("draft".to_string(), json!("hello"))]);
+        let outcome = publishing(new_human_node(Review))
+            .run_until_suspended(&mut shared)
+            .await;
+        assert!(matches!(outcome, Err(NodeError::CheckpointError(_))));
+
+        let run = SuspendedRun {
+            node: "node-7".into(),
+            payload: json!(null),
+            flows: Vec::new(),
+            shared: json!({}),
+        };
+        let outcome = publishing(new_human_node(Review).with_id("review"))
+            .resume_suspended(run, json!("approve"), &mut shared)
+            .await;
+        assert!(matches!(outcome, Err(NodeError::CheckpointError(_))));
+    }
+
+    #[tokio::test]
+    async fn runs_cant_be_suspended_at_shared_ids() {
+        // The human node shares the id of the flow it is nested in
+        let mut shared = SharedStore::from([("draft".to_string(), json!("hello"))]);
+        let outcome = publishing(new_human_node(Review).with_id("inner"))
+            .run_until_suspended(&mut shared)
+            .await;
+        match outcome {
+            Err(NodeError::CheckpointError(message)) => {
+                assert!(message.contains("(\"inner\")"), "{}", message)
+            }
+            outcome => panic!("expected a CheckpointError, got {:?}", outcome),
+        }
+
+        // The same human node reused in both branches is added to the graph twice
+        let review = new_human_node(Review).with_id("review");
+        let Executable::Async(write) = step("write") else {
+            unreachable!()
+        };
+        let flow = AsyncFlow::new(Executable::Async(
+            write
+                .next_on(Executable::Async(review.clone()), "short")
+                .next_on(Executable::Async(review), "default"),
+        ))
+        .with_id("outer");
+        let outcome = flow.run_until_suspended(&mut shared).await;
+        match outcome {
+            Err(NodeError::CheckpointError(message)) => {
+                assert!(message.contains("other nodes"), "{}", message)
+            }
+            outcome => panic!("expected a CheckpointError, got {:?}", outcome),
+        }
+    }
+}
@@ -1,4 +1,10 @@
+pub mod async_batch_flow;
 pub mod async_batch_node;
 pub mod async_flow;
+pub mod async_fn_node;
 pub mod async_node;
+pub mod async_parallel_batch_flow;
 pub mod async_parallel_batch_node;
+pub mod cancellation;
+pub mod human_node;
+pub mod rate_limit;
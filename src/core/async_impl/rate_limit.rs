@@ -0,0 +1,91 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A token-bucket rate limiter.
+/// The bucket holds up to `burst` tokens and refills at `per_second` tokens per second, every
+/// request consumes one token (waiting for it if the bucket is empty).
+/// Clones share the same bucket, so one limiter can be handed to every node hitting the same API.
+#[derive(Clone)]
+pub struct RateLimiter {
+    per_second: f64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    // In the bucket rather than the limiter, so `with_burst` reaches every clone
+    burst: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Allows `per_second` requests per second, without bursts
+    pub fn per_second(per_second: f64) -> Self {
+        assert!(
+            per_second.is_finite() && per_second > 0.0,
+            "The rate must be a positive number of requests per second"
+        );
+        RateLimiter {
+            per_second,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: 1.0,
+                burst: 1.0,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    /// Lets up to `burst` requests through at once after an idle period.
+    /// The bucket stays shared with the limiter's clones, it is refilled to `burst` for all.
+    pub fn with_burst(self, burst: usize) -> Self {
+        assert!(burst > 0, "Burst must be greater than 0");
+        {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            bucket.burst = burst as f64;
+            bucket.tokens = burst as f64;
+            bucket.last_refill = Instant::now();
+        }
+        self
+    }
+
+    /// Waits until a request is allowed to go through
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+                let now = Instant::now();
+                let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(bucket.burst);
+                bucket.last_refill = now;
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_second)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn clones_share_the_bucket_and_its_burst() {
+        let limiter = RateLimiter::per_second(20.0);
+        let clone = limiter.clone();
+        let limiter = limiter.with_burst(2);
+
+        let started = Instant::now();
+        clone.acquire().await;
+        limiter.acquire().await;
+        assert!(started.elapsed() < Duration::from_millis(25));
+        // The burst is spent by both, the next request waits for a refill (50ms at 20/s)
+        limiter.acquire().await;
+        assert!(started.elapsed() >= Duration::from_millis(40));
+    }
+}
@@ -0,0 +1,451 @@
+use crate::core::async_impl::human_node::SuspendedFlow;
+use crate::core::error::NodeError;
+use crate::core::graph::{Graph, NodeHandle};
+use crate::core::sync_impl::NodeValue;
+use crate::core::sync_impl::node::{NodeCore, is_generated_id};
+use crate::core::unknown_action::UnknownActionPolicy;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Where a flow run stands after one of its nodes completed, saved by flows built
+/// `with_checkpoints` so an interrupted run can `resume`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The id of the flow
+    pub flow: String,
+    /// The id of the last node which completed
+    pub node: String,
+    /// The action it returned
+    pub action: String,
+    /// How many nodes the run went through
+    pub step: usize,
+    pub params: HashMap<String, NodeValue>,
+    pub shared: NodeValue,
+}
+
+/// Keeps the last checkpoint of each flow (by flow id)
+pub trait CheckpointStore: Send + Sync {
+    fn save(&self, checkpoint: &Checkpoint) -> Result<(), NodeError>;
+    fn load(&self, flow: &str) -> Result<Option<Checkpoint>, NodeError>;
+    /// Called once the flow reached its end
+    fn clear(&self, flow: &str) -> Result<(), NodeError>;
+}
+
+/// Stores each flow's checkpoint as `<dir>/<flow id>.json`, created when first needed.
+/// The characters of the id other than ASCII letters, digits, `-` and `_` are percent-encoded, so
+/// two ids never share a file (on a case-sensitive file system).
+#[derive(Clone, Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileStore { dir: dir.into() }
+    }
+
+    fn path(&self, flow: &str) -> PathBuf {
+        // Ids are free text, keep them from escaping `dir`
+        let file_name: String = flow
+            .bytes()
+            .map(|byte| {
+                if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' {
+                    (byte as char).to_string()
+                } else {
+                    format!("%{:02X}", byte)
+                }
+            })
+            .collect();
+        self.dir.join(format!("{}.json", file_name))
+    }
+}
+
+impl CheckpointStore for FileStore {
+    fn save(&self, checkpoint: &Checkpoint) -> Result<(), NodeError> {
+        let path = self.path(&checkpoint.flow);
+        let io_error = |error: std::io::Error| {
+            NodeError::CheckpointError(format!("Failed to write {}: {}", path.display(), error))
+        };
+        std::fs::create_dir_all(&self.dir).map_err(io_error)?;
+        // Written aside then renamed, so a crash while saving leaves the previous checkpoint
+        let partial = path.with_extension("json.partial");
+        std::fs::write(&partial, serde_json::to_vec(checkpoint)?).map_err(io_error)?;
+        std::fs::rename(&partial, &path).map_err(io_error)
+    }
+
+    fn load(&self, flow: &str) -> Result<Option<Checkpoint>, NodeError> {
+        let path = self.path(flow);
+        match std::fs::read(&path) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(NodeError::CheckpointError(format!(
+                "Failed to read {}: {}",
+                path.display(),
+                error
+            ))),
+        }
+    }
+
+    fn clear(&self, flow: &str) -> Result<(), NodeError> {
+        let path = self.path(flow);
+        match std::fs::remove_file(&path) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => {
+                Err(NodeError::CheckpointError(format!(
+                    "Failed to remove {}: {}",
+                    path.display(),
+                    error
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A flow's store, along with the (de)serialization of its shared state, which is only
+/// required from flows built `with_checkpoints`
+pub(crate) struct Checkpointer<S> {
+    store: Arc<dyn CheckpointStore>,
+    encode: fn(&S) -> Result<NodeValue, serde_json::Error>,
+    decode: fn(NodeValue) -> Result<S, serde_json::Error>,
+}
+
+impl<S> Clone for Checkpointer<S> {
+    fn clone(&self) -> Self {
+        Checkpointer {
+            store: Arc::clone(&self.store),
+            encode: self.encode,
+            decode: self.decode,
+        }
+    }
+}
+
+impl<S: Serialize + DeserializeOwned> Checkpointer<S> {
+    pub(crate) fn new<C: CheckpointStore + 'static>(store: C) -> Self {
+        Checkpointer {
+            store: Arc::new(store),
+            encode: |shared| serde_json::to_value(shared),
+            decode: serde_json::from_value,
+        }
+    }
+}
+
+impl<S> Checkpointer<S> {
+    pub(crate) fn save(
+        &self,
+        flow: &str,
+        node: &str,
+        action: &str,
+        step: usize,
+        params: &HashMap<String, NodeValue>,
+        shared: &S,
+    ) -> Result<(), NodeError> {
+        self.store.save(&Checkpoint {
+            flow: flow.to_string(),
+            node: node.to_string(),
+            action: action.to_string(),
+            step,
+            params: params.clone(),
+            shared: (self.encode)(shared)?,
+        })
+    }
+
+    /// The last checkpoint of `flow` and its shared state
+    pub(crate) fn load(&self, flow: &str) -> Result<Option<(Checkpoint, S)>, NodeError> {
+        match self.store.load(flow)? {
+            Some(checkpoint) if checkpoint.flow != flow => {
+                Err(NodeError::CheckpointError(format!(
+                    "The checkpoint loaded for flow \"{}\" was saved by flow \"{}\"",
+                    flow, checkpoint.flow
+                )))
+            }
+            Some(checkpoint) => {
+                let shared = (self.decode)(checkpoint.shared.clone())?;
+                Ok(Some((checkpoint, shared)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn clear(&self, flow: &str) -> Result<(), NodeError> {
+        self.store.clear(flow)
+    }
+}
+
+/// Checkpoints refer to the flow and its nodes by id, so the ids must be the same in the process
+/// resuming the run and tell the nodes apart. Generated ids are not the same from one process to
+/// the next, and a node reused in two branches (or two nodes given the same id) would be resumed
+/// at the first of them, flows with either can't be checkpointed.
+pub(crate) fn require_stable_ids<S>(flow: &NodeCore<S>, graph: &Graph<S>) -> Result<(), NodeError> {
+    let nodes: Vec<&str> = graph
+        .handles()
+        .map(|handle| graph.node(handle).id())
+        .collect();
+    let generated: Vec<String> = std::iter::once(flow.id.as_str())
+        .chain(nodes.iter().copied())
+        .filter(|id| is_generated_id(id))
+        .map(|id| format!("\"{}\"", id))
+        .collect();
+    let mut seen = HashSet::new();
+    let duplicated: BTreeSet<&str> = nodes.into_iter().filter(|id| !seen.insert(*id)).collect();
+
+    let mut problems = Vec::new();
+    if !generated.is_empty() {
+        problems.push(format!(
+            "its run would be saved under ids generated for this process ({})",
+            generated.join(", ")
+        ));
+    }
+    if !duplicated.is_empty() {
+        let duplicated: Vec<String> = duplicated.iter().map(|id| format!("\"{}\"", id)).collect();
+        problems.push(format!(
+            "several of its nodes share an id ({}) its run couldn't be resumed at",
+            duplicated.join(", ")
+        ));
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(NodeError::CheckpointError(format!(
+        "Flow {} can't be checkpointed, {}: give the flow and its nodes distinct ids with \
+         `with_id`",
+        flow,
+        problems.join(" and ")
+    )))
+}
+
+/// Where a run of a flow's graph is: the next node to run, how many ran before it and the
+/// action which led to it
+pub(crate) struct Position {
+    pub(crate) next: Option<NodeHandle>,
+    pub(crate) step: usize,
+    pub(crate) last_action: String,
+}
+
+impl Position {
+    pub(crate) fn start<S>(graph: &Graph<S>) -> Position {
+        Position {
+            next: Some(graph.start()),
+            step: 0,
+            last_action: "start".into(),
+        }
+    }
+
+    /// Right after the node `checkpoint` was saved for, `shared` being the state it saved
+    pub(crate) fn after<S>(
+        graph: &Graph<S>,
+        checkpoint: &Checkpoint,
+        shared: &S,
+        unknown_action: &UnknownActionPolicy,
+    ) -> Result<Position, NodeError> {
+        let completed = Self::find(graph, &checkpoint.flow, &checkpoint.node)?;
+        Ok(Position {
+            next: unknown_action.route(graph, completed, &checkpoint.action, shared)?,
+            step: checkpoint.step,
+            last_action: checkpoint.action.clone(),
+        })
+    }
+
+    /// At the node the flow was running when its run was suspended
+    pub(crate) fn at<S>(
+        graph: &Graph<S>,
+        suspended: &SuspendedFlow,
+    ) -> Result<Position, NodeError> {
+        Ok(Position {
+            next: Some(Self::find(graph, &suspended.flow, &suspended.node)?),
+            step: suspended.step,
+            last_action: suspended.action.clone(),
+        })
+    }
+
+    fn find<S>(graph: &Graph<S>, flow: &str, node: &str) -> Result<NodeHandle, NodeError> {
+        graph.find(node).ok_or_else(|| {
+            NodeError::CheckpointError(format!(
+                "The saved run of flow \"{}\" refers to node \"{}\", which the flow doesn't have",
+                flow, node
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Executable;
+    use crate::core::sync_impl::SharedStore;
+    use crate::core::sync_impl::flow::Flow;
+    use crate::core::sync_impl::node::{Node, NodeLogic};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "orichalcum-checkpoints-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Appends `id` to `shared["log"]`, failing while `fail` is set
+    #[derive(Clone)]
+    struct Step {
+        id: String,
+        fail: Option<Arc<AtomicBool>>,
+    }
+
+    impl NodeLogic for Step {
+        fn post(
+            &self,
+            shared: &mut SharedStore,
+            _prep_res: NodeValue,
+            _exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            if self
+                .fail
+                .as_ref()
+                .is_some_and(|fail| fail.load(Ordering::SeqCst))
+            {
+                return Err(NodeError::PostError("down".into()));
+            }
+            let log = shared.entry("log".into()).or_insert(json!([]));
+            log.as_array_mut().unwrap().push(json!(self.id));
+            Ok(None)
+        }
+
+        fn clone_box(&self) -> Box<dyn NodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    fn step(id: &str, fail: Option<Arc<AtomicBool>>) -> Node {
+        Node::new(Step {
+            id: id.to_string(),
+            fail,
+        })
+        .with_id(id)
+    }
+
+    /// A node with a generated id
+    fn unnamed() -> Node {
+        Node::new(Step {
+            id: "unnamed".into(),
+            fail: None,
+        })
+    }
+
+    fn pipeline(dir: &PathBuf, fail: Arc<AtomicBool>) -> Flow {
+        let c = Executable::Sync(step("c", None));
+        Flow::new(step("a", None).next(Executable::Sync(step("b", Some(fail)).next(c))))
+            .with_id("pipeline")
+            .with_checkpoints(FileStore::new(dir))
+    }
+
+    #[test]
+    fn resumes_in_a_fresh_process() {
+        let dir = temp_dir("fresh-process");
+        let fail = Arc::new(AtomicBool::new(true));
+        let mut shared = SharedStore::new();
+        assert!(pipeline(&dir, fail).run(&mut shared).is_err());
+        assert!(dir.join("pipeline.json").exists());
+
+        // Another process creates a different number of nodes before rebuilding the flow
+        let _unrelated: Vec<Node> = (0..3).map(|_| unnamed()).collect();
+        let flow = pipeline(&dir, Arc::new(AtomicBool::new(false)));
+        let mut shared = SharedStore::new();
+        assert_eq!(flow.resume(&mut shared).unwrap(), Some("default".into()));
+        assert_eq!(shared["log"], json!(["a", "b", "c"]));
+        assert!(!dir.join("pipeline.json").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn generated_ids_are_refused() {
+        let dir = temp_dir("generated-ids");
+        let flow = Flow::new(unnamed().next(Executable::Sync(step("b", None))))
+            .with_id("generated")
+            .with_checkpoints(FileStore::new(&dir));
+        let outcome = flow.run(&mut SharedStore::new());
+        assert!(matches!(outcome, Err(NodeError::CheckpointError(_))));
+        assert!(matches!(
+            flow.resume(&mut SharedStore::new()),
+            Err(NodeError::CheckpointError(_))
+        ));
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn nodes_sharing_an_id_are_refused() {
+        let dir = temp_dir("shared-ids");
+        // The same node reused in both branches is added to the graph twice
+        let done = step("done", None);
+        let check = step("check", None)
+            .next_on(done.clone(), "ok")
+            .next_on(step("fix", None).next(done), "retry");
+        let flow = Flow::new(check)
+            .with_id("branches")
+            .with_checkpoints(FileStore::new(&dir));
+        match flow.run(&mut SharedStore::new()) {
+            Err(NodeError::CheckpointError(message)) => {
+                assert!(message.contains("share an id (\"done\")"), "{}", message)
+            }
+            outcome => panic!("expected a CheckpointError, got {:?}", outcome),
+        }
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn file_store_keeps_ids_inside_its_dir() {
+        let dir = temp_dir("file-store");
+        let store = FileStore::new(&dir);
+        let checkpoint = Checkpoint {
+            flow: "../escape".into(),
+            node: "a".into(),
+            action: "default".into(),
+            step: 1,
+            params: HashMap::new(),
+            shared: json!({ "n": 1 }),
+        };
+        store.save(&checkpoint).unwrap();
+        assert!(dir.join("%2E%2E%2Fescape.json").exists());
+        assert_eq!(store.load("../escape").unwrap(), Some(checkpoint));
+        store.clear("../escape").unwrap();
+        assert_eq!(store.load("../escape").unwrap(), None);
+        store.clear("../escape").unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn file_store_gives_each_id_its_file() {
+        let dir = temp_dir("file-names");
+        let store = FileStore::new(&dir);
+        let ids = ["a/b", "a_b", "a%2Fb", "a.b", "é"];
+        let paths: HashSet<PathBuf> = ids.iter().map(|id| store.path(id)).collect();
+        assert_eq!(paths.len(), ids.len());
+    }
+
+    #[test]
+    fn checkpoints_of_another_flow_are_refused() {
+        let dir = temp_dir("other-flow");
+        let store = FileStore::new(&dir);
+        let checkpoint = Checkpoint {
+            flow: "other".into(),
+            node: "a".into(),
+            action: "default".into(),
+            step: 1,
+            params: HashMap::new(),
+            shared: json!({}),
+        };
+        store.save(&checkpoint).unwrap();
+        std::fs::rename(store.path("other"), store.path("pipeline")).unwrap();
+        let checkpointer = Checkpointer::<SharedStore>::new(store);
+        assert!(matches!(
+            checkpointer.load("pipeline"),
+            Err(NodeError::CheckpointError(_))
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
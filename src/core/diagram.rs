@@ -0,0 +1,424 @@
+use crate::core::Executable;
+use crate::core::async_impl::async_node::AsyncNode;
+use crate::core::graph::Graph;
+use crate::core::sync_impl::node::Node;
+use std::fmt::Write;
+
+/// A flow graph as drawn by `to_dot`/`to_mermaid`, independent from the shared state type.
+/// Nested flows carry their own `Diagram`, rendered as a cluster inside their node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagram {
+    pub nodes: Vec<DiagramNode>,
+    /// `(from, action, to)`, nodes being referred to by their position in `nodes`
+    pub edges: Vec<(usize, String, usize)>,
+    pub start: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiagramNode {
+    pub id: String,
+    /// The node's name
+    pub label: String,
+    pub description: Option<String>,
+    pub is_async: bool,
+    pub kind: DiagramKind,
+}
+
+/// What a node is, as far as diagrams are concerned (see `NodeLogic::diagram_kind`)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum DiagramKind {
+    #[default]
+    Node,
+    Batch,
+    ParallelBatch,
+    Flow(Diagram),
+    BatchFlow(Diagram),
+    ParallelBatchFlow(Diagram),
+}
+
+impl DiagramKind {
+    /// The marker appended to the label of batch nodes (flows are clusters titled by their name)
+    fn marker(&self) -> Option<&'static str> {
+        match self {
+            DiagramKind::Batch => Some("batch"),
+            DiagramKind::ParallelBatch => Some("parallel batch"),
+            _ => None,
+        }
+    }
+
+    fn sub_diagram(&self) -> Option<&Diagram> {
+        match self {
+            DiagramKind::Flow(diagram)
+            | DiagramKind::BatchFlow(diagram)
+            | DiagramKind::ParallelBatchFlow(diagram) => Some(diagram),
+            _ => None,
+        }
+    }
+}
+
+impl<S: 'static> Graph<S> {
+    pub fn diagram(&self) -> Diagram {
+        let nodes = self
+            .handles()
+            .map(|handle| DiagramNode::of(self.node(handle)))
+            .collect();
+        let mut edges = Vec::new();
+        for handle in self.handles() {
+            let mut successors: Vec<_> = self.successors(handle).iter().collect();
+            // The edges are stored in a HashMap, sort them so the output is stable (the nodes are
+            // numbered by handle, which `GraphBuilder` hands out in a deterministic order)
+            successors.sort();
+            for (action, to) in successors {
+                edges.push((handle.index(), action.clone(), to.index()));
+            }
+            // Predicates can't be drawn, guarded edges are labelled with the order they are checked
+            for (order, guard) in self.guards(handle).iter().enumerate() {
+                let label = match &guard.action {
+                    Some(action) => format!("{} when #{}", action, order + 1),
+                    None => format!("when #{}", order + 1),
+                };
+                edges.push((handle.index(), label, guard.to.index()));
+            }
+        }
+        Diagram {
+            nodes,
+            edges,
+            start: self.start().index(),
+        }
+    }
+}
+
+impl DiagramNode {
+    pub fn of<S: 'static>(node: &Executable<S>) -> DiagramNode {
+        match node {
+            Executable::Sync(node) => node.into(),
+            Executable::Async(node) => node.into(),
+        }
+    }
+}
+
+impl<S: 'static> From<&Node<S>> for DiagramNode {
+    fn from(node: &Node<S>) -> Self {
+        DiagramNode {
+            id: node.data.id.clone(),
+            label: node.data.name.clone(),
+            description: node.data.description.clone(),
+            is_async: false,
+            kind: node.behaviour.diagram_kind(),
+        }
+    }
+}
+
+impl<S: 'static> From<&AsyncNode<S>> for DiagramNode {
+    fn from(node: &AsyncNode<S>) -> Self {
+        DiagramNode {
+            id: node.data.id.clone(),
+            label: node.data.name.clone(),
+            description: node.data.description.clone(),
+            is_async: true,
+            kind: node.behaviour.diagram_kind(),
+        }
+    }
+}
+
+/// The diagram of a flow is its graph, any other node is drawn on its own
+impl From<DiagramNode> for Diagram {
+    fn from(node: DiagramNode) -> Self {
+        match node.kind {
+            DiagramKind::Flow(diagram) => diagram,
+            _ => Diagram {
+                nodes: vec![node],
+                edges: Vec::new(),
+                start: 0,
+            },
+        }
+    }
+}
+
+impl Diagram {
+    /// Renders the diagram as Graphviz DOT
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph flow {\n    compound=true;\n    node [shape=box];\n");
+        out.push_str("    start [shape=point];\n");
+        self.write_dot(&mut out, "n", 1);
+        let _ = writeln!(out, "    start -> {};", dot_anchor(self, "n", self.start));
+        out.push_str("}\n");
+        out
+    }
+
+    fn write_dot(&self, out: &mut String, prefix: &str, depth: usize) {
+        let indent = "    ".repeat(depth);
+        for (index, node) in self.nodes.iter().enumerate() {
+            let id = format!("{}{}", prefix, index);
+            match node.kind.sub_diagram() {
+                Some(sub_diagram) => {
+                    let _ = writeln!(out, "{}subgraph cluster_{} {{", indent, id);
+                    let _ = writeln!(out, "{}    label=\"{}\";", indent, escape_dot(&node.label));
+                    if node.is_async {
+                        let _ = writeln!(out, "{}    style=rounded;", indent);
+                    }
+                    sub_diagram.write_dot(out, &format!("{}_", id), depth + 1);
+                    let _ = writeln!(out, "{}}}", indent);
+                }
+                None => {
+                    let style = if node.is_async { ", style=rounded" } else { "" };
+                    let tooltip = match &node.description {
+                        Some(description) => format!(", tooltip=\"{}\"", escape_dot(description)),
+                        None => String::new(),
+                    };
+                    let _ = writeln!(
+                        out,
+                        "{}{} [label=\"{}\"{}{}];",
+                        indent,
+                        id,
+                        escape_dot(&node_label(node)),
+                        style,
+                        tooltip
+                    );
+                }
+            }
+        }
+        for (from, action, to) in &self.edges {
+            // Edges can't point to a cluster, they point to the nested flow's start node and
+            // are clipped at the cluster's border
+            let mut attributes = vec![format!("label=\"{}\"", escape_dot(action))];
+            if self.nodes[*from].kind.sub_diagram().is_some() {
+                attributes.push(format!("ltail=cluster_{}{}", prefix, from));
+            }
+            if self.nodes[*to].kind.sub_diagram().is_some() {
+                attributes.push(format!("lhead=cluster_{}{}", prefix, to));
+            }
+            let _ = writeln!(
+                out,
+                "{}{} -> {} [{}];",
+                indent,
+                dot_anchor(self, prefix, *from),
+                dot_anchor(self, prefix, *to),
+                attributes.join(", ")
+            );
+        }
+    }
+
+    /// Renders the diagram as a Mermaid flowchart
+    pub fn to_mermaid(&self) -> String {
+        let mut out = String::from("flowchart TD\n    start((start))\n");
+        self.write_mermaid(&mut out, "n", 1);
+        let _ = writeln!(out, "    start --> n{}", self.start);
+        out
+    }
+
+    fn write_mermaid(&self, out: &mut String, prefix: &str, depth: usize) {
+        let indent = "    ".repeat(depth);
+        for (index, node) in self.nodes.iter().enumerate() {
+            let id = format!("{}{}", prefix, index);
+            match node.kind.sub_diagram() {
+                Some(sub_diagram) => {
+                    let _ = writeln!(
+                        out,
+                        "{}subgraph {} [\"{}\"]",
+                        indent,
+                        id,
+                        escape_mermaid(&node.label)
+                    );
+                    sub_diagram.write_mermaid(out, &format!("{}_", id), depth + 1);
+                    let _ = writeln!(out, "{}end", indent);
+                }
+                None => {
+                    let label = escape_mermaid(&node_label(node));
+                    // Async nodes are drawn with rounded corners
+                    let _ = if node.is_async {
+                        writeln!(out, "{}{}(\"{}\")", indent, id, label)
+                    } else {
+                        writeln!(out, "{}{}[\"{}\"]", indent, id, label)
+                    };
+                }
+            }
+        }
+        // Mermaid edges can point to subgraphs directly
+        for (from, action, to) in &self.edges {
+            let _ = writeln!(
+                out,
+                "{}{}{} -->|\"{}\"| {}{}",
+                indent,
+                prefix,
+                from,
+                escape_mermaid(action),
+                prefix,
+                to
+            );
+        }
+    }
+}
+
+/// The node an edge from or to node `index` is drawn with
+fn dot_anchor(diagram: &Diagram, prefix: &str, index: usize) -> String {
+    match diagram.nodes[index].kind.sub_diagram() {
+        Some(sub_diagram) => dot_anchor(
+            sub_diagram,
+            &format!("{}{}_", prefix, index),
+            sub_diagram.start,
+        ),
+        None => format!("{}{}", prefix, index),
+    }
+}
+
+fn node_label(node: &DiagramNode) -> String {
+    match node.kind.marker() {
+        Some(marker) => format!("{} ({})", node.label, marker),
+        None => node.label.clone(),
+    }
+}
+
+/// `my_crate::nodes::Summarize` -> `Summarize`, paths are shortened inside generics as well
+/// (`BatchLogic<my_crate::nodes::Summarize>` -> `BatchLogic<Summarize>`)
+pub(crate) fn short_type_name(type_name: &str) -> String {
+    let mut short = String::new();
+    let mut path = String::new();
+    for c in type_name.chars() {
+        if c.is_alphanumeric() || c == '_' || c == ':' {
+            path.push(c);
+        } else {
+            short.push_str(path.rsplit("::").next().unwrap_or_default());
+            path.clear();
+            short.push(c);
+        }
+    }
+    short.push_str(path.rsplit("::").next().unwrap_or_default());
+    short
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_mermaid(text: &str) -> String {
+    text.replace('"', "#quot;")
+        .replace('<', "#lt;")
+        .replace('>', "#gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::async_impl::async_node::AsyncNodeLogic;
+    use crate::core::error::NodeError;
+    use crate::core::sync_impl::NodeValue;
+    use crate::core::sync_impl::flow::Flow;
+    use crate::core::sync_impl::node::NodeLogic;
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+
+    /// Declares do-nothing logics, drawn with their type name
+    macro_rules! logics {
+        ($($name:ident),*) => {$(
+            #[derive(Clone)]
+            struct $name;
+
+            impl NodeLogic for $name {
+                fn clone_box(&self) -> Box<dyn NodeLogic> {
+                    Box::new((*self).clone())
+                }
+            }
+        )*};
+    }
+
+    logics!(Review, Draft, Polish, Archive);
+
+    #[derive(Clone)]
+    struct Publish;
+
+    #[async_trait]
+    impl AsyncNodeLogic for Publish {
+        async fn prep(
+            &self,
+            _params: &HashMap<String, NodeValue>,
+            _shared: &HashMap<String, NodeValue>,
+        ) -> Result<NodeValue, NodeError> {
+            Ok(NodeValue::Null)
+        }
+
+        async fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+            Ok(NodeValue::Null)
+        }
+
+        async fn post(
+            &self,
+            _shared: &mut HashMap<String, NodeValue>,
+            _prep_res: NodeValue,
+            _exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            Ok(None)
+        }
+
+        fn clone_box(&self) -> Box<dyn AsyncNodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    fn review_flow() -> Flow {
+        let write = Flow::new(Node::new(Draft).next(Executable::Sync(Node::new(Polish))));
+        Flow::new(
+            Node::new(Review)
+                .next_on(Executable::Sync((*write).clone()), "rewrite")
+                .next_on(Executable::Async(AsyncNode::new(Publish)), "approve")
+                .next_on(Executable::Sync(Node::new(Archive)), "reject"),
+        )
+    }
+
+    #[test]
+    fn mermaid_output_is_stable() {
+        let expected = r#"flowchart TD
+    start((start))
+    n0["Review"]
+    n1("Publish")
+    n2["Archive"]
+    subgraph n3 ["flow"]
+        n3_0["Draft"]
+        n3_1["Polish"]
+        n3_0 -->|"default"| n3_1
+    end
+    n0 -->|"approve"| n1
+    n0 -->|"reject"| n2
+    n0 -->|"rewrite"| n3
+    start --> n0
+"#;
+        for _ in 0..10 {
+            assert_eq!(review_flow().to_mermaid(), expected);
+        }
+    }
+
+    #[test]
+    fn dot_output_is_stable() {
+        let expected = r#"digraph flow {
+    compound=true;
+    node [shape=box];
+    start [shape=point];
+    n0 [label="Review"];
+    n1 [label="Publish", style=rounded];
+    n2 [label="Archive"];
+    subgraph cluster_n3 {
+        label="flow";
+        n3_0 [label="Draft"];
+        n3_1 [label="Polish"];
+        n3_0 -> n3_1 [label="default"];
+    }
+    n0 -> n1 [label="approve"];
+    n0 -> n2 [label="reject"];
+    n0 -> n3_0 [label="rewrite", lhead=cluster_n3];
+    start -> n0;
+}
+"#;
+        for _ in 0..10 {
+            assert_eq!(review_flow().to_dot(), expected);
+        }
+    }
+
+    #[test]
+    fn type_names_are_shortened() {
+        assert_eq!(short_type_name("my_crate::nodes::Summarize"), "Summarize");
+        assert_eq!(
+            short_type_name("orichalcum::BatchLogic<my_crate::nodes::Summarize>"),
+            "BatchLogic<Summarize>"
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use crate::core::async_impl::human_node::SuspendedRun;
+use crate::core::graph::ValidationIssue;
+use crate::core::unknown_action::list_actions;
+use thiserror::Error;
+
+/// The error type shared by every phase of a node's lifecycle (`prep`, `exec`, `post`).
+/// Flows wrap the error of the node that failed so the caller knows where the run stopped.
+#[derive(Debug, Error)]
+pub enum NodeError {
+    #[error("Error occurred during prep: {0}")]
+    PrepError(String),
+    #[error("Error occurred during exec: {0}")]
+    ExecError(String),
+    #[error("Error occurred during post: {0}")]
+    PostError(String),
+    #[error("(De)serialization of a node value failed: {0}")]
+    SerdeError(#[from] serde_json::Error),
+    #[error("Timed out: {0}")]
+    TimeoutError(String),
+    #[error("The flow's deadline was exceeded at step {step}")]
+    DeadlineExceeded { step: usize },
+    #[error("The run was cancelled")]
+    Cancelled,
+    #[error("A synchronous node panicked: {0}")]
+    PanicError(String),
+    #[error(
+        "Node \"{node}\" at step {step} (reached through action \"{action}\") failed: {source}"
+    )]
+    FlowError {
+        /// The id of the node which failed
+        node: String,
+        step: usize,
+        action: String,
+        source: Box<NodeError>,
+    },
+    #[error("Batch item {index} failed: {source}")]
+    BatchError {
+        index: usize,
+        source: Box<NodeError>,
+    },
+    /// Not a failure: a human node suspended the run, see `AsyncFlow::run_until_suspended`
+    #[error("The run was suspended at node \"{}\" to wait for input", .0.node)]
+    Suspended(Box<SuspendedRun>),
+    /// A node returned an action its edges don't match, see `UnknownActionPolicy`
+    #[error(
+        "Node \"{node}\" returned action \"{action}\", which has no successor (available actions: {})",
+        list_actions(.available)
+    )]
+    UnknownAction {
+        /// The id of the node
+        node: String,
+        action: String,
+        available: Vec<String>,
+    },
+    #[error("Checkpoint error: {0}")]
+    CheckpointError(String),
+    #[error("Invalid graph: {}", list_issues(.0))]
+    InvalidGraph(Vec<ValidationIssue>),
+    #[cfg(feature = "llm")]
+    #[error("Error occurred during LLM call: {0}")]
+    LLMError(#[from] crate::llm::error::LLMError),
+}
+
+fn list_issues(issues: &[ValidationIssue]) -> String {
+    issues
+        .iter()
+        .map(|issue| issue.to_string())
+        .collect::<Vec<_>>()
+        .join("; ")
+}
@@ -0,0 +1,572 @@
+use crate::core::Executable;
+use crate::core::sync_impl::SharedStore;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Refers to a node of a `Graph` (or of the `GraphBuilder` building it).
+/// Handles are only meaningful for the builder which handed them out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeHandle(usize);
+
+impl NodeHandle {
+    /// The position of the node in its graph, in insertion order
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+/// The action of wildcard edges, which match any action the node has no edge for
+pub const ANY_ACTION: &str = "*";
+
+/// Decides from the shared state whether a guarded edge is taken
+pub type Predicate<S = SharedStore> = Arc<dyn Fn(&S) -> bool + Send + Sync>;
+
+/// A successor reached when its predicate holds on the shared state left by the node's `post`,
+/// see `Node::next_when`
+pub struct Guard<S = SharedStore> {
+    /// The action the node must have returned, any action if `None`
+    pub action: Option<String>,
+    pub predicate: Predicate<S>,
+    pub node: Executable<S>,
+}
+
+impl<S: 'static> Clone for Guard<S> {
+    fn clone(&self) -> Self {
+        Guard {
+            action: self.action.clone(),
+            predicate: self.predicate.clone(),
+            node: self.node.clone(),
+        }
+    }
+}
+
+/// A guarded edge of a `Graph`
+pub struct GuardedEdge<S = SharedStore> {
+    /// The action the node must have returned, any action if `None`
+    pub action: Option<String>,
+    pub predicate: Predicate<S>,
+    pub to: NodeHandle,
+}
+
+impl<S> GuardedEdge<S> {
+    /// Whether the edge is taken after the node returned `action`
+    pub fn holds(&self, action: &str, shared: &S) -> bool {
+        self.action
+            .as_deref()
+            .is_none_or(|guarded| guarded == action)
+            && (self.predicate)(shared)
+    }
+}
+
+/// An immutable node graph, shared (through an `Arc`) by the flows running it.
+/// The nodes are stored once and the edges point to them by handle, so walking the graph never
+/// clones a node and cycles (agent loops) are representable.
+pub struct Graph<S = SharedStore> {
+    nodes: Vec<Executable<S>>,
+    edges: Vec<HashMap<String, NodeHandle>>,
+    guards: Vec<Vec<GuardedEdge<S>>>,
+    start: NodeHandle,
+}
+
+impl<S> Graph<S> {
+    /// Compiles a start node and the successors chained to it with `next`/`next_on`
+    pub fn from_start(start: Executable<S>) -> Arc<Graph<S>> {
+        let mut builder = GraphBuilder::new();
+        let start = builder.add(start);
+        builder.build(start)
+    }
+
+    pub fn start(&self) -> NodeHandle {
+        self.start
+    }
+
+    pub fn node(&self, handle: NodeHandle) -> &Executable<S> {
+        &self.nodes[handle.0]
+    }
+
+    /// The node reached from `handle` when it returns `action` (through its wildcard edge if
+    /// it has none for `action`), if any
+    pub fn successor(&self, handle: NodeHandle, action: &str) -> Option<NodeHandle> {
+        let edges = &self.edges[handle.0];
+        edges.get(action).or_else(|| edges.get(ANY_ACTION)).copied()
+    }
+
+    pub fn successors(&self, handle: NodeHandle) -> &HashMap<String, NodeHandle> {
+        &self.edges[handle.0]
+    }
+
+    /// The actions `handle` has edges for (guarded ones included), sorted
+    pub fn actions(&self, handle: NodeHandle) -> Vec<String> {
+        let guarded = self.guards[handle.0]
+            .iter()
+            .filter_map(|guard| guard.action.as_ref());
+        let mut actions: Vec<String> = self.edges[handle.0]
+            .keys()
+            .chain(guarded)
+            .cloned()
+            .collect();
+        actions.sort();
+        actions.dedup();
+        actions
+    }
+
+    /// The guarded edges of `handle`, in the order they are checked
+    pub fn guards(&self, handle: NodeHandle) -> &[GuardedEdge<S>] {
+        &self.guards[handle.0]
+    }
+
+    /// Where the flow goes once `handle` returned `action` and left `shared`: to the first of
+    /// its guarded edges which holds, otherwise to the successor for `action`
+    pub fn route(&self, handle: NodeHandle, action: &str, shared: &S) -> Option<NodeHandle> {
+        self.guards[handle.0]
+            .iter()
+            .find(|guard| guard.holds(action, shared))
+            .map(|guard| guard.to)
+            .or_else(|| self.successor(handle, action))
+    }
+
+    /// The first node (in insertion order) with the given id
+    pub fn find(&self, id: &str) -> Option<NodeHandle> {
+        self.handles().find(|&handle| self.node(handle).id() == id)
+    }
+
+    pub fn handles(&self) -> impl Iterator<Item = NodeHandle> + use<S> {
+        (0..self.nodes.len()).map(NodeHandle)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+impl<S: 'static> Graph<S> {
+    /// Walks the graph from its start node and reports what would go wrong at runtime.
+    /// `sync_only` graphs (the ones run by `Flow`) can't contain `AsyncNode`s.
+    /// Nested flows are validated as well.
+    pub fn validate(&self, sync_only: bool) -> Vec<ValidationIssue> {
+        let mut issues = Vec::new();
+        let mut reached = HashSet::from([self.start]);
+        let mut to_visit = VecDeque::from([self.start]);
+
+        while let Some(handle) = to_visit.pop_front() {
+            let node = self.node(handle);
+            if sync_only && matches!(node, Executable::Async(_)) {
+                issues.push(ValidationIssue::AsyncInSyncFlow {
+                    node: handle,
+                    id: node.id().to_string(),
+                });
+            }
+
+            // Only nodes which declared their actions can be checked
+            let declared = node.actions();
+            if !declared.is_empty() {
+                let edges = &self.edges[handle.0];
+                for action in declared {
+                    if !edges.contains_key(action) && !edges.contains_key(ANY_ACTION) {
+                        issues.push(ValidationIssue::MissingSuccessor {
+                            node: handle,
+                            id: node.id().to_string(),
+                            action: action.clone(),
+                        });
+                    }
+                }
+                let guarded = self.guards[handle.0]
+                    .iter()
+                    .filter_map(|guard| guard.action.as_ref());
+                for action in self.edges[handle.0].keys().chain(guarded) {
+                    if action != ANY_ACTION && !declared.contains(action) {
+                        issues.push(ValidationIssue::UndeclaredAction {
+                            node: handle,
+                            id: node.id().to_string(),
+                            action: action.clone(),
+                        });
+                    }
+                }
+            }
+
+            issues.extend(
+                node.validate()
+                    .into_iter()
+                    .map(|issue| ValidationIssue::InNestedFlow {
+                        node: handle,
+                        id: node.id().to_string(),
+                        issue: Box::new(issue),
+                    }),
+            );
+
+            let guarded = self.guards[handle.0].iter().map(|guard| &guard.to);
+            for &successor in self.edges[handle.0].values().chain(guarded) {
+                if reached.insert(successor) {
+                    to_visit.push_back(successor);
+                }
+            }
+        }
+
+        issues.extend(
+            self.handles()
+                .filter(|handle| !reached.contains(handle))
+                .map(|node| ValidationIssue::Unreachable {
+                    node,
+                    id: self.node(node).id().to_string(),
+                }),
+        );
+        issues
+    }
+}
+
+/// A problem found by `Graph::validate`, nodes are referred to by their id and handle
+/// (`"id" (#index)`)
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum ValidationIssue {
+    #[error(
+        "node \"{id}\" (#{}) is an AsyncNode in a sync Flow without a blocking bridge, use AsyncFlow",
+        .node.index()
+    )]
+    AsyncInSyncFlow { node: NodeHandle, id: String },
+    #[error("node \"{id}\" (#{}) can't be reached from the start node", .node.index())]
+    Unreachable { node: NodeHandle, id: String },
+    #[error(
+        "node \"{id}\" (#{}) declares action \"{action}\" but has no successor for it",
+        .node.index()
+    )]
+    MissingSuccessor {
+        node: NodeHandle,
+        id: String,
+        action: String,
+    },
+    #[error(
+        "node \"{id}\" (#{}) has a successor for action \"{action}\" which it never returns",
+        .node.index()
+    )]
+    UndeclaredAction {
+        node: NodeHandle,
+        id: String,
+        action: String,
+    },
+    #[error("the fallback node \"{id}\" for unknown actions is not part of the flow")]
+    MissingFallback { id: String },
+    #[error("in the flow at node \"{id}\" (#{}): {issue}", .node.index())]
+    InNestedFlow {
+        node: NodeHandle,
+        id: String,
+        issue: Box<ValidationIssue>,
+    },
+}
+
+/// Builds a `Graph`, nodes are added once and then wired together by handle:
+/// ```
+/// use orichalcum::core::graph::GraphBuilder;
+/// use orichalcum::core::sync_impl::SharedStore;
+/// use orichalcum::core::sync_impl::flow::Flow;
+/// use orichalcum::core::sync_impl::fn_node::FnLogic;
+/// use serde_json::json;
+/// use std::sync::Arc;
+///
+/// // `act` counts its turns, `think` -> `act` loops until there were three
+/// let think = FnLogic::new().build();
+/// let act = FnLogic::new()
+///     .post(|shared: &mut SharedStore, _, _| {
+///         let turns = shared.get("turns").and_then(|turns| turns.as_u64()).unwrap_or(0);
+///         shared.insert("turns".into(), json!(turns + 1));
+///         Ok(Some("continue".into()))
+///     })
+///     .build();
+/// let done = FnLogic::new().build();
+///
+/// let mut builder = GraphBuilder::new();
+/// let think = builder.add(think.into());
+/// let act = builder.add(act.into());
+/// let done = builder.add(done.into());
+/// builder.connect(think, act);
+/// builder.connect_on(act, "continue", think);
+/// builder.connect_when(act, Arc::new(|shared: &SharedStore| shared["turns"] == 3), done);
+/// let flow = Flow::from_graph(builder.build(think));
+///
+/// let mut shared = SharedStore::new();
+/// flow.run(&mut shared).unwrap();
+/// assert_eq!(shared["turns"], json!(3));
+/// ```
+pub struct GraphBuilder<S = SharedStore> {
+    nodes: Vec<Executable<S>>,
+    edges: Vec<HashMap<String, NodeHandle>>,
+    guards: Vec<Vec<GuardedEdge<S>>>,
+}
+
+impl<S> Default for GraphBuilder<S> {
+    fn default() -> Self {
+        GraphBuilder {
+            nodes: Vec::new(),
+            edges: Vec::new(),
+            guards: Vec::new(),
+        }
+    }
+}
+
+impl<S> GraphBuilder<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `node`, along with the successors chained to it with `next`/`next_on` and
+    /// `next_when`/`next_on_when` (which become nodes and edges of the graph as well).
+    pub fn add(&mut self, mut node: Executable<S>) -> NodeHandle {
+        // Sorted by action so the handles (and everything numbered by them) are the same from
+        // one run to the next, the guards keep the order they are checked in
+        let mut successors: Vec<_> = std::mem::take(node.successors_mut()).into_iter().collect();
+        successors.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let guards = std::mem::take(node.guards_mut());
+        let handle = NodeHandle(self.nodes.len());
+        self.nodes.push(node);
+        self.edges.push(HashMap::new());
+        self.guards.push(Vec::new());
+
+        for (action, successor) in successors {
+            let to = self.add(successor);
+            self.edges[handle.0].insert(action, to);
+        }
+        for guard in guards {
+            let to = self.add(guard.node);
+            self.guards[handle.0].push(GuardedEdge {
+                action: guard.action,
+                predicate: guard.predicate,
+                to,
+            });
+        }
+        handle
+    }
+
+    pub fn connect(&mut self, from: NodeHandle, to: NodeHandle) -> &mut Self {
+        self.connect_on(from, "default", to)
+    }
+
+    pub fn connect_on(&mut self, from: NodeHandle, action: &str, to: NodeHandle) -> &mut Self {
+        self.check(from);
+        self.check(to);
+        if self.edges[from.0].insert(action.to_string(), to).is_some() {
+            log::warn!(
+                "Warning: Action {} was found in successors, Overwriting key {}.",
+                &action,
+                &action
+            );
+        }
+        self
+    }
+
+    /// Routes `from` to `to` when `predicate` holds, whatever action `from` returned.
+    /// Guarded edges are checked in the order they were added, before the action's successor.
+    pub fn connect_when(
+        &mut self,
+        from: NodeHandle,
+        predicate: Predicate<S>,
+        to: NodeHandle,
+    ) -> &mut Self {
+        self.guard(from, None, predicate, to)
+    }
+
+    /// Routes `from` to `to` when it returned `action` and `predicate` holds
+    pub fn connect_on_when(
+        &mut self,
+        from: NodeHandle,
+        action: &str,
+        predicate: Predicate<S>,
+        to: NodeHandle,
+    ) -> &mut Self {
+        self.guard(from, Some(action.to_string()), predicate, to)
+    }
+
+    fn check(&self, handle: NodeHandle) {
+        assert!(
+            handle.0 < self.nodes.len(),
+            "NodeHandle does not belong to this GraphBuilder"
+        );
+    }
+
+    fn guard(
+        &mut self,
+        from: NodeHandle,
+        action: Option<String>,
+        predicate: Predicate<S>,
+        to: NodeHandle,
+    ) -> &mut Self {
+        self.check(from);
+        self.check(to);
+        self.guards[from.0].push(GuardedEdge {
+            action,
+            predicate,
+            to,
+        });
+        self
+    }
+
+    pub fn build(self, start: NodeHandle) -> Arc<Graph<S>> {
+        self.check(start);
+        Arc::new(Graph {
+            nodes: self.nodes,
+            edges: self.edges,
+            guards: self.guards,
+            start,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::sync_impl::NodeValue;
+    use crate::core::sync_impl::flow::Flow;
+    use crate::core::sync_impl::fn_node::FnLogic;
+    use crate::core::sync_impl::node::{Node, NodeLogic};
+    use serde_json::json;
+
+    #[derive(Clone)]
+    struct Noop;
+
+    impl NodeLogic for Noop {
+        fn clone_box(&self) -> Box<dyn NodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    fn node(id: &str) -> Node {
+        Node::new(Noop).with_id(id)
+    }
+
+    #[test]
+    fn handles_follow_the_actions_order() {
+        // Every HashMap gets its own hashing seed, so a few builds would catch an unstable order
+        for _ in 0..20 {
+            let start = node("start")
+                .next_on(Executable::Sync(node("c")), "c")
+                .next_on(Executable::Sync(node("a")), "a")
+                .next_on(
+                    Executable::Sync(node("b").next(Executable::Sync(node("b2")))),
+                    "b",
+                );
+            let graph = Graph::from_start(Executable::Sync(start));
+            let ids: Vec<_> = graph
+                .handles()
+                .map(|handle| graph.node(handle).id())
+                .collect();
+            assert_eq!(ids, ["start", "a", "b", "b2", "c"]);
+        }
+    }
+
+    #[test]
+    fn builder_wires_cycles() {
+        let mut builder = GraphBuilder::new();
+        let think = builder.add(Executable::Sync(node("think")));
+        let act = builder.add(Executable::Sync(node("act")));
+        builder.connect(think, act);
+        builder.connect_on(act, "continue", think);
+        let graph = builder.build(think);
+        assert_eq!(graph.start(), think);
+        assert_eq!(graph.successor(think, "default"), Some(act));
+        assert_eq!(graph.successor(act, "continue"), Some(think));
+        assert_eq!(graph.successor(act, "default"), None);
+        assert_eq!(graph.find("act"), Some(act));
+    }
+
+    #[test]
+    fn validation_reports_unreachable_and_undeclared() {
+        let mut builder = GraphBuilder::new();
+        let start = builder.add(Executable::Sync(node("start").with_actions(&["ok"])));
+        let other = builder.add(Executable::Sync(node("other")));
+        builder.connect_on(start, "oops", other);
+        let orphan = builder.add(Executable::Sync(node("orphan")));
+        let graph = builder.build(start);
+
+        let issues = graph.validate(true);
+        assert!(issues.contains(&ValidationIssue::MissingSuccessor {
+            node: start,
+            id: "start".into(),
+            action: "ok".into(),
+        }));
+        assert!(issues.contains(&ValidationIssue::UndeclaredAction {
+            node: start,
+            id: "start".into(),
+            action: "oops".into(),
+        }));
+        assert!(issues.contains(&ValidationIssue::Unreachable {
+            node: orphan,
+            id: "orphan".into(),
+        }));
+        assert_eq!(issues.len(), 3);
+    }
+
+    /// Writes `id` to `shared["outcome"]`
+    fn outcome(id: &'static str) -> Node {
+        FnLogic::new()
+            .post(move |shared: &mut SharedStore, _, _| {
+                shared.insert("outcome".into(), json!(id));
+                Ok(None)
+            })
+            .build()
+            .with_id(id)
+    }
+
+    /// Grades `shared["score"]`: honours (90+), then pass (50+), otherwise `retake` through the
+    /// action's successor
+    fn grading() -> Flow {
+        let grade = FnLogic::new()
+            .post(|_: &mut SharedStore, _, _| Ok(Some("graded".into())))
+            .build()
+            .with_id("grade")
+            .next_on_when(
+                |shared| shared["score"] == json!(100),
+                outcome("perfect"),
+                "retry",
+            )
+            .next_on_when(
+                |shared| shared["score"].as_i64() >= Some(90),
+                outcome("honours"),
+                "graded",
+            )
+            .next_when(
+                |shared| shared["score"].as_i64() >= Some(50),
+                outcome("pass"),
+            )
+            .next_on(outcome("retake"), "graded");
+        Flow::new(grade)
+    }
+
+    fn graded(score: i64) -> NodeValue {
+        let mut shared = SharedStore::from([("score".to_string(), json!(score))]);
+        grading().run(&mut shared).unwrap();
+        shared["outcome"].clone()
+    }
+
+    #[test]
+    fn guarded_edges_are_taken_in_order_before_the_action() {
+        assert_eq!(graded(100), json!("honours"));
+        assert_eq!(graded(95), json!("honours"));
+        assert_eq!(graded(60), json!("pass"));
+        assert_eq!(graded(10), json!("retake"));
+    }
+
+    #[test]
+    #[should_panic(expected = "does not belong to this GraphBuilder")]
+    fn edges_from_a_foreign_handle_panic() {
+        let mut other = GraphBuilder::<SharedStore>::new();
+        other.add(node("a").into());
+        let foreign = other.add(node("b").into());
+        let mut builder = GraphBuilder::new();
+        let start = builder.add(node("start").into());
+        builder.connect(foreign, start);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not belong to this GraphBuilder")]
+    fn guards_from_a_foreign_handle_panic() {
+        let mut other = GraphBuilder::<SharedStore>::new();
+        other.add(node("a").into());
+        let foreign = other.add(node("b").into());
+        let mut builder = GraphBuilder::new();
+        let start = builder.add(node("start").into());
+        builder.connect_when(foreign, Arc::new(|_: &SharedStore| true), start);
+    }
+}
@@ -0,0 +1,774 @@
+use crate::core::Executable;
+use crate::core::async_impl::async_batch_flow::{AsyncBatchFlow, BatchParamsFn};
+use crate::core::async_impl::async_batch_node::new_async_batch_node;
+use crate::core::async_impl::async_flow::AsyncFlow;
+use crate::core::async_impl::async_node::{AsyncNode, AsyncNodeLogic};
+use crate::core::async_impl::async_parallel_batch_flow::{AsyncParallelBatchFlow, MergeStrategy};
+use crate::core::async_impl::async_parallel_batch_node::{
+    AsyncParallelBatchLogic, new_async_parallel_batch_node,
+};
+use crate::core::graph::{Graph, GraphBuilder, NodeHandle};
+use crate::core::sync_impl::batch_flow::BatchFlow;
+use crate::core::sync_impl::batch_node::new_batch_node;
+use crate::core::sync_impl::flow::Flow;
+use crate::core::sync_impl::node::{Node, NodeLogic};
+use crate::core::sync_impl::{NodeValue, SharedStore};
+use serde_json::Map;
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Why a flow definition could not be loaded.
+/// `path` points at the offending part of the document, e.g. `nodes.summarize.next.done`.
+#[derive(Debug, Error)]
+pub enum LoadError {
+    #[error("Failed to parse the flow definition: {0}")]
+    Parse(String),
+    #[error("Invalid flow definition at {path}: {message}")]
+    Invalid { path: String, message: String },
+}
+
+/// How a registered node is wrapped, from the `batch` key of its definition
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BatchMode {
+    Single,
+    Sequential,
+    Parallel,
+}
+
+/// Builds a registered node, given the `params` of its definition
+type NodeFactory<S> = Arc<
+    dyn Fn(&HashMap<String, NodeValue>, BatchMode) -> Result<Executable<S>, String> + Send + Sync,
+>;
+
+/// Maps the names used by flow definitions to node factories, batch param functions and merge
+/// strategies, then builds `Flow`s and `AsyncFlow`s from documents like:
+/// ```yaml
+/// start: fetch
+/// params: { topic: rust }         # only on the top-level flow
+/// nodes:
+///   fetch:                        # the node's id
+///     type: fetch_page            # a registered node
+///     name: Fetch the page        # optional, as is `description`
+///     params: { retries: 3 }      # handed to the node's factory
+///     actions: [ok, failed]       # optional, checked by `validate`
+///     next: { ok: per_doc, failed: report }
+///   per_doc:
+///     batch_flow:
+///       params_fn: one_per_doc    # a registered batch param function
+///       parallel: true            # AsyncFlow only
+///       flow: { start: summarize, nodes: { summarize: { type: summarize, batch: sequential } } }
+///   report:
+///     flow: { start: ..., nodes: { ... } }
+/// ```
+/// Typed shared states must be `Clone`, as `AsyncFlow` requires.
+pub struct Registry<S = SharedStore> {
+    nodes: HashMap<String, NodeFactory<S>>,
+    params_fns: HashMap<String, BatchParamsFn<S>>,
+    merges: HashMap<String, MergeStrategy<S>>,
+}
+
+impl<S> Default for Registry<S> {
+    fn default() -> Self {
+        Registry {
+            nodes: HashMap::new(),
+            params_fns: HashMap::new(),
+            merges: HashMap::new(),
+        }
+    }
+}
+
+impl<S: Clone + Send + Sync + 'static> Registry<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a synchronous node, `factory` receives the `params` of the node's definition
+    pub fn register<L, F>(&mut self, name: &str, factory: F) -> &mut Self
+    where
+        L: NodeLogic<S> + Clone,
+        F: Fn(&HashMap<String, NodeValue>) -> L + Send + Sync + 'static,
+    {
+        let factory: NodeFactory<S> = Arc::new(move |params, batch| {
+            let logic = factory(params);
+            match batch {
+                BatchMode::Single => Ok(Executable::Sync(Node::new(logic))),
+                BatchMode::Sequential => Ok(Executable::Sync(new_batch_node(logic))),
+                BatchMode::Parallel => {
+                    Err("parallel batches are only available for async nodes".into())
+                }
+            }
+        });
+        self.insert_node(name, factory)
+    }
+
+    /// Registers an async node, `factory` receives the `params` of the node's definition
+    pub fn register_async<L, F>(&mut self, name: &str, factory: F) -> &mut Self
+    where
+        L: AsyncNodeLogic<S> + Clone,
+        F: Fn(&HashMap<String, NodeValue>) -> L + Send + Sync + 'static,
+    {
+        let factory: NodeFactory<S> = Arc::new(move |params, batch| {
+            let logic = factory(params);
+            Ok(Executable::Async(match batch {
+                BatchMode::Single => AsyncNode::new(logic),
+                BatchMode::Sequential => new_async_batch_node(logic),
+                BatchMode::Parallel => {
+                    new_async_parallel_batch_node(AsyncParallelBatchLogic::new(logic))
+                }
+            }))
+        });
+        self.insert_node(name, factory)
+    }
+
+    /// Registers the `prep_fn` of batch flows, see `BatchFlow::new`
+    pub fn register_params_fn<F>(&mut self, name: &str, params_fn: F) -> &mut Self
+    where
+        F: Fn(&HashMap<String, NodeValue>, &S) -> NodeValue + Send + Sync + 'static,
+    {
+        self.params_fns
+            .insert(name.to_string(), Arc::new(params_fn));
+        self
+    }
+
+    /// Registers how parallel batch flows merge their sub-runs (`MergeStrategy::changed_keys` is
+    /// used when a definition doesn't name one, typed shared states must name one)
+    pub fn register_merge(&mut self, name: &str, merge: MergeStrategy<S>) -> &mut Self {
+        self.merges.insert(name.to_string(), merge);
+        self
+    }
+
+    fn insert_node(&mut self, name: &str, factory: NodeFactory<S>) -> &mut Self {
+        if self.nodes.insert(name.to_string(), factory).is_some() {
+            log::warn!(
+                "Warning: Node type {} was already registered, overwriting it.",
+                name
+            );
+        }
+        self
+    }
+
+    /// Builds a `Flow` from a JSON definition
+    pub fn load_flow(&self, json: &str) -> Result<Flow<S>, LoadError> {
+        self.flow_from_value(&parse_json(json)?)
+    }
+
+    /// Builds a `Flow` from a YAML definition
+    #[cfg(feature = "yaml")]
+    pub fn load_flow_yaml(&self, yaml: &str) -> Result<Flow<S>, LoadError> {
+        self.flow_from_value(&parse_yaml(yaml)?)
+    }
+
+    pub fn flow_from_value(&self, definition: &NodeValue) -> Result<Flow<S>, LoadError> {
+        let (graph, params) = self.build_graph(definition, "", Mode::Sync)?;
+        let mut flow = Flow::from_graph(graph);
+        flow.set_params(params);
+        Ok(flow)
+    }
+
+    /// Builds an `AsyncFlow` from a JSON definition
+    pub fn load_async_flow(&self, json: &str) -> Result<AsyncFlow<S>, LoadError> {
+        self.async_flow_from_value(&parse_json(json)?)
+    }
+
+    /// Builds an `AsyncFlow` from a YAML definition
+    #[cfg(feature = "yaml")]
+    pub fn load_async_flow_yaml(&self, yaml: &str) -> Result<AsyncFlow<S>, LoadError> {
+        self.async_flow_from_value(&parse_yaml(yaml)?)
+    }
+
+    pub fn async_flow_from_value(&self, definition: &NodeValue) -> Result<AsyncFlow<S>, LoadError> {
+        let (graph, params) = self.build_graph(definition, "", Mode::Async)?;
+        let mut flow = AsyncFlow::from_graph(graph);
+        flow.set_params(params);
+        Ok(flow)
+    }
+}
+
+/// A flow's graph and params, as read from its definition
+type LoadedFlow<S> = (Arc<Graph<S>>, HashMap<String, NodeValue>);
+
+/// Whether the flow being built is a `Flow` or an `AsyncFlow`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Sync,
+    Async,
+}
+
+impl<S: Clone + Send + Sync + 'static> Registry<S> {
+    fn build_graph(
+        &self,
+        definition: &NodeValue,
+        path: &str,
+        mode: Mode,
+    ) -> Result<LoadedFlow<S>, LoadError> {
+        let document = as_object(definition, path)?;
+        check_keys(document, path, &["start", "params", "nodes"])?;
+
+        let nodes_path = join(path, "nodes");
+        let nodes = match document.get("nodes") {
+            Some(nodes) => as_object(nodes, &nodes_path)?,
+            None => return Err(invalid(path, "missing \"nodes\"")),
+        };
+        if nodes.is_empty() {
+            return Err(invalid(&nodes_path, "a flow needs at least one node"));
+        }
+
+        let mut builder = GraphBuilder::new();
+        let mut handles: HashMap<&str, NodeHandle> = HashMap::new();
+        for (name, node) in nodes {
+            let node = self.build_node(name, node, &join(&nodes_path, name), mode)?;
+            handles.insert(name, builder.add(node));
+        }
+
+        let find = |name: &NodeValue, path: &str| -> Result<NodeHandle, LoadError> {
+            let name = name
+                .as_str()
+                .ok_or_else(|| invalid(path, "expected the name of a node"))?;
+            handles.get(name).copied().ok_or_else(|| {
+                let mut known: Vec<&str> = handles.keys().copied().collect();
+                known.sort();
+                invalid(
+                    path,
+                    &format!(
+                        "unknown node \"{}\" (the flow's nodes are: {})",
+                        name,
+                        known.join(", ")
+                    ),
+                )
+            })
+        };
+
+        for (name, node) in nodes {
+            let next_path = join(&join(&nodes_path, name), "next");
+            if let Some(next) = node.get("next") {
+                for (action, target) in as_object(next, &next_path)? {
+                    let to = find(target, &join(&next_path, action))?;
+                    builder.connect_on(handles[name.as_str()], action, to);
+                }
+            }
+        }
+
+        let start = match document.get("start") {
+            Some(start) => find(start, &join(path, "start"))?,
+            None => return Err(invalid(path, "missing \"start\"")),
+        };
+
+        let params = match document.get("params") {
+            // Nested flows run with the params of their parent, theirs would be ignored
+            Some(_) if !path.is_empty() => {
+                return Err(invalid(
+                    &join(path, "params"),
+                    "only the top-level flow takes params, nested flows receive their parent's",
+                ));
+            }
+            Some(params) => as_params(params, &join(path, "params"))?,
+            None => HashMap::new(),
+        };
+
+        Ok((builder.build(start), params))
+    }
+
+    fn build_node(
+        &self,
+        id: &str,
+        definition: &NodeValue,
+        path: &str,
+        mode: Mode,
+    ) -> Result<Executable<S>, LoadError> {
+        let node = as_object(definition, path)?;
+        check_keys(
+            node,
+            path,
+            &[
+                "type",
+                "name",
+                "description",
+                "params",
+                "batch",
+                "actions",
+                "next",
+                "flow",
+                "batch_flow",
+            ],
+        )?;
+
+        let kinds: Vec<&str> = ["type", "flow", "batch_flow"]
+            .into_iter()
+            .filter(|kind| node.contains_key(*kind))
+            .collect();
+        let mut executable = match kinds.as_slice() {
+            ["type"] => self.build_registered(node, path, mode)?,
+            ["flow"] => {
+                for key in ["params", "batch"] {
+                    if node.contains_key(key) {
+                        return Err(invalid(
+                            &join(path, key),
+                            "only nodes with a \"type\" take this key",
+                        ));
+                    }
+                }
+                let (graph, _) = self.build_graph(&node["flow"], &join(path, "flow"), mode)?;
+                match mode {
+                    Mode::Sync => Flow::from_graph(graph).into(),
+                    Mode::Async => AsyncFlow::from_graph(graph).into(),
+                }
+            }
+            ["batch_flow"] => {
+                for key in ["params", "batch"] {
+                    if node.contains_key(key) {
+                        return Err(invalid(
+                            &join(path, key),
+                            "only nodes with a \"type\" take this key",
+                        ));
+                    }
+                }
+                self.build_batch_flow(&node["batch_flow"], &join(path, "batch_flow"), mode)?
+            }
+            [] => {
+                return Err(invalid(
+                    path,
+                    "a node needs one of \"type\", \"flow\" or \"batch_flow\"",
+                ));
+            }
+            _ => {
+                return Err(invalid(
+                    path,
+                    &format!("a node can't have both \"{}\"", kinds.join("\" and \"")),
+                ));
+            }
+        };
+
+        let data = match &mut executable {
+            Executable::Sync(node) => &mut node.data,
+            Executable::Async(node) => &mut node.data,
+        };
+        // The node's key in the definition is its id
+        data.id = id.to_string();
+        if let Some(name) = node.get("name") {
+            data.name = as_str(name, &join(path, "name"))?.to_string();
+        }
+        if let Some(description) = node.get("description") {
+            data.description = Some(as_str(description, &join(path, "description"))?.to_string());
+        }
+        if let Some(actions) = node.get("actions") {
+            let actions_path = join(path, "actions");
+            data.actions = actions
+                .as_array()
+                .and_then(|actions| {
+                    actions
+                        .iter()
+                        .map(|action| action.as_str().map(str::to_string))
+                        .collect::<Option<Vec<String>>>()
+                })
+                .ok_or_else(|| invalid(&actions_path, "expected an array of action names"))?;
+        }
+        Ok(executable)
+    }
+
+    fn build_registered(
+        &self,
+        node: &Map<String, NodeValue>,
+        path: &str,
+        mode: Mode,
+    ) -> Result<Executable<S>, LoadError> {
+        let type_path = join(path, "type");
+        let name = node["type"]
+            .as_str()
+            .ok_or_else(|| invalid(&type_path, "expected the name of a registered node"))?;
+        let factory = self.nodes.get(name).ok_or_else(|| {
+            let mut registered: Vec<&str> = self.nodes.keys().map(String::as_str).collect();
+            registered.sort();
+            invalid(
+                &type_path,
+                &format!(
+                    "unknown node type \"{}\" (registered: {})",
+                    name,
+                    registered.join(", ")
+                ),
+            )
+        })?;
+
+        let params = match node.get("params") {
+            Some(params) => as_params(params, &join(path, "params"))?,
+            None => HashMap::new(),
+        };
+        let batch_path = join(path, "batch");
+        let batch = match node.get("batch").map(|batch| batch.as_str()) {
+            None => BatchMode::Single,
+            Some(Some("sequential")) => BatchMode::Sequential,
+            Some(Some("parallel")) => BatchMode::Parallel,
+            Some(_) => {
+                return Err(invalid(
+                    &batch_path,
+                    "expected \"sequential\" or \"parallel\"",
+                ));
+            }
+        };
+
+        let executable = factory(&params, batch).map_err(|message| {
+            let at = if batch == BatchMode::Single {
+                &type_path
+            } else {
+                &batch_path
+            };
+            invalid(at, &message)
+        })?;
+        if mode == Mode::Sync && matches!(executable, Executable::Async(_)) {
+            return Err(invalid(
+                &type_path,
+                &format!(
+                    "\"{}\" is an async node, which a sync Flow can't run (use an AsyncFlow)",
+                    name
+                ),
+            ));
+        }
+        Ok(executable)
+    }
+
+    fn build_batch_flow(
+        &self,
+        definition: &NodeValue,
+        path: &str,
+        mode: Mode,
+    ) -> Result<Executable<S>, LoadError> {
+        let batch_flow = as_object(definition, path)?;
+        check_keys(
+            batch_flow,
+            path,
+            &["params_fn", "parallel", "merge", "flow"],
+        )?;
+
+        let params_fn_path = join(path, "params_fn");
+        let params_fn = match batch_flow.get("params_fn") {
+            Some(name) => {
+                let name = name.as_str().ok_or_else(|| {
+                    invalid(&params_fn_path, "expected the name of a params function")
+                })?;
+                self.params_fns.get(name).cloned().ok_or_else(|| {
+                    let mut registered: Vec<&str> =
+                        self.params_fns.keys().map(String::as_str).collect();
+                    registered.sort();
+                    invalid(
+                        &params_fn_path,
+                        &format!(
+                            "unknown params function \"{}\" (registered: {})",
+                            name,
+                            registered.join(", ")
+                        ),
+                    )
+                })?
+            }
+            None => return Err(invalid(path, "missing \"params_fn\"")),
+        };
+
+        let parallel_path = join(path, "parallel");
+        let parallel = match batch_flow.get("parallel") {
+            Some(parallel) => parallel
+                .as_bool()
+                .ok_or_else(|| invalid(&parallel_path, "expected true or false"))?,
+            None => false,
+        };
+        if parallel && mode == Mode::Sync {
+            return Err(invalid(
+                &parallel_path,
+                "parallel batch flows can only run in an AsyncFlow",
+            ));
+        }
+        let merge_path = join(path, "merge");
+        let merge = match batch_flow.get("merge") {
+            Some(_) if !parallel => {
+                return Err(invalid(&merge_path, "only parallel batch flows merge"));
+            }
+            Some(name) => {
+                let name = name
+                    .as_str()
+                    .ok_or_else(|| invalid(&merge_path, "expected the name of a merge"))?;
+                match self.merges.get(name) {
+                    Some(merge) => Some(merge.clone()),
+                    None => {
+                        return Err(invalid(&merge_path, &format!("unknown merge \"{}\"", name)));
+                    }
+                }
+            }
+            // Only parallel batch flows merge, and typed shared states must say how
+            None if parallel => match MergeStrategy::changed_keys_if_shared_store() {
+                Some(merge) => Some(merge),
+                None => {
+                    return Err(invalid(
+                        &merge_path,
+                        "typed shared states can't be merged key by key, name a registered merge",
+                    ));
+                }
+            },
+            None => None,
+        };
+
+        let flow = match batch_flow.get("flow") {
+            Some(flow) => {
+                let (graph, _) = self.build_graph(flow, &join(path, "flow"), mode)?;
+                match mode {
+                    Mode::Sync => Flow::from_graph(graph).into(),
+                    Mode::Async => AsyncFlow::from_graph(graph).into(),
+                }
+            }
+            None => return Err(invalid(path, "missing \"flow\"")),
+        };
+
+        let prep_fn =
+            move |params: &HashMap<String, NodeValue>, shared: &S| params_fn(params, shared);
+        Ok(match flow {
+            Executable::Sync(flow) => BatchFlow::new(flow, prep_fn).into(),
+            Executable::Async(flow) => match merge {
+                Some(merge) => AsyncParallelBatchFlow::new_with_merge(flow, prep_fn, merge).into(),
+                None => AsyncBatchFlow::new(flow, prep_fn).into(),
+            },
+        })
+    }
+}
+
+fn parse_json(json: &str) -> Result<NodeValue, LoadError> {
+    serde_json::from_str(json).map_err(|e| LoadError::Parse(e.to_string()))
+}
+
+#[cfg(feature = "yaml")]
+fn parse_yaml(yaml: &str) -> Result<NodeValue, LoadError> {
+    serde_yaml::from_str(yaml).map_err(|e| LoadError::Parse(e.to_string()))
+}
+
+/// `nodes` + `fetch` -> `nodes.fetch`, the root of the document being the empty path
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn invalid(path: &str, message: &str) -> LoadError {
+    LoadError::Invalid {
+        path: if path.is_empty() {
+            "the root".to_string()
+        } else {
+            path.to_string()
+        },
+        message: message.to_string(),
+    }
+}
+
+fn as_object<'a>(
+    value: &'a NodeValue,
+    path: &str,
+) -> Result<&'a Map<String, NodeValue>, LoadError> {
+    value
+        .as_object()
+        .ok_or_else(|| invalid(path, "expected an object"))
+}
+
+fn as_str<'a>(value: &'a NodeValue, path: &str) -> Result<&'a str, LoadError> {
+    value
+        .as_str()
+        .ok_or_else(|| invalid(path, "expected a string"))
+}
+
+fn as_params(value: &NodeValue, path: &str) -> Result<HashMap<String, NodeValue>, LoadError> {
+    Ok(as_object(value, path)?
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect())
+}
+
+/// Typos in keys would otherwise be silently ignored
+fn check_keys(
+    object: &Map<String, NodeValue>,
+    path: &str,
+    known: &[&str],
+) -> Result<(), LoadError> {
+    match object.keys().find(|key| !known.contains(&key.as_str())) {
+        Some(key) => Err(invalid(
+            &join(path, key),
+            &format!("unknown key (expected one of: {})", known.join(", ")),
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::error::NodeError;
+    use async_trait::async_trait;
+    use serde_json::json;
+
+    /// Adds `item` to `shared["log"]` and returns `action`
+    #[derive(Clone)]
+    struct Append {
+        item: NodeValue,
+        action: Option<String>,
+    }
+
+    impl NodeLogic for Append {
+        fn post(
+            &self,
+            shared: &mut SharedStore,
+            _prep_res: NodeValue,
+            _exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            let log = shared.entry("log".into()).or_insert(json!([]));
+            log.as_array_mut().unwrap().push(self.item.clone());
+            Ok(self.action.clone())
+        }
+
+        fn clone_box(&self) -> Box<dyn NodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    #[derive(Clone)]
+    struct Wait;
+
+    #[async_trait]
+    impl AsyncNodeLogic for Wait {
+        async fn prep(
+            &self,
+            _params: &HashMap<String, NodeValue>,
+            _shared: &SharedStore,
+        ) -> Result<NodeValue, NodeError> {
+            Ok(NodeValue::Null)
+        }
+
+        async fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+            Ok(NodeValue::Null)
+        }
+
+        async fn post(
+            &self,
+            _shared: &mut SharedStore,
+            _prep_res: NodeValue,
+            _exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            Ok(None)
+        }
+
+        fn clone_box(&self) -> Box<dyn AsyncNodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    /// `append` adds its `item` param to `shared["log"]` and returns its `action` param
+    fn registry() -> Registry {
+        let mut registry = Registry::new();
+        registry.register("append", |params| Append {
+            item: params.get("item").cloned().unwrap_or_default(),
+            action: params
+                .get("action")
+                .and_then(|action| action.as_str())
+                .map(String::from),
+        });
+        registry.register_async("wait", |_| Wait);
+        registry
+    }
+
+    /// The path and message of the error loading `definition` as a `Flow`
+    fn load_error(definition: NodeValue) -> (String, String) {
+        match registry().flow_from_value(&definition) {
+            Err(LoadError::Invalid { path, message }) => (path, message),
+            Err(error) => panic!("expected an invalid definition, got {}", error),
+            Ok(_) => panic!("the definition should be refused"),
+        }
+    }
+
+    #[test]
+    fn loads_and_runs_a_flow() {
+        let flow = registry()
+            .load_flow(
+                r#"{
+                    "start": "check",
+                    "nodes": {
+                        "check": { "type": "append", "params": { "item": 1, "action": "ok" },
+                                   "next": { "ok": "save" } },
+                        "save": { "type": "append", "params": { "item": 2 } }
+                    }
+                }"#,
+            )
+            .unwrap();
+        let mut shared = SharedStore::new();
+        flow.run(&mut shared).unwrap();
+        assert_eq!(shared["log"], json!([1, 2]));
+        assert!(flow.find("save").is_some());
+    }
+
+    #[test]
+    fn unknown_node_types_are_reported() {
+        let (path, message) = load_error(json!({
+            "start": "a",
+            "nodes": { "a": { "type": "nope" } }
+        }));
+        assert_eq!(path, "nodes.a.type");
+        assert_eq!(
+            message,
+            "unknown node type \"nope\" (registered: append, wait)"
+        );
+    }
+
+    #[test]
+    fn edges_must_lead_to_nodes_of_the_flow() {
+        let (path, message) = load_error(json!({
+            "start": "a",
+            "nodes": { "a": { "type": "append", "next": { "done": "missing" } } }
+        }));
+        assert_eq!(path, "nodes.a.next.done");
+        assert_eq!(
+            message,
+            "unknown node \"missing\" (the flow's nodes are: a)"
+        );
+
+        let (path, _) = load_error(json!({
+            "start": "a",
+            "nodes": { "a": { "type": "append", "next": ["b"] } }
+        }));
+        assert_eq!(path, "nodes.a.next");
+
+        let (path, _) = load_error(json!({
+            "start": "b",
+            "nodes": { "a": { "type": "append" } }
+        }));
+        assert_eq!(path, "start");
+    }
+
+    #[test]
+    fn malformed_definitions_are_refused() {
+        let (path, message) = load_error(json!({
+            "start": "a",
+            "nodes": { "a": { "type": "append", "nxt": {} } }
+        }));
+        assert_eq!(path, "nodes.a.nxt");
+        assert!(message.starts_with("unknown key"));
+
+        let (path, _) = load_error(json!({ "start": "a", "nodes": { "a": { "type": "wait" } } }));
+        assert_eq!(path, "nodes.a.type");
+
+        assert!(matches!(
+            registry().load_flow("{ \"start\": "),
+            Err(LoadError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn typed_parallel_batch_flows_must_name_a_merge() {
+        let mut registry = Registry::<Vec<i64>>::new();
+        registry.register_params_fn("items", |_, _| json!([]));
+        let definition = json!({
+            "start": "batch",
+            "nodes": { "batch": { "batch_flow": {
+                "parallel": true, "params_fn": "items", "flow": { "start": "a", "nodes": {} }
+            } } }
+        });
+        let Err(LoadError::Invalid { path, message }) = registry.async_flow_from_value(&definition)
+        else {
+            panic!("the definition should be refused");
+        };
+        assert_eq!(path, "nodes.batch.batch_flow.merge");
+        assert!(message.contains("name a registered merge"));
+    }
+}
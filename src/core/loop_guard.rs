@@ -0,0 +1,212 @@
+use crate::core::graph::NodeHandle;
+use std::collections::VecDeque;
+
+/// The action returned by a flow run stopped by its `LoopGuard`, nodes can't return it
+/// themselves (see `RESERVED_ACTIONS`)
+pub const MAX_STEPS_EXCEEDED_ACTION: &str = "max_steps_exceeded";
+
+/// Cycles longer than this many steps are not looked for
+const MAX_CYCLE_LENGTH: usize = 32;
+
+/// Stops flow runs which loop for too long (an agent that never says "done").
+/// A stopped run returns the `"max_steps_exceeded"` action instead of failing, and the shared
+/// state is left as the last node wrote it so it can be inspected.
+/// The default guard lets runs loop forever.
+#[derive(Clone, Debug, Default)]
+pub struct LoopGuard {
+    max_steps: Option<usize>,
+    cycle_repeats: Option<usize>,
+}
+
+impl LoopGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops the run instead of running more than `max_steps` nodes
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        assert!(max_steps > 0, "Max steps must be greater than 0");
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    /// Stops the run once the same sequence of (node, action) steps repeated `repeats` times in
+    /// a row (for sequences of up to 32 steps)
+    pub fn with_cycle_detection(mut self, repeats: usize) -> Self {
+        assert!(repeats > 1, "A cycle needs to repeat at least twice");
+        self.cycle_repeats = Some(repeats);
+        self
+    }
+
+    pub fn max_steps(&self) -> Option<usize> {
+        self.max_steps
+    }
+
+    pub fn cycle_repeats(&self) -> Option<usize> {
+        self.cycle_repeats
+    }
+
+    /// The tracker following one run
+    pub(crate) fn track(&self) -> LoopTracker<'_> {
+        LoopTracker {
+            guard: self,
+            history: VecDeque::new(),
+        }
+    }
+}
+
+/// Records the steps of a run to tell when its `LoopGuard` must stop it
+pub(crate) struct LoopTracker<'a> {
+    guard: &'a LoopGuard,
+    /// The last steps, as many as the longest cycle looked for takes to repeat
+    history: VecDeque<(NodeHandle, String)>,
+}
+
+impl LoopTracker<'_> {
+    pub(crate) fn record(&mut self, handle: NodeHandle, action: &str) {
+        // Without cycle detection, only the step count matters
+        if let Some(repeats) = self.guard.cycle_repeats {
+            if self.history.len() == MAX_CYCLE_LENGTH * repeats {
+                self.history.pop_front();
+            }
+            self.history.push_back((handle, action.to_string()));
+        }
+    }
+
+    /// Why the run must stop before running step `step`, if it must
+    pub(crate) fn exceeded(&self, step: usize) -> Option<String> {
+        if let Some(max_steps) = self.guard.max_steps
+            && step >= max_steps
+        {
+            return Some(format!("the limit of {} steps was reached", max_steps));
+        }
+        let repeats = self.guard.cycle_repeats?;
+        let longest = (self.history.len() / repeats).min(MAX_CYCLE_LENGTH);
+        (1..=longest)
+            .find(|&length| self.repeats_last(length, repeats))
+            .map(|length| {
+                format!(
+                    "the same {} step(s) were repeated {} times in a row",
+                    length, repeats
+                )
+            })
+    }
+
+    /// Whether the last `length` steps are the same as the `repeats - 1` blocks before them
+    fn repeats_last(&self, length: usize, repeats: usize) -> bool {
+        let end = self.history.len();
+        (end - length * repeats..end - length).all(|i| self.history[i] == self.history[i + length])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Executable;
+    use crate::core::error::NodeError;
+    use crate::core::graph::GraphBuilder;
+    use crate::core::sync_impl::flow::Flow;
+    use crate::core::sync_impl::node::{Node, NodeLogic};
+    use crate::core::sync_impl::{NodeValue, SharedStore};
+    use serde_json::json;
+
+    /// Counts its runs in `shared[key]` and returns `action`
+    #[derive(Clone)]
+    struct Counting {
+        key: &'static str,
+        action: &'static str,
+    }
+
+    impl NodeLogic for Counting {
+        fn post(
+            &self,
+            shared: &mut SharedStore,
+            _prep_res: NodeValue,
+            _exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            let runs = shared
+                .get(self.key)
+                .and_then(|runs| runs.as_u64())
+                .unwrap_or(0);
+            shared.insert(self.key.to_string(), json!(runs + 1));
+            Ok(Some(self.action.to_string()))
+        }
+
+        fn clone_box(&self) -> Box<dyn NodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    fn counting(key: &'static str, action: &'static str) -> Executable {
+        Executable::Sync(Node::new(Counting { key, action }))
+    }
+
+    /// `think` -> `act` -> `think` ... forever
+    fn endless(loop_guard: LoopGuard) -> Flow {
+        let mut builder = GraphBuilder::new();
+        let think = builder.add(counting("think", "default"));
+        let act = builder.add(counting("act", "continue"));
+        builder.connect(think, act);
+        builder.connect_on(act, "continue", think);
+        Flow::from_graph(builder.build(think)).with_loop_guard(loop_guard)
+    }
+
+    #[test]
+    fn max_steps_stops_the_run() {
+        let flow = endless(LoopGuard::new().with_max_steps(5));
+        let mut shared = SharedStore::new();
+        let action = flow.run(&mut shared).unwrap();
+        assert_eq!(action.as_deref(), Some(MAX_STEPS_EXCEEDED_ACTION));
+        assert_eq!(shared["think"], json!(3));
+        assert_eq!(shared["act"], json!(2));
+    }
+
+    #[test]
+    fn cycles_are_stopped_once_repeated() {
+        let flow = endless(LoopGuard::new().with_cycle_detection(3));
+        let mut shared = SharedStore::new();
+        let action = flow.run(&mut shared).unwrap();
+        assert_eq!(action.as_deref(), Some(MAX_STEPS_EXCEEDED_ACTION));
+        assert_eq!(shared["think"], json!(3));
+        assert_eq!(shared["act"], json!(3));
+    }
+
+    #[test]
+    fn a_cycle_is_only_the_same_steps_in_a_row() {
+        let mut builder = GraphBuilder::<SharedStore>::new();
+        let think = builder.add(counting("think", "default"));
+        let act = builder.add(counting("act", "continue"));
+        let guard = LoopGuard::new().with_cycle_detection(2);
+        let mut tracker = guard.track();
+
+        tracker.record(think, "default");
+        tracker.record(act, "continue");
+        tracker.record(think, "default");
+        tracker.record(act, "retry");
+        assert_eq!(tracker.exceeded(4), None);
+        tracker.record(think, "default");
+        tracker.record(act, "retry");
+        let reason = tracker.exceeded(6).expect("(think, act) repeated twice");
+        assert!(reason.contains("same 2 step(s)"));
+    }
+
+    #[test]
+    fn the_history_only_keeps_the_steps_a_cycle_can_span() {
+        let mut builder = GraphBuilder::<SharedStore>::new();
+        let think = builder.add(counting("think", "default"));
+        let act = builder.add(counting("act", "default"));
+        let guard = LoopGuard::new().with_cycle_detection(3);
+        let mut tracker = guard.track();
+        for step in 0..1000 {
+            tracker.record(think, &step.to_string());
+        }
+        assert_eq!(tracker.history.len(), MAX_CYCLE_LENGTH * 3);
+        assert_eq!(tracker.exceeded(1000), None);
+
+        for _ in 0..3 {
+            tracker.record(think, "default");
+            tracker.record(act, "default");
+        }
+        assert!(tracker.exceeded(1006).is_some());
+    }
+}
@@ -1,19 +1,75 @@
-mod async_impl;
-mod sync_impl;
+pub mod async_impl;
+pub mod checkpoint;
+pub mod diagram;
+pub mod error;
+pub mod graph;
+pub mod loader;
+pub mod loop_guard;
+pub mod observer;
+pub mod report;
+pub mod retry;
+pub(crate) mod spans;
+pub mod sync_impl;
+pub mod timeout;
+pub mod unknown_action;
+pub mod wiring;
 
 use async_impl::async_node::AsyncNode;
+use async_impl::cancellation::CANCELLED_ACTION;
+use error::NodeError;
+use graph::{Guard, ValidationIssue};
+use loop_guard::MAX_STEPS_EXCEEDED_ACTION;
 use std::collections::HashMap;
-use sync_impl::node::Node;
+use sync_impl::SharedStore;
+use sync_impl::node::{Node, NodeCore};
+
+/// The actions flows return for the runs they stopped, which nodes can't return themselves
+pub const RESERVED_ACTIONS: [&str; 2] = [CANCELLED_ACTION, MAX_STEPS_EXCEEDED_ACTION];
+
+/// Fails a `post` which returned one of the `RESERVED_ACTIONS`
+pub(crate) fn refuse_reserved_action(
+    action: Result<Option<String>, NodeError>,
+) -> Result<Option<String>, NodeError> {
+    match action {
+        Ok(Some(action)) if RESERVED_ACTIONS.contains(&action.as_str()) => {
+            Err(NodeError::PostError(format!(
+                "action \"{}\" is reserved for the runs flows stop, return another one",
+                action
+            )))
+        }
+        action => action,
+    }
+}
 
 /// The General Executable Enum
-#[derive(Clone)]
-pub enum Executable {
-    Sync(Node),
-    Async(AsyncNode),
+pub enum Executable<S = SharedStore> {
+    Sync(Node<S>),
+    Async(AsyncNode<S>),
 }
 
-impl Executable {
-    pub fn successors(&self) -> &HashMap<String, Executable> {
+impl<S: 'static> Clone for Executable<S> {
+    fn clone(&self) -> Self {
+        match self {
+            Executable::Sync(node) => Executable::Sync(node.clone()),
+            Executable::Async(node) => Executable::Async(node.clone()),
+        }
+    }
+}
+
+impl<S> From<Node<S>> for Executable<S> {
+    fn from(node: Node<S>) -> Self {
+        Executable::Sync(node)
+    }
+}
+
+impl<S> From<AsyncNode<S>> for Executable<S> {
+    fn from(node: AsyncNode<S>) -> Self {
+        Executable::Async(node)
+    }
+}
+
+impl<S> Executable<S> {
+    pub fn successors(&self) -> &HashMap<String, Executable<S>> {
         match self {
             // In this arm, `node` is a `&Node`
             Executable::Sync(node) => &node.data.successors,
@@ -22,4 +78,60 @@ impl Executable {
             Executable::Async(node) => &node.data.successors,
         }
     }
+
+    /// The id, name and description of the node
+    pub fn data(&self) -> &NodeCore<S> {
+        match self {
+            Executable::Sync(node) => &node.data,
+            Executable::Async(node) => &node.data,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.data().id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.data().name
+    }
+
+    /// The actions the node declared it can return
+    pub fn actions(&self) -> &[String] {
+        match self {
+            Executable::Sync(node) => &node.data.actions,
+            Executable::Async(node) => &node.data.actions,
+        }
+    }
+
+    /// The guarded successors, in the order they are checked
+    pub fn guards(&self) -> &[Guard<S>] {
+        match self {
+            Executable::Sync(node) => &node.data.guards,
+            Executable::Async(node) => &node.data.guards,
+        }
+    }
+
+    pub(crate) fn guards_mut(&mut self) -> &mut Vec<Guard<S>> {
+        match self {
+            Executable::Sync(node) => &mut node.data.guards,
+            Executable::Async(node) => &mut node.data.guards,
+        }
+    }
+
+    pub(crate) fn successors_mut(&mut self) -> &mut HashMap<String, Executable<S>> {
+        match self {
+            Executable::Sync(node) => &mut node.data.successors,
+            Executable::Async(node) => &mut node.data.successors,
+        }
+    }
+}
+
+impl<S: 'static> Executable<S> {
+    /// The problems found in the graphs the node runs, if it's a flow
+    pub fn validate(&self) -> Vec<ValidationIssue> {
+        match self {
+            Executable::Sync(node) => node.behaviour.validate(),
+            Executable::Async(node) => node.behaviour.validate(),
+        }
+    }
 }
@@ -0,0 +1,331 @@
+use crate::core::error::NodeError;
+use crate::core::sync_impl::NodeValue;
+use crate::core::sync_impl::node::NodeCore;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+tokio::task_local! {
+    /// The observers of the async run in progress
+    static TASK_OBSERVERS: Observers;
+}
+
+thread_local! {
+    /// The observers of the sync run in progress (sync nodes can't see task-locals, even when an
+    /// `AsyncFlow` runs them)
+    static THREAD_OBSERVERS: RefCell<Option<Observers>> = const { RefCell::new(None) };
+}
+
+/// Receives the events of the flows it was registered on (see `Flow::with_observer`), nested
+/// flows included. Events are delivered as they happen, on the thread running the node, so
+/// observers should hand slow work (I/O, UIs) off rather than block the flow.
+pub trait FlowObserver: Send + Sync {
+    fn on_event(&self, event: &FlowEvent<'_>);
+}
+
+/// So one observer can be registered on several flows
+impl<O: FlowObserver + ?Sized> FlowObserver for Arc<O> {
+    fn on_event(&self, event: &FlowEvent<'_>) {
+        (**self).on_event(event)
+    }
+}
+
+/// The node an event is about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NodeInfo<'a> {
+    pub id: &'a str,
+    pub name: &'a str,
+}
+
+/// What happened during a run. Durations are measured from the matching `*Start` event.
+#[derive(Debug)]
+pub enum FlowEvent<'a> {
+    FlowStart {
+        flow: NodeInfo<'a>,
+        params: &'a HashMap<String, NodeValue>,
+    },
+    /// `outcome` is the flow's last action
+    FlowEnd {
+        flow: NodeInfo<'a>,
+        outcome: Result<Option<&'a str>, &'a NodeError>,
+        duration: Duration,
+    },
+    PrepStart {
+        node: NodeInfo<'a>,
+        params: &'a HashMap<String, NodeValue>,
+    },
+    PrepEnd {
+        node: NodeInfo<'a>,
+        outcome: Result<&'a NodeValue, &'a NodeError>,
+        duration: Duration,
+    },
+    /// `attempt` starts at 1 and is increased by each retry
+    ExecStart {
+        node: NodeInfo<'a>,
+        input: &'a NodeValue,
+        attempt: usize,
+    },
+    ExecEnd {
+        node: NodeInfo<'a>,
+        attempt: usize,
+        outcome: Result<&'a NodeValue, &'a NodeError>,
+        duration: Duration,
+    },
+    /// `exec_res` is what `post` receives, which comes from `exec_fallback` if every attempt
+    /// failed
+    PostStart {
+        node: NodeInfo<'a>,
+        exec_res: &'a NodeValue,
+    },
+    /// `outcome` is the action chosen by the node
+    PostEnd {
+        node: NodeInfo<'a>,
+        outcome: Result<Option<&'a str>, &'a NodeError>,
+        duration: Duration,
+    },
+    /// `flow` ran one of its nodes, `outcome` being the action it returned
+    Step {
+        flow: NodeInfo<'a>,
+        node: NodeInfo<'a>,
+        step: usize,
+        outcome: Result<Option<&'a str>, &'a NodeError>,
+        duration: Duration,
+    },
+    /// An item of a batch node, or a param set of a batch flow, is done
+    BatchItem {
+        node: NodeInfo<'a>,
+        index: usize,
+        total: usize,
+        outcome: Result<(), &'a NodeError>,
+        duration: Duration,
+    },
+}
+
+impl<'a> FlowEvent<'a> {
+    /// The node (or flow) the event is about
+    pub fn node(&self) -> NodeInfo<'a> {
+        match self {
+            FlowEvent::FlowStart { flow, .. } | FlowEvent::FlowEnd { flow, .. } => *flow,
+            FlowEvent::PrepStart { node, .. }
+            | FlowEvent::PrepEnd { node, .. }
+            | FlowEvent::ExecStart { node, .. }
+            | FlowEvent::ExecEnd { node, .. }
+            | FlowEvent::PostStart { node, .. }
+            | FlowEvent::PostEnd { node, .. }
+            | FlowEvent::Step { node, .. }
+            | FlowEvent::BatchItem { node, .. } => *node,
+        }
+    }
+}
+
+/// The observers of the run in progress (the ones of every flow it is nested in), and the node
+/// being run, which batch items are reported for
+#[derive(Clone)]
+pub(crate) struct Observers {
+    observers: Arc<[Arc<dyn FlowObserver>]>,
+    node: Option<(String, String)>,
+}
+
+impl Observers {
+    /// The observers of a flow: the inherited ones, then its own. `None` if there are none.
+    pub(crate) fn join(
+        inherited: Option<Observers>,
+        own: &[Arc<dyn FlowObserver>],
+    ) -> Option<Observers> {
+        match inherited {
+            Some(inherited) if own.is_empty() => Some(inherited),
+            None if own.is_empty() => None,
+            inherited => Some(Observers {
+                observers: inherited
+                    .iter()
+                    .flat_map(|inherited| inherited.observers.iter())
+                    .chain(own)
+                    .cloned()
+                    .collect(),
+                node: None,
+            }),
+        }
+    }
+
+    /// The same observers, while `node` is run
+    pub(crate) fn at<S>(&self, node: &NodeCore<S>) -> Observers {
+        Observers {
+            observers: Arc::clone(&self.observers),
+            node: Some((node.id.clone(), node.name.clone())),
+        }
+    }
+
+    fn notify(&self, event: &FlowEvent<'_>) {
+        for observer in self.observers.iter() {
+            observer.on_event(event);
+        }
+    }
+}
+
+/// The observers of the run in progress, if it is observed.
+/// The thread's come first: they are only set while a sync node runs, which makes them the
+/// innermost ones, even when an async task runs that node.
+pub(crate) fn current() -> Option<Observers> {
+    THREAD_OBSERVERS
+        .with(|observers| observers.borrow().clone())
+        .or_else(|| TASK_OBSERVERS.try_with(|observers| observers.clone()).ok())
+}
+
+/// Builds the event only if someone is listening
+pub(crate) fn emit<'a>(observers: Option<&Observers>, event: impl FnOnce() -> FlowEvent<'a>) {
+    if let Some(observers) = observers {
+        observers.notify(&event());
+    }
+}
+
+/// Reports an item of the batch being run
+pub(crate) fn batch_item(
+    index: usize,
+    total: usize,
+    outcome: Result<(), &NodeError>,
+    duration: Duration,
+) {
+    if let Some(observers) = current()
+        && let Some((id, name)) = &observers.node
+    {
+        observers.notify(&FlowEvent::BatchItem {
+            node: NodeInfo { id, name },
+            index,
+            total,
+            outcome,
+            duration,
+        });
+    }
+}
+
+/// Restores the thread's previous observers, even if the code they were replaced for panics
+struct Restore(Option<Observers>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        THREAD_OBSERVERS.with(|observers| *observers.borrow_mut() = self.0.take());
+    }
+}
+
+/// Runs `f` (synchronously) with `observers` visible to the nodes it runs
+pub(crate) fn scoped_sync<R>(observers: Option<Observers>, f: impl FnOnce() -> R) -> R {
+    let Some(observers) = observers else {
+        return f();
+    };
+    let _restore = Restore(THREAD_OBSERVERS.with(|current| current.replace(Some(observers))));
+    f()
+}
+
+/// Runs `f`, which blocks the thread on async nodes, with the thread's observers handed to it to
+/// be scoped to its task: async flows nested in those nodes can then add their own
+pub(crate) fn handed_to_task<R>(f: impl FnOnce(Option<Observers>) -> R) -> R {
+    let restore = Restore(THREAD_OBSERVERS.with(|current| current.replace(None)));
+    f(restore.0.clone())
+}
+
+/// Awaits `fut` with `observers` visible to the nodes it runs
+pub(crate) async fn scoped<F: Future>(observers: Option<Observers>, fut: F) -> F::Output {
+    match observers {
+        Some(observers) => TASK_OBSERVERS.scope(observers, fut).await,
+        None => fut.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Executable;
+    use crate::core::retry::RetryPolicy;
+    use crate::core::sync_impl::SharedStore;
+    use crate::core::sync_impl::flow::Flow;
+    use crate::core::sync_impl::node::{Node, NodeLogic};
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Writes every event down as `<event> <node id>`
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl FlowObserver for Recorder {
+        fn on_event(&self, event: &FlowEvent<'_>) {
+            let kind = match event {
+                FlowEvent::FlowStart { .. } => "flow start".to_string(),
+                FlowEvent::FlowEnd { .. } => "flow end".to_string(),
+                FlowEvent::PrepStart { .. } => "prep start".to_string(),
+                FlowEvent::PrepEnd { .. } => "prep end".to_string(),
+                FlowEvent::ExecStart { attempt, .. } => format!("exec start #{}", attempt),
+                FlowEvent::ExecEnd {
+                    attempt, outcome, ..
+                } => format!("exec end #{} ok={}", attempt, outcome.is_ok()),
+                FlowEvent::PostStart { .. } => "post start".to_string(),
+                FlowEvent::PostEnd { .. } => "post end".to_string(),
+                FlowEvent::Step { step, .. } => format!("step {}", step),
+                FlowEvent::BatchItem { index, .. } => format!("item {}", index),
+            };
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", kind, event.node().id));
+        }
+    }
+
+    /// Fails its first `exec`
+    #[derive(Clone, Default)]
+    struct FailsOnce(Arc<AtomicUsize>);
+
+    impl NodeLogic for FailsOnce {
+        fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+            match self.0.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(NodeError::ExecError("first try".into())),
+                _ => Ok(NodeValue::Null),
+            }
+        }
+
+        fn clone_box(&self) -> Box<dyn NodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    #[test]
+    fn events_follow_the_run_in_order() {
+        let retried = Node::new(FailsOnce::default())
+            .with_id("a")
+            .with_retry(RetryPolicy::new(2));
+        let inner = Flow::new(Node::new(FailsOnce(Arc::new(AtomicUsize::new(1)))).with_id("b"))
+            .with_id("inner");
+        let recorder = Arc::new(Recorder::default());
+        let flow = Flow::new(retried.next(Executable::Sync((*inner).clone())))
+            .with_id("outer")
+            .with_observer(recorder.clone());
+
+        flow.run(&mut SharedStore::new()).unwrap();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                "flow start outer",
+                "prep start a",
+                "prep end a",
+                "exec start #1 a",
+                "exec end #1 ok=false a",
+                "exec start #2 a",
+                "exec end #2 ok=true a",
+                "post start a",
+                "post end a",
+                "step 0 a",
+                "flow start inner",
+                "prep start b",
+                "prep end b",
+                "exec start #1 b",
+                "exec end #1 ok=true b",
+                "post start b",
+                "post end b",
+                "step 0 b",
+                "flow end inner",
+                "step 1 inner",
+                "flow end outer",
+            ]
+        );
+    }
+}
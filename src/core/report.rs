@@ -0,0 +1,184 @@
+use crate::core::error::NodeError;
+use crate::core::observer::{FlowEvent, FlowObserver};
+use serde::{Serialize, Serializer};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What a flow run did, returned by `Flow::run_with_report` (and `AsyncFlow`'s).
+/// Nested flows are reported as one step, their own steps can be followed with a `FlowObserver`.
+#[derive(Clone, Debug, Serialize)]
+pub struct RunReport {
+    /// The id of the flow
+    pub flow: String,
+    /// The nodes the flow visited, in order
+    pub steps: Vec<StepReport>,
+    /// The flow's last action, `None` if it failed
+    pub final_action: Option<String>,
+    pub error: Option<String>,
+    #[serde(rename = "duration_ms", serialize_with = "as_millis")]
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StepReport {
+    pub step: usize,
+    /// The id of the node
+    pub node: String,
+    pub name: String,
+    /// The action the flow followed (`default` if the node returned none), `None` if it failed
+    pub action: Option<String>,
+    pub error: Option<String>,
+    /// How many times `exec` was retried
+    pub retries: usize,
+    #[serde(rename = "duration_ms", serialize_with = "as_millis")]
+    pub duration: Duration,
+}
+
+/// Builds the report of a run from its events
+#[derive(Default)]
+pub(crate) struct Recorder {
+    recording: Mutex<Recording>,
+}
+
+#[derive(Default)]
+struct Recording {
+    /// How many flows are running: the reported one is at depth 1, the ones nested in it deeper
+    depth: usize,
+    /// The attempts of the node being run by the reported flow
+    attempts: usize,
+    steps: Vec<StepReport>,
+}
+
+impl Recorder {
+    pub(crate) fn report(
+        &self,
+        flow: &str,
+        outcome: &Result<Option<String>, NodeError>,
+        duration: Duration,
+    ) -> RunReport {
+        let steps = std::mem::take(&mut self.recording.lock().unwrap().steps);
+        RunReport {
+            flow: flow.to_string(),
+            steps,
+            final_action: outcome.as_ref().ok().cloned().flatten(),
+            error: outcome.as_ref().err().map(|error| error.to_string()),
+            duration,
+        }
+    }
+}
+
+impl FlowObserver for Recorder {
+    fn on_event(&self, event: &FlowEvent<'_>) {
+        let mut recording = self.recording.lock().unwrap();
+        match event {
+            FlowEvent::FlowStart { .. } => recording.depth += 1,
+            FlowEvent::FlowEnd { .. } => recording.depth -= 1,
+            // Only the nodes of the reported flow count, not the ones of its nested flows
+            FlowEvent::ExecStart { attempt, .. } if recording.depth == 1 => {
+                recording.attempts = *attempt
+            }
+            FlowEvent::Step {
+                node,
+                step,
+                outcome,
+                duration,
+                ..
+            } if recording.depth == 1 => {
+                let retries = recording.attempts.saturating_sub(1);
+                recording.attempts = 0;
+                recording.steps.push(StepReport {
+                    step: *step,
+                    node: node.id.to_string(),
+                    name: node.name.to_string(),
+                    action: outcome
+                        .ok()
+                        .map(|action| action.unwrap_or("default").to_string()),
+                    error: outcome.err().map(|error| error.to_string()),
+                    retries,
+                    duration: *duration,
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+fn as_millis<Ser: Serializer>(duration: &Duration, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::Executable;
+    use crate::core::error::NodeError;
+    use crate::core::retry::RetryPolicy;
+    use crate::core::sync_impl::flow::Flow;
+    use crate::core::sync_impl::node::{Node, NodeLogic};
+    use crate::core::sync_impl::{NodeValue, SharedStore};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Fails the `exec` attempts before `succeeds_at` (forever if `None`)
+    #[derive(Clone)]
+    struct Flaky {
+        attempts: Arc<AtomicUsize>,
+        succeeds_at: Option<usize>,
+    }
+
+    impl NodeLogic for Flaky {
+        fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst) + 1;
+            match self.succeeds_at {
+                Some(succeeds_at) if attempt >= succeeds_at => Ok(NodeValue::Null),
+                _ => Err(NodeError::ExecError(format!("attempt {} failed", attempt))),
+            }
+        }
+
+        fn clone_box(&self) -> Box<dyn NodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    fn flaky(id: &str, succeeds_at: Option<usize>) -> Node {
+        Node::new(Flaky {
+            attempts: Arc::default(),
+            succeeds_at,
+        })
+        .with_id(id)
+        .with_name(&id.to_uppercase())
+    }
+
+    #[test]
+    fn reports_the_steps_up_to_the_failing_node() {
+        let fetch = flaky("fetch", Some(3)).with_retry(RetryPolicy::new(3));
+        let parse = flaky("parse", None).with_retry(RetryPolicy::new(2));
+        let flow = Flow::new(fetch.next(Executable::Sync(parse))).with_id("pipeline");
+
+        let report = flow.run_with_report(&mut SharedStore::new());
+        assert_eq!(report.flow, "pipeline");
+        assert_eq!(report.final_action, None);
+        let error = report.error.as_deref().unwrap();
+        assert!(error.contains("Node \"parse\" at step 1"), "{}", error);
+        assert!(error.ends_with("attempt 2 failed"), "{}", error);
+
+        let [fetch, parse] = &report.steps[..] else {
+            panic!("expected two steps, got {:?}", report.steps);
+        };
+        assert_eq!((fetch.step, fetch.node.as_str()), (0, "fetch"));
+        assert_eq!(fetch.name, "FETCH");
+        assert_eq!(fetch.action.as_deref(), Some("default"));
+        assert_eq!((fetch.error.as_deref(), fetch.retries), (None, 2));
+        assert_eq!((parse.step, parse.node.as_str()), (1, "parse"));
+        assert_eq!(parse.action, None);
+        assert_eq!(
+            parse.error.as_deref(),
+            Some("Error occurred during exec: attempt 2 failed")
+        );
+        assert_eq!(parse.retries, 1);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["steps"][1]["node"], "parse");
+        assert!(json["steps"][1]["duration_ms"].is_f64());
+        assert!(json["duration_ms"].is_f64());
+    }
+}
@@ -0,0 +1,205 @@
+use crate::core::error::NodeError;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long to wait between two attempts of `exec`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Backoff {
+    /// Wait the same amount of time after every failed attempt
+    Fixed(Duration),
+    /// Wait `base * factor^(attempt - 1)`, capped at `max`
+    Exponential {
+        base: Duration,
+        factor: f64,
+        max: Duration,
+    },
+}
+
+impl Backoff {
+    pub fn fixed(wait: Duration) -> Self {
+        Backoff::Fixed(wait)
+    }
+
+    /// Doubles the wait after every attempt, capped at `max`
+    pub fn exponential(base: Duration, max: Duration) -> Self {
+        Backoff::Exponential {
+            base,
+            factor: 2.0,
+            max,
+        }
+    }
+
+    /// The wait before attempt `attempt + 1` (attempts are counted from 1), without jitter
+    pub fn delay(&self, attempt: usize) -> Duration {
+        match self {
+            Backoff::Fixed(wait) => *wait,
+            Backoff::Exponential { base, factor, max } => {
+                let exponent = attempt.saturating_sub(1).min(i32::MAX as usize) as i32;
+                let scaled = base.as_secs_f64() * factor.powi(exponent);
+                // `from_secs_f64` panics on overflow, the cap protects us from it
+                if scaled.is_finite() && scaled < max.as_secs_f64() {
+                    Duration::from_secs_f64(scaled)
+                } else {
+                    *max
+                }
+            }
+        }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::Fixed(Duration::ZERO)
+    }
+}
+
+/// The predicate deciding whether an error is worth another attempt
+pub type RetryPredicate = Arc<dyn Fn(&NodeError) -> bool + Send + Sync>;
+
+/// The retry policy of a node (stored on `NodeCore`).
+/// `Node::run` and `AsyncNode::run` call `exec` up to `max_attempts` times, waiting according to
+/// the `backoff` in between. Once the last attempt failed (or the error isn't retryable), the
+/// logic's `exec_fallback` gets the final say.
+/// The default policy is the PocketFlow one: a single attempt and no wait.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    backoff: Backoff,
+    jitter: f64,
+    retry_on: Option<RetryPredicate>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff: Backoff::default(),
+            jitter: 0.0,
+            retry_on: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy allowing `max_attempts` calls to `exec` (the first call included)
+    pub fn new(max_attempts: usize) -> Self {
+        assert!(max_attempts > 0, "Max attempts must be greater than 0");
+        RetryPolicy {
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Randomly shortens each wait by up to `jitter` (a ratio between 0 and 1) of its length,
+    /// so that many nodes failing at once don't all retry at the same time.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&jitter),
+            "Jitter must be between 0 and 1"
+        );
+        self.jitter = jitter;
+        self
+    }
+
+    /// Only retry the errors for which `predicate` returns true (every error is retried otherwise)
+    pub fn retry_on<P>(mut self, predicate: P) -> Self
+    where
+        P: Fn(&NodeError) -> bool + Send + Sync + 'static,
+    {
+        self.retry_on = Some(Arc::new(predicate));
+        self
+    }
+
+    pub fn max_attempts(&self) -> usize {
+        self.max_attempts
+    }
+
+    /// Whether `error`, returned by attempt number `attempt`, should lead to another attempt.
+    /// A cancelled, suspended or out of time run is never retried, whatever `retry_on` says.
+    pub fn should_retry(&self, attempt: usize, error: &NodeError) -> bool {
+        let stops_the_run = matches!(
+            error,
+            NodeError::Cancelled | NodeError::Suspended(_) | NodeError::DeadlineExceeded { .. }
+        );
+        !stops_the_run
+            && attempt < self.max_attempts
+            && self
+                .retry_on
+                .as_ref()
+                .is_none_or(|predicate| predicate(error))
+    }
+
+    /// The wait (jitter included) before the attempt following attempt number `attempt`
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let delay = self.backoff.delay(attempt);
+        if self.jitter > 0.0 {
+            delay.mul_f64(1.0 - self.jitter * fastrand::f64())
+        } else {
+            delay
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::async_impl::human_node::SuspendedRun;
+    use crate::core::sync_impl::NodeValue;
+
+    #[test]
+    fn retries_until_max_attempts() {
+        let retry = RetryPolicy::new(3);
+        let error = NodeError::ExecError("flaky".into());
+        assert!(retry.should_retry(1, &error));
+        assert!(retry.should_retry(2, &error));
+        assert!(!retry.should_retry(3, &error));
+    }
+
+    #[test]
+    fn retry_on_filters_errors() {
+        let retry = RetryPolicy::new(3)
+            .retry_on(|error| matches!(error, NodeError::ExecError(message) if message == "busy"));
+        assert!(retry.should_retry(1, &NodeError::ExecError("busy".into())));
+        assert!(!retry.should_retry(1, &NodeError::ExecError("bad input".into())));
+    }
+
+    #[test]
+    fn never_retries_errors_stopping_the_run() {
+        let retry = RetryPolicy::new(5).retry_on(|_| true);
+        assert!(!retry.should_retry(1, &NodeError::Cancelled));
+        assert!(!retry.should_retry(1, &NodeError::DeadlineExceeded { step: 0 }));
+        let suspended = SuspendedRun {
+            node: "review".into(),
+            payload: NodeValue::Null,
+            flows: Vec::new(),
+            shared: NodeValue::Null,
+        };
+        assert!(!retry.should_retry(1, &NodeError::Suspended(Box::new(suspended))));
+    }
+
+    #[test]
+    fn exponential_backoff_is_capped() {
+        let backoff = Backoff::exponential(Duration::from_millis(10), Duration::from_millis(50));
+        assert_eq!(backoff.delay(1), Duration::from_millis(10));
+        assert_eq!(backoff.delay(2), Duration::from_millis(20));
+        assert_eq!(backoff.delay(3), Duration::from_millis(40));
+        assert_eq!(backoff.delay(4), Duration::from_millis(50));
+        assert_eq!(backoff.delay(usize::MAX), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_wait() {
+        let retry = RetryPolicy::new(2)
+            .with_backoff(Backoff::fixed(Duration::from_millis(100)))
+            .with_jitter(0.5);
+        for _ in 0..20 {
+            let delay = retry.delay(1);
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        }
+    }
+}
@@ -0,0 +1,308 @@
+#[cfg(feature = "tracing")]
+pub(crate) use enabled::*;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) use disabled::*;
+
+#[cfg(feature = "tracing")]
+mod enabled {
+    use crate::core::error::NodeError;
+    use crate::core::sync_impl::node::NodeCore;
+    use std::future::Future;
+    use tracing::Instrument;
+    use tracing::field::Empty;
+
+    /// A span opened around a flow run, a node or one of its phases
+    #[derive(Clone)]
+    pub(crate) struct Span(tracing::Span);
+
+    /// The span of the run in progress, sync nodes run by an `AsyncFlow` are attached to it
+    pub(crate) fn current() -> Span {
+        Span(tracing::Span::current())
+    }
+
+    pub(crate) fn flow<S>(node: &NodeCore<S>) -> Span {
+        Span(tracing::info_span!(
+            "flow",
+            id = %node.id,
+            name = %node.name,
+            action = Empty,
+            error = Empty,
+        ))
+    }
+
+    pub(crate) fn batch_flow<S>(node: &NodeCore<S>) -> Span {
+        Span(tracing::info_span!(
+            "batch_flow",
+            id = %node.id,
+            name = %node.name,
+            action = Empty,
+            error = Empty,
+        ))
+    }
+
+    /// An item of a batch node, or a param set of a batch flow
+    pub(crate) fn batch_item(index: usize) -> Span {
+        Span(tracing::debug_span!("batch_item", index))
+    }
+
+    pub(crate) fn node<S>(node: &NodeCore<S>) -> Span {
+        Span(tracing::info_span!(
+            "node",
+            id = %node.id,
+            name = %node.name,
+            action = Empty,
+            error = Empty,
+        ))
+    }
+
+    pub(crate) fn prep() -> Span {
+        Span(tracing::debug_span!("prep"))
+    }
+
+    pub(crate) fn exec(attempt: usize) -> Span {
+        Span(tracing::debug_span!("exec", attempt))
+    }
+
+    pub(crate) fn post() -> Span {
+        Span(tracing::debug_span!("post"))
+    }
+
+    impl Span {
+        pub(crate) fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+            self.0.in_scope(f)
+        }
+
+        pub(crate) async fn instrument<F: Future>(&self, fut: F) -> F::Output {
+            fut.instrument(self.0.clone()).await
+        }
+
+        /// Records the action taken (`default` if none was returned), or the error
+        pub(crate) fn record(&self, outcome: &Result<Option<String>, NodeError>) {
+            match outcome {
+                Ok(action) => {
+                    self.0
+                        .record("action", action.as_deref().unwrap_or("default"));
+                }
+                Err(error) => {
+                    self.0.record("error", tracing::field::display(error));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod disabled {
+    use crate::core::error::NodeError;
+    use crate::core::sync_impl::node::NodeCore;
+    use std::future::Future;
+
+    /// Without the `tracing` feature, spans compile to nothing so callers don't need `cfg`s
+    #[derive(Clone)]
+    pub(crate) struct Span;
+
+    pub(crate) fn current() -> Span {
+        Span
+    }
+
+    pub(crate) fn flow<S>(_node: &NodeCore<S>) -> Span {
+        Span
+    }
+
+    pub(crate) fn batch_flow<S>(_node: &NodeCore<S>) -> Span {
+        Span
+    }
+
+    pub(crate) fn batch_item(_index: usize) -> Span {
+        Span
+    }
+
+    pub(crate) fn node<S>(_node: &NodeCore<S>) -> Span {
+        Span
+    }
+
+    pub(crate) fn prep() -> Span {
+        Span
+    }
+
+    pub(crate) fn exec(_attempt: usize) -> Span {
+        Span
+    }
+
+    pub(crate) fn post() -> Span {
+        Span
+    }
+
+    impl Span {
+        pub(crate) fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+            f()
+        }
+
+        pub(crate) async fn instrument<F: Future>(&self, fut: F) -> F::Output {
+            fut.await
+        }
+
+        pub(crate) fn record(&self, _outcome: &Result<Option<String>, NodeError>) {}
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::core::Executable;
+    use crate::core::error::NodeError;
+    use crate::core::sync_impl::flow::Flow;
+    use crate::core::sync_impl::node::{Node, NodeLogic};
+    use crate::core::sync_impl::{NodeValue, SharedStore};
+    use std::fmt::Debug;
+    use std::sync::Mutex;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// A span as seen by `Recorder`: its name, the index of its parent and its fields
+    #[derive(Debug)]
+    struct Recorded {
+        name: &'static str,
+        parent: Option<usize>,
+        fields: Vec<(&'static str, String)>,
+    }
+
+    impl Visit for Recorded {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.fields.push((field.name(), format!("{:?}", value)));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.fields.push((field.name(), value.to_string()));
+        }
+    }
+
+    /// Keeps every span opened (span ids are their index + 1), the parent of a span being the
+    /// one entered when it was opened
+    #[derive(Default)]
+    struct Recorder {
+        spans: Mutex<Vec<Recorded>>,
+        entered: Mutex<Vec<usize>>,
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+            let mut span = Recorded {
+                name: attributes.metadata().name(),
+                parent: self.entered.lock().unwrap().last().copied(),
+                fields: Vec::new(),
+            };
+            attributes.record(&mut span);
+            let mut spans = self.spans.lock().unwrap();
+            spans.push(span);
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, id: &Id, values: &Record<'_>) {
+            values.record(&mut self.spans.lock().unwrap()[id.into_u64() as usize - 1]);
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &Event<'_>) {}
+
+        fn enter(&self, id: &Id) {
+            self.entered
+                .lock()
+                .unwrap()
+                .push(id.into_u64() as usize - 1);
+        }
+
+        fn exit(&self, _id: &Id) {
+            self.entered.lock().unwrap().pop();
+        }
+    }
+
+    #[derive(Clone)]
+    struct Noop;
+
+    impl NodeLogic for Noop {
+        fn clone_box(&self) -> Box<dyn NodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    #[test]
+    fn spans_nest_like_the_run() {
+        let recorder = std::sync::Arc::new(Recorder::default());
+        let flow = Flow::new(
+            Node::new(Noop)
+                .with_id("a")
+                .next_on(Executable::Sync(Node::new(Noop).with_id("b")), "default"),
+        )
+        .with_id("outer");
+        tracing::subscriber::with_default(recorder.clone(), || {
+            flow.run(&mut SharedStore::new()).unwrap();
+        });
+
+        let spans = recorder.spans.lock().unwrap();
+        // `(name, parent's name, id field)`
+        let tree: Vec<_> = spans
+            .iter()
+            .map(|span| {
+                let id = span
+                    .fields
+                    .iter()
+                    .find(|(field, _)| *field == "id")
+                    .map(|(_, id)| id.as_str());
+                (span.name, span.parent.map(|parent| spans[parent].name), id)
+            })
+            .collect();
+        assert_eq!(
+            tree,
+            [
+                ("flow", None, Some("outer")),
+                ("node", Some("flow"), Some("a")),
+                ("prep", Some("node"), None),
+                ("exec", Some("node"), None),
+                ("post", Some("node"), None),
+                ("node", Some("flow"), Some("b")),
+                ("prep", Some("node"), None),
+                ("exec", Some("node"), None),
+                ("post", Some("node"), None),
+            ]
+        );
+        // The outcome is recorded once the span is done
+        assert!(spans[0].fields.contains(&("action", "default".into())));
+        assert!(spans[1].fields.contains(&("action", "default".into())));
+    }
+
+    #[test]
+    fn errors_are_recorded_on_the_failing_node() {
+        #[derive(Clone)]
+        struct Failing;
+
+        impl NodeLogic for Failing {
+            fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+                Err(NodeError::ExecError("down".into()))
+            }
+
+            fn clone_box(&self) -> Box<dyn NodeLogic> {
+                Box::new((*self).clone())
+            }
+        }
+
+        let recorder = std::sync::Arc::new(Recorder::default());
+        let node = Node::new(Failing).with_id("broken");
+        tracing::subscriber::with_default(recorder.clone(), || {
+            node.run(&mut SharedStore::new()).unwrap_err();
+        });
+        let spans = recorder.spans.lock().unwrap();
+        assert_eq!(spans[0].name, "node");
+        assert!(
+            spans[0]
+                .fields
+                .contains(&("error", "Error occurred during exec: down".into()))
+        );
+    }
+}
@@ -1,6 +1,13 @@
-use crate::core::sync_impl::NodeValue;
-use crate::core::sync_impl::node::{Node, NodeLogic};
+use crate::core::Executable;
+use crate::core::diagram::{Diagram, DiagramKind, DiagramNode};
+use crate::core::error::NodeError;
+use crate::core::graph::ValidationIssue;
+use crate::core::observer;
+use crate::core::spans;
+use crate::core::sync_impl::node::{Node, NodeCore, NodeLogic};
+use crate::core::sync_impl::{NodeValue, SharedStore};
 use std::collections::HashMap;
+use std::time::Instant;
 
 /// A BatchFlow is a `Node` (so orchestrable) which runs
 /// a `Flow` many times with different params.
@@ -11,115 +18,226 @@ use std::collections::HashMap;
 /// Damn, I might be a prophet. But yup, after reconsideration
 /// everything up there holds true, except the `BatchFlowLogic` holds a `Node` (this was the most
 /// straightforward way I could think to enable BatchFlow nesting)
-pub struct BatchFlow(Node);
+pub struct BatchFlow<S = SharedStore>(Node<S>);
 
 /// The Derefs are needed to be able to access the inside `Node` of the `Flow` easily
-impl std::ops::Deref for BatchFlow {
-    type Target = Node;
+impl<S> std::ops::Deref for BatchFlow<S> {
+    type Target = Node<S>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
     }
 }
 
-impl std::ops::DerefMut for BatchFlow {
+impl<S> From<BatchFlow<S>> for Executable<S> {
+    fn from(flow: BatchFlow<S>) -> Self {
+        Executable::Sync(flow.0)
+    }
+}
+
+impl<S> std::ops::DerefMut for BatchFlow<S> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.0
     }
 }
 
-#[derive(Clone)]
-pub struct BatchFlowLogic<F>
+pub struct BatchFlowLogic<F, S = SharedStore>
 where
-    F: Fn(&HashMap<String, NodeValue>, &HashMap<String, NodeValue>) -> NodeValue
-        + Clone
-        + Send
-        + Sync
-        + 'static,
+    F: Fn(&HashMap<String, NodeValue>, &S) -> NodeValue + Clone + Send + Sync + 'static,
 {
     // We have node so that we may nest BatchFlow'self
     // Technically, you could BatchFlow a single node as well?
     // But it's not as helpful
-    flow: Node,
+    flow: Node<S>,
     prep_fn: F,
 }
 
-impl<F> NodeLogic for BatchFlowLogic<F>
+impl<F, S: 'static> Clone for BatchFlowLogic<F, S>
 where
-    F: Fn(&HashMap<String, NodeValue>, &HashMap<String, NodeValue>) -> NodeValue
-        + Clone
-        + Send
-        + Sync
-        + 'static,
+    F: Fn(&HashMap<String, NodeValue>, &S) -> NodeValue + Clone + Send + Sync + 'static,
 {
-    fn prep(
-        &self,
-        params: &HashMap<String, NodeValue>,
-        shared: &HashMap<String, NodeValue>,
-    ) -> NodeValue {
-        // Call the user-defined closure
-        serde_json::to_value((shared, (self.prep_fn)(params, shared)))
-            .expect("Serialization of shared to thing should work")
-    }
-
-    fn exec(&self, input: NodeValue) -> NodeValue {
-        if let Some(array) = input.as_array() {
-            if array.len() != 2 {
-                panic!("Well shit");
-            }
-            // Ok, we covered our bases now
-            let mut shared: HashMap<String, NodeValue> =
-                serde_json::from_value(array[0].clone()).unwrap_or_default();
-            let params_array: Vec<HashMap<String, NodeValue>> =
-                serde_json::from_value(array[1].clone()).unwrap_or_default();
-            params_array.into_iter().for_each(|params| {
-                let mut combined_params: HashMap<String, NodeValue> = params.clone();
-                combined_params.extend(self.flow.data.params.clone());
-                let mut flow = self.flow.clone();
-                flow.set_params(combined_params);
-                flow.run(&mut shared);
-            });
-
-            serde_json::to_value(shared).expect("Serialization of shared dictionary should work!")
-        } else {
-            panic!("Serialization failure occured");
+    fn clone(&self) -> Self {
+        BatchFlowLogic {
+            flow: self.flow.clone(),
+            prep_fn: self.prep_fn.clone(),
         }
     }
+}
 
-    fn post(
+impl<F, S> BatchFlowLogic<F, S>
+where
+    F: Fn(&HashMap<String, NodeValue>, &S) -> NodeValue + Clone + Send + Sync + 'static,
+    S: Send + Sync + 'static,
+{
+    fn run_batch(
         &self,
-        shared: &mut HashMap<String, NodeValue>,
-        _prep_res: NodeValue,
-        exec_res: NodeValue,
-    ) -> Option<String> {
-        if let Ok(shared_post) = serde_json::from_value(exec_res) {
-            *shared = shared_post
-        } else {
-            log::error!(
-                "A deserialization error occured in BatchFlow, will proceed with non-updated shared"
+        params: &HashMap<String, NodeValue>,
+        shared: &mut S,
+    ) -> Result<Option<String>, NodeError> {
+        // Call the user-defined closure
+        let params_array: Vec<HashMap<String, NodeValue>> =
+            serde_json::from_value((self.prep_fn)(params, shared)).map_err(|e| {
+                NodeError::ExecError(format!(
+                    "BatchFlow expects prep_fn to return an array of param objects: {}",
+                    e
+                ))
+            })?;
+
+        let total = params_array.len();
+        for (index, params) in params_array.into_iter().enumerate() {
+            let mut combined_params: HashMap<String, NodeValue> = params;
+            combined_params.extend(self.flow.data.params.clone());
+            let started = Instant::now();
+            let outcome = spans::batch_item(index)
+                .in_scope(|| self.flow.run_with_params(shared, &combined_params));
+            observer::batch_item(
+                index,
+                total,
+                outcome.as_ref().map(|_| ()),
+                started.elapsed(),
             );
+            // One failing param set stops the whole batch
+            outcome.map_err(|source| NodeError::BatchError {
+                index,
+                source: Box::new(source),
+            })?;
         }
+
         // In PocketFlow they return the exec_res, but I think it's cleaner like this. If
         // you're not happy with this, you can also just implement your custom
         // BatchFlowLogic
         // (This allows basic chaining)
-        Some("default".into())
+        Ok(Some("default".into()))
     }
+}
 
-    fn clone_box(&self) -> Box<dyn NodeLogic> {
+impl<F, S> NodeLogic<S> for BatchFlowLogic<F, S>
+where
+    F: Fn(&HashMap<String, NodeValue>, &S) -> NodeValue + Clone + Send + Sync + 'static,
+    S: Send + Sync + 'static,
+{
+    fn orchestrate(
+        &self,
+        node: &NodeCore<S>,
+        params: &HashMap<String, NodeValue>,
+        shared: &mut S,
+    ) -> Option<Result<Option<String>, NodeError>> {
+        let span = spans::batch_flow(node);
+        let outcome = span.in_scope(|| self.run_batch(params, shared));
+        span.record(&outcome);
+        Some(outcome)
+    }
+
+    fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+        // `orchestrate` takes over the whole run, so this is never called by `Node::run`
+        Err(NodeError::ExecError(
+            "BatchFlows are run through orchestrate, not exec".into(),
+        ))
+    }
+
+    // The batched flow is the one holding a graph
+    fn validate(&self) -> Vec<ValidationIssue> {
+        self.flow.behaviour.validate()
+    }
+
+    fn diagram_kind(&self) -> DiagramKind {
+        DiagramKind::BatchFlow(DiagramNode::from(&self.flow).into())
+    }
+
+    fn clone_box(&self) -> Box<dyn NodeLogic<S>> {
         Box::new((*self).clone())
     }
 }
 
-impl BatchFlow {
-    pub fn new<F>(flow: Node, prep_fn: F) -> Self
+impl<S> BatchFlow<S>
+where
+    S: Send + Sync + 'static,
+{
+    pub fn new<F>(flow: Node<S>, prep_fn: F) -> Self
     where
-        F: Fn(&HashMap<String, NodeValue>, &HashMap<String, NodeValue>) -> NodeValue
-            + Clone
-            + Send
-            + Sync
-            + 'static,
+        F: Fn(&HashMap<String, NodeValue>, &S) -> NodeValue + Clone + Send + Sync + 'static,
     {
-        BatchFlow(Node::new(BatchFlowLogic { flow, prep_fn }))
+        BatchFlow(Node::new(BatchFlowLogic { flow, prep_fn }).with_name("batch flow"))
+    }
+
+    pub fn with_id(self, id: &str) -> Self {
+        BatchFlow(self.0.with_id(id))
+    }
+
+    pub fn with_name(self, name: &str) -> Self {
+        BatchFlow(self.0.with_name(name))
+    }
+
+    pub fn with_description(self, description: &str) -> Self {
+        BatchFlow(self.0.with_description(description))
+    }
+
+    pub fn diagram(&self) -> Diagram {
+        DiagramNode::from(&self.0).into()
+    }
+
+    /// The batched flow's graph in Graphviz DOT, nested flows are drawn as clusters
+    pub fn to_dot(&self) -> String {
+        self.diagram().to_dot()
+    }
+
+    /// The batched flow's graph as a Mermaid flowchart, nested flows are drawn as subgraphs
+    pub fn to_mermaid(&self) -> String {
+        self.diagram().to_mermaid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::sync_impl::flow::Flow;
+    use serde_json::json;
+
+    /// Appends its `n` param to `shared["done"]`
+    #[derive(Clone)]
+    struct Item;
+
+    impl NodeLogic for Item {
+        fn prep(
+            &self,
+            params: &HashMap<String, NodeValue>,
+            _shared: &SharedStore,
+        ) -> Result<NodeValue, NodeError> {
+            Ok(params["n"].clone())
+        }
+
+        fn post(
+            &self,
+            shared: &mut SharedStore,
+            n: NodeValue,
+            _exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            let done = shared.entry("done".into()).or_insert(json!([]));
+            done.as_array_mut().unwrap().push(n);
+            Ok(None)
+        }
+
+        fn clone_box(&self) -> Box<dyn NodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    #[test]
+    fn is_found_by_its_id_in_a_flow() {
+        let batch = BatchFlow::new(Node::new(Item), |_, _| json!([{ "n": 0 }, { "n": 1 }]))
+            .with_id("per-item")
+            .with_name("per item")
+            .with_description("Records each item");
+        let flow = Flow::new((*batch).clone()).with_id("outer");
+        let found = flow.find("per-item").expect("the batch flow has an id");
+        assert_eq!(found.name(), "per item");
+        assert_eq!(
+            found.data().description.as_deref(),
+            Some("Records each item")
+        );
+
+        let mut shared = SharedStore::new();
+        flow.run(&mut shared).unwrap();
+        assert_eq!(shared["done"], json!([0, 1]));
     }
 }
@@ -1,13 +1,18 @@
+use crate::core::diagram::DiagramKind;
+use crate::core::error::NodeError;
+use crate::core::observer;
+use crate::core::spans;
 use crate::core::sync_impl::NodeValue;
 use crate::core::sync_impl::node::{Node, NodeLogic};
 use std::collections::HashMap;
+use std::time::Instant;
 
 /// ------- BatchNode -------------------------------------------------------------
 /// This logic is fairly easy to implement since the core logic is really just about taking
 /// many items and applying the logic on all of them. But the more powerful approach here
 /// is just have BatchNode be generic over NodeLogic, this way it is composable with `Node`
 #[derive(Clone)]
-pub struct BatchLogic<L: NodeLogic> {
+pub struct BatchLogic<L> {
     logic: L,
 }
 
@@ -16,7 +21,7 @@ pub struct BatchLogic<L: NodeLogic> {
 /// `BatchLogic` is simply a conceptual struct which marks what we'd want to be batched.
 /// `BatchNode` which we define through the composition of a `Node` with a `NodeLogic` which is
 /// `Clone`-able, is simply a `Node` which applies its logic to a bunch of items (sequentially.)
-impl<L: NodeLogic> BatchLogic<L> {
+impl<L> BatchLogic<L> {
     pub fn new(logic: L) -> Self {
         BatchLogic { logic }
     }
@@ -24,45 +29,67 @@ impl<L: NodeLogic> BatchLogic<L> {
 
 /// The advent of the BatchNode
 /// Defining the logic for what is a `BatchLogic` which is a "true" `NodeLogic`.
-impl<L: NodeLogic + Clone> NodeLogic for BatchLogic<L> {
+impl<S: 'static, L: NodeLogic<S> + Clone> NodeLogic<S> for BatchLogic<L> {
     fn prep(
         &self,
         params: &HashMap<String, NodeValue>,
-        shared: &HashMap<String, NodeValue>,
-    ) -> NodeValue {
+        shared: &S,
+    ) -> Result<NodeValue, NodeError> {
         self.logic.prep(params, shared)
     }
 
-    fn exec(&self, items: NodeValue) -> NodeValue {
+    fn exec(&self, items: NodeValue) -> Result<NodeValue, NodeError> {
         // Check that input is indeed an array
         if let Some(arr) = items.as_array() {
+            // The first failing item fails the whole batch
             let results: Vec<NodeValue> = arr
                 .iter()
-                .map(|item| self.logic.exec(item.clone()))
-                .collect();
+                .enumerate()
+                .map(|(index, item)| {
+                    let started = Instant::now();
+                    let outcome =
+                        spans::batch_item(index).in_scope(|| self.logic.exec(item.clone()));
+                    observer::batch_item(
+                        index,
+                        arr.len(),
+                        outcome.as_ref().map(|_| ()),
+                        started.elapsed(),
+                    );
+                    outcome.map_err(|source| NodeError::BatchError {
+                        index,
+                        source: Box::new(source),
+                    })
+                })
+                .collect::<Result<_, _>>()?;
 
-            results.into()
+            Ok(results.into())
         } else {
             log::error!("items is not an array");
-            NodeValue::Null
+            Err(NodeError::ExecError(
+                "BatchLogic expects prep to return an array of items".into(),
+            ))
         }
     }
 
     fn post(
         &self,
-        shared: &mut HashMap<String, NodeValue>,
+        shared: &mut S,
         prep_res: NodeValue,
         exec_res: NodeValue,
-    ) -> Option<String> {
+    ) -> Result<Option<String>, NodeError> {
         self.logic.post(shared, prep_res, exec_res)
     }
 
-    fn clone_box(&self) -> Box<dyn NodeLogic> {
+    fn diagram_kind(&self) -> DiagramKind {
+        DiagramKind::Batch
+    }
+
+    fn clone_box(&self) -> Box<dyn NodeLogic<S>> {
         Box::new((*self).clone())
     }
 }
 
 /// The `BatchNode` factory
-pub fn new_batch_node<L: NodeLogic + Clone>(logic: L) -> Node {
+pub fn new_batch_node<S: 'static, L: NodeLogic<S> + Clone>(logic: L) -> Node<S> {
     Node::new(BatchLogic { logic })
 }
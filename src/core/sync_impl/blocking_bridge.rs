@@ -0,0 +1,190 @@
+use crate::core::error::NodeError;
+use crate::core::observer;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use tokio::runtime::{Builder, Handle, Runtime};
+
+/// How a `Flow` runs the async nodes (and async flows) of its graph: by blocking its thread on
+/// them, see `Flow::with_blocking_bridge`.
+/// Blocking on a runtime panics on the threads driving async tasks, so a flow with a bridge must
+/// be run from plain threads, or as a synchronous node of an `AsyncFlow`, which runs those outside
+/// of its runtime.
+#[derive(Clone)]
+pub struct BlockingBridge {
+    runtime: BridgeRuntime,
+}
+
+#[derive(Clone)]
+enum BridgeRuntime {
+    /// Built the first time an async node is reached, then shared by the bridge's clones
+    Own(Arc<OnceLock<Runtime>>),
+    Provided(Handle),
+}
+
+impl BlockingBridge {
+    /// Runs async nodes on a current-thread runtime of its own
+    pub fn own_runtime() -> Self {
+        BlockingBridge {
+            runtime: BridgeRuntime::Own(Arc::new(OnceLock::new())),
+        }
+    }
+
+    /// Runs async nodes on the runtime of `handle`, whose timers and I/O they can then share
+    /// with the rest of the program
+    pub fn with_handle(handle: Handle) -> Self {
+        BlockingBridge {
+            runtime: BridgeRuntime::Provided(handle),
+        }
+    }
+
+    /// Blocks until `fut` completes
+    pub(crate) fn block_on<F: Future>(&self, fut: F) -> Result<F::Output, NodeError> {
+        // The observers of the thread are the async nodes' task-local ones while they run
+        observer::handed_to_task(|observers| {
+            let fut = observer::scoped(observers, fut);
+            match &self.runtime {
+                BridgeRuntime::Own(runtime) => Ok(Self::own(runtime)?.block_on(fut)),
+                BridgeRuntime::Provided(handle) => Ok(handle.block_on(fut)),
+            }
+        })
+    }
+
+    fn own(runtime: &OnceLock<Runtime>) -> Result<&Runtime, NodeError> {
+        if let Some(runtime) = runtime.get() {
+            return Ok(runtime);
+        }
+        let built = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|error| {
+                NodeError::ExecError(format!(
+                    "Failed to build the runtime of the blocking bridge: {}",
+                    error
+                ))
+            })?;
+        // Another thread may have built one in the meantime, its runtime is kept
+        Ok(runtime.get_or_init(|| built))
+    }
+}
+
+impl Default for BlockingBridge {
+    fn default() -> Self {
+        Self::own_runtime()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::Executable;
+    use crate::core::async_impl::async_node::{AsyncNode, AsyncNodeLogic};
+    use crate::core::sync_impl::flow::Flow;
+    use crate::core::sync_impl::node::{Node, NodeLogic};
+    use crate::core::sync_impl::{NodeValue, SharedStore};
+    use async_trait::async_trait;
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn append(shared: &mut SharedStore, id: &str) {
+        let log = shared.entry("log".into()).or_insert(json!([]));
+        log.as_array_mut().unwrap().push(json!(id));
+    }
+
+    /// Appends its id to `shared["log"]`
+    #[derive(Clone)]
+    struct Step(&'static str);
+
+    impl NodeLogic for Step {
+        fn post(
+            &self,
+            shared: &mut SharedStore,
+            _prep_res: NodeValue,
+            _exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            append(shared, self.0);
+            Ok(None)
+        }
+
+        fn clone_box(&self) -> Box<dyn NodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    /// Waits on a tokio timer (which needs a runtime), then appends its id to `shared["log"]`
+    #[derive(Clone)]
+    struct Sleepy(&'static str);
+
+    #[async_trait]
+    impl AsyncNodeLogic for Sleepy {
+        async fn prep(
+            &self,
+            _params: &HashMap<String, NodeValue>,
+            _shared: &SharedStore,
+        ) -> Result<NodeValue, NodeError> {
+            Ok(NodeValue::Null)
+        }
+
+        async fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            Ok(NodeValue::Null)
+        }
+
+        async fn post(
+            &self,
+            shared: &mut SharedStore,
+            _prep_res: NodeValue,
+            _exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            append(shared, self.0);
+            Ok(None)
+        }
+
+        fn clone_box(&self) -> Box<dyn AsyncNodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    /// `a` (sync) -> `b` (async) -> `c` (sync)
+    fn mixed() -> Flow {
+        let c = Executable::Sync(Node::new(Step("c")));
+        let b = Executable::Async(AsyncNode::new(Sleepy("b")).next(c));
+        Flow::new(Node::new(Step("a")).next(b))
+    }
+
+    #[test]
+    fn async_nodes_run_on_the_bridge_own_runtime() {
+        let flow = mixed().with_blocking_bridge(BlockingBridge::own_runtime());
+        assert!(flow.validate().is_ok());
+        let mut shared = SharedStore::new();
+        assert_eq!(flow.run(&mut shared).unwrap(), Some("default".into()));
+        assert_eq!(shared["log"], json!(["a", "b", "c"]));
+
+        // The runtime is kept from one run to the next
+        let mut shared = SharedStore::new();
+        flow.run(&mut shared).unwrap();
+        assert_eq!(shared["log"], json!(["a", "b", "c"]));
+    }
+
+    #[test]
+    fn async_nodes_run_on_a_provided_runtime() {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_time()
+            .build()
+            .unwrap();
+        let flow =
+            mixed().with_blocking_bridge(BlockingBridge::with_handle(runtime.handle().clone()));
+        let mut shared = SharedStore::new();
+        flow.run(&mut shared).unwrap();
+        assert_eq!(shared["log"], json!(["a", "b", "c"]));
+    }
+
+    #[test]
+    fn async_nodes_need_a_bridge() {
+        assert!(matches!(
+            mixed().validate(),
+            Err(NodeError::InvalidGraph(_))
+        ));
+    }
+}
@@ -1,46 +1,254 @@
 use crate::core::Executable;
-use crate::core::sync_impl::NodeValue;
-use crate::core::sync_impl::node::{Node, NodeLogic};
+use crate::core::checkpoint::{self, CheckpointStore, Checkpointer, Position};
+use crate::core::diagram::{Diagram, DiagramKind, DiagramNode};
+use crate::core::error::NodeError;
+use crate::core::graph::{Graph, ValidationIssue};
+use crate::core::loop_guard::{LoopGuard, MAX_STEPS_EXCEEDED_ACTION};
+use crate::core::observer::{self, FlowEvent, FlowObserver, Observers};
+use crate::core::report::{Recorder, RunReport};
+use crate::core::spans;
+use crate::core::sync_impl::blocking_bridge::BlockingBridge;
+use crate::core::sync_impl::node::{Node, NodeCore, NodeLogic};
+use crate::core::sync_impl::{NodeValue, SharedStore};
+use crate::core::unknown_action::UnknownActionPolicy;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
 
 /// The logic that is specif
-#[derive(Clone)]
-pub struct FlowLogic {
-    start: Node,
+pub struct FlowLogic<S = SharedStore> {
+    graph: Arc<Graph<S>>,
+    loop_guard: LoopGuard,
+    unknown_action: UnknownActionPolicy,
+    observers: Vec<Arc<dyn FlowObserver>>,
+    checkpoints: Option<Checkpointer<S>>,
+    bridge: Option<BlockingBridge>,
+}
+
+// Cloning a flow only clones the `Arc`, the graph itself is shared
+impl<S> Clone for FlowLogic<S> {
+    fn clone(&self) -> Self {
+        FlowLogic {
+            graph: Arc::clone(&self.graph),
+            loop_guard: self.loop_guard.clone(),
+            unknown_action: self.unknown_action.clone(),
+            observers: self.observers.clone(),
+            checkpoints: self.checkpoints.clone(),
+            bridge: self.bridge.clone(),
+        }
+    }
 }
 
 /// A flow really, just is a Node with orchestration logic
 /// to enforce that, we will create a NewType with a "factory" which prebuilds it.
-#[derive(Clone)]
-pub struct Flow(Node);
+/// The nodes work on the caller's shared state directly: when a node fails, the changes made by
+/// the nodes before it are kept.
+pub struct Flow<S = SharedStore>(Node<S>);
+
+impl<S: 'static> Clone for Flow<S> {
+    fn clone(&self) -> Self {
+        Flow(self.0.clone())
+    }
+}
+
+impl<S> From<Flow<S>> for Executable<S> {
+    fn from(flow: Flow<S>) -> Self {
+        Executable::Sync(flow.0)
+    }
+}
 
 /// The Derefs are needed to be able to access the inside `Node` of the `Flow` easily
-impl std::ops::Deref for Flow {
-    type Target = Node;
+impl<S> std::ops::Deref for Flow<S> {
+    type Target = Node<S>;
 
     fn deref(&self) -> &Self::Target {
         &self.0
     }
 }
 
-impl std::ops::DerefMut for Flow {
+impl<S> std::ops::DerefMut for Flow<S> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.0
     }
 }
 
-impl Flow {
-    pub fn new(start: Node) -> Flow {
-        Flow(Node::new(FlowLogic { start }))
+impl<S> Flow<S>
+where
+    S: Send + Sync + 'static,
+{
+    /// Builds the flow's graph from `start` and the successors chained to it
+    pub fn new(start: impl Into<Executable<S>>) -> Flow<S> {
+        Self::from_graph(Graph::from_start(start.into()))
+    }
+
+    /// Runs a graph built with a `GraphBuilder`, which can contain cycles.
+    /// Its nodes must all be synchronous, unless the flow has a blocking bridge (see
+    /// `with_blocking_bridge`), use `AsyncFlow` otherwise.
+    pub fn from_graph(graph: Arc<Graph<S>>) -> Flow<S> {
+        Flow(
+            Node::new(FlowLogic {
+                graph,
+                loop_guard: LoopGuard::default(),
+                unknown_action: UnknownActionPolicy::default(),
+                observers: Vec::new(),
+                checkpoints: None,
+                bridge: None,
+            })
+            .with_name("flow"),
+        )
+    }
+
+    pub fn with_id(self, id: &str) -> Self {
+        Flow(self.0.with_id(id))
+    }
+
+    pub fn with_name(self, name: &str) -> Self {
+        Flow(self.0.with_name(name))
+    }
+
+    pub fn with_description(self, description: &str) -> Self {
+        Flow(self.0.with_description(description))
+    }
+
+    /// Stops runs which loop for too long, see `LoopGuard`
+    pub fn with_loop_guard(mut self, loop_guard: LoopGuard) -> Self {
+        let behaviour: &mut dyn NodeLogic<S> = &mut *self.behaviour;
+
+        if let Some(flow_logic) = behaviour.as_any_mut().downcast_mut::<FlowLogic<S>>() {
+            flow_logic.loop_guard = loop_guard;
+        } else {
+            panic!("Error: Flow's logic is not of type FlowLogic");
+        }
+        self
+    }
+
+    /// What the flow does when a node returns an action none of its edges match, see
+    /// `UnknownActionPolicy`
+    pub fn with_unknown_action(mut self, policy: UnknownActionPolicy) -> Self {
+        let behaviour: &mut dyn NodeLogic<S> = &mut *self.behaviour;
+
+        if let Some(flow_logic) = behaviour.as_any_mut().downcast_mut::<FlowLogic<S>>() {
+            flow_logic.unknown_action = policy;
+        } else {
+            panic!("Error: Flow's logic is not of type FlowLogic");
+        }
+        self
+    }
+
+    /// Reports the flow's events (the ones of nested flows included) to `observer`
+    pub fn with_observer<O: FlowObserver + 'static>(mut self, observer: O) -> Self {
+        let behaviour: &mut dyn NodeLogic<S> = &mut *self.behaviour;
+
+        if let Some(flow_logic) = behaviour.as_any_mut().downcast_mut::<FlowLogic<S>>() {
+            flow_logic.observers.push(Arc::new(observer));
+        } else {
+            panic!("Error: Flow's logic is not of type FlowLogic");
+        }
+        self
+    }
+
+    /// Runs the async nodes (and async flows) of the graph by blocking on them through `bridge`,
+    /// instead of panicking when they are reached
+    pub fn with_blocking_bridge(mut self, bridge: BlockingBridge) -> Self {
+        let behaviour: &mut dyn NodeLogic<S> = &mut *self.behaviour;
+
+        if let Some(flow_logic) = behaviour.as_any_mut().downcast_mut::<FlowLogic<S>>() {
+            flow_logic.bridge = Some(bridge);
+        } else {
+            panic!("Error: Flow's logic is not of type FlowLogic");
+        }
+        self
+    }
+
+    /// Saves a checkpoint (the last node completed, the params and the shared state) to `store`
+    /// after each node, so an interrupted run can be picked up with `resume`. Checkpoints are
+    /// kept by flow id and cleared once the flow reaches its end. The flow and its nodes must be
+    /// given ids (`with_id`), runs fail with a `CheckpointError` otherwise.
+    pub fn with_checkpoints<C: CheckpointStore + 'static>(mut self, store: C) -> Self
+    where
+        S: Serialize + DeserializeOwned,
+    {
+        let behaviour: &mut dyn NodeLogic<S> = &mut *self.behaviour;
+
+        if let Some(flow_logic) = behaviour.as_any_mut().downcast_mut::<FlowLogic<S>>() {
+            flow_logic.checkpoints = Some(Checkpointer::new(store));
+        } else {
+            panic!("Error: Flow's logic is not of type FlowLogic");
+        }
+        self
+    }
+
+    /// Continues the run saved in the flow's last checkpoint from the node after the one it was
+    /// saved for, with the params and shared state it holds (which replaces `shared`).
+    /// Without a checkpoint, this is `run`. Nested flows run again from their start.
+    pub fn resume(&self, shared: &mut S) -> Result<Option<String>, NodeError> {
+        match self.behaviour.as_any().downcast_ref::<FlowLogic<S>>() {
+            Some(flow_logic) => flow_logic.resume(&self.data, shared),
+            None => panic!("Error: Flow's logic is not of type FlowLogic"),
+        }
+    }
+
+    /// Runs the flow like `run`, and reports the nodes it visited, the actions they took,
+    /// their durations, retries and errors
+    pub fn run_with_report(&self, shared: &mut S) -> RunReport {
+        let recorder = Arc::new(Recorder::default());
+        let recording: Arc<dyn FlowObserver> = recorder.clone();
+        let observers = Observers::join(observer::current(), &[recording]);
+        let started = Instant::now();
+        let outcome = observer::scoped_sync(observers, || self.run(shared));
+        recorder.report(&self.data.id, &outcome, started.elapsed())
+    }
+
+    /// Checks the flow's graph (and the ones of nested flows) before running it: async nodes
+    /// (which `Flow` can't run without a blocking bridge), unreachable nodes, and declared
+    /// actions without a successor (or successors for actions that were not declared), and a
+    /// fallback node for unknown actions which is not part of the graph.
+    pub fn validate(&self) -> Result<(), NodeError> {
+        let issues = self.behaviour.validate();
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(NodeError::InvalidGraph(issues))
+        }
+    }
+
+    /// The graph the flow runs
+    pub fn graph(&self) -> &Arc<Graph<S>> {
+        match self.behaviour.as_any().downcast_ref::<FlowLogic<S>>() {
+            Some(flow_logic) => &flow_logic.graph,
+            None => panic!("Error: Flow's logic is not of type FlowLogic"),
+        }
+    }
+
+    /// The node of the flow's graph with the given id (nested flows are not searched)
+    pub fn find(&self, id: &str) -> Option<&Executable<S>> {
+        let graph = self.graph();
+        graph.find(id).map(|handle| graph.node(handle))
     }
 
-    pub fn start(&mut self, start: Node) {
+    pub fn diagram(&self) -> Diagram {
+        DiagramNode::from(&self.0).into()
+    }
+
+    /// The flow's graph in Graphviz DOT, nested flows are drawn as clusters
+    pub fn to_dot(&self) -> String {
+        self.diagram().to_dot()
+    }
+
+    /// The flow's graph as a Mermaid flowchart, nested flows are drawn as subgraphs
+    pub fn to_mermaid(&self) -> String {
+        self.diagram().to_mermaid()
+    }
+
+    pub fn start(&mut self, start: impl Into<Executable<S>>) {
         // extract the `NodeLogic` from the Flow
-        let behaviour: &mut dyn NodeLogic = &mut *self.behaviour;
+        let behaviour: &mut dyn NodeLogic<S> = &mut *self.behaviour;
 
-        if let Some(flow_logic) = behaviour.as_any_mut().downcast_mut::<FlowLogic>() {
+        if let Some(flow_logic) = behaviour.as_any_mut().downcast_mut::<FlowLogic<S>>() {
             // Should always be possible if the Flow as created through the factory
-            flow_logic.start = start;
+            flow_logic.graph = Graph::from_start(start.into());
         } else {
             // This should never happen, but somehow it did
             panic!("Error: Flow's logic is not of type FlowLogic");
@@ -48,89 +256,258 @@ impl Flow {
     }
 }
 
-impl NodeLogic for FlowLogic {
-    fn prep(
+impl<S> FlowLogic<S>
+where
+    S: Send + Sync + 'static,
+{
+    fn resume(&self, flow: &NodeCore<S>, shared: &mut S) -> Result<Option<String>, NodeError> {
+        let Some(checkpoints) = &self.checkpoints else {
+            return Err(NodeError::CheckpointError(format!(
+                "Flow {} has no checkpoint store to resume from, see `with_checkpoints`",
+                flow
+            )));
+        };
+        checkpoint::require_stable_ids(flow, &self.graph)?;
+        match checkpoints.load(&flow.id)? {
+            Some((checkpoint, state)) => {
+                log::info!(
+                    "Resuming flow {} after node \"{}\" (step {}).",
+                    flow,
+                    checkpoint.node,
+                    checkpoint.step
+                );
+                *shared = state;
+                let position =
+                    Position::after(&self.graph, &checkpoint, shared, &self.unknown_action)?;
+                self.run_flow(flow, &checkpoint.params, shared, position)
+            }
+            None => self.run_flow(flow, &flow.params, shared, Position::start(&self.graph)),
+        }
+    }
+
+    /// Runs the flow's nodes from `position` for its observers, `flow` being the node running it
+    fn run_flow(
         &self,
+        flow: &NodeCore<S>,
         params: &HashMap<String, NodeValue>,
-        shared: &HashMap<String, NodeValue>,
-    ) -> NodeValue {
-        serde_json::to_value((params, shared)).expect("If this works, I'll be so lit")
-    }
-
-    fn exec(&self, input: NodeValue) -> NodeValue {
-        //  This is the init (Basically, we deserialize the value that was passed from the previous
-        //  step)
-        let (params, mut shared): (HashMap<String, NodeValue>, HashMap<String, NodeValue>) =
-            if let Some(arr) = input.as_array() {
-                if arr.len() != 2 {
-                    log::error!("serde_json::to_value() failed to convert the params and shared.");
-                    (HashMap::new(), HashMap::new())
-                } else {
-                    // We can proceed
-                    let params = serde_json::from_value(arr[0].clone()).unwrap_or_default();
-                    let shared = serde_json::from_value(arr[1].clone()).unwrap_or_default();
-                    (params, shared)
-                }
-            } else {
-                (HashMap::new(), HashMap::new())
+        shared: &mut S,
+        position: Position,
+    ) -> Result<Option<String>, NodeError> {
+        let observers = Observers::join(observer::current(), &self.observers);
+        let info = flow.info();
+        let span = spans::flow(flow);
+
+        let outcome = observer::scoped_sync(observers.clone(), || {
+            observer::emit(observers.as_ref(), || FlowEvent::FlowStart {
+                flow: info,
+                params,
+            });
+            let started = Instant::now();
+            let outcome = span.in_scope(|| self.run_nodes(flow, params, shared, position));
+            observer::emit(observers.as_ref(), || FlowEvent::FlowEnd {
+                flow: info,
+                outcome: outcome.as_ref().map(Option::as_deref),
+                duration: started.elapsed(),
+            });
+            outcome
+        });
+        span.record(&outcome);
+        outcome
+    }
+
+    /// The orchestration logic
+    fn run_nodes(
+        &self,
+        flow: &NodeCore<S>,
+        params: &HashMap<String, NodeValue>,
+        shared: &mut S,
+        position: Position,
+    ) -> Result<Option<String>, NodeError> {
+        let observers = observer::current();
+        let graph = &self.graph;
+        let Position {
+            next: mut current,
+            mut step,
+            mut last_action,
+        } = position;
+        let mut tracker = self.loop_guard.track();
+        if self.checkpoints.is_some() {
+            checkpoint::require_stable_ids(flow, graph)?;
+        }
+
+        while let Some(handle) = current {
+            if let Some(reason) = tracker.exceeded(step) {
+                log::warn!("Flow stopped before step {}: {}.", step, reason);
+                return Ok(Some(MAX_STEPS_EXCEEDED_ACTION.to_string()));
+            }
+            let node = graph.node(handle).data();
+            // The flow's params replace the node's own
+            let started = Instant::now();
+            let outcome = match graph.node(handle) {
+                Executable::Sync(node) => node.run_with_params(shared, params),
+                Executable::Async(async_node) => match &self.bridge {
+                    Some(bridge) => bridge
+                        .block_on(async_node.run_with_params(shared, params))
+                        .and_then(|outcome| outcome),
+                    None => panic!(
+                        "Flow cannot handle AsyncNode {} without a blocking bridge (see `Flow::with_blocking_bridge`), or use AsyncFlow.",
+                        node
+                    ),
+                },
             };
-        let mut current: Option<Node> = Some(self.start.clone());
-        let mut last_action: String = "".into();
-
-        // This is the orchestration logic
-        while let Some(mut curr) = current {
-            curr.set_params(params.clone());
-            last_action = curr.run(&mut shared).unwrap_or("default".into());
-            let next_executable = curr.data.successors.get(&last_action).cloned();
-
-            match next_executable {
-                Some(Executable::Sync(sync_node)) => current = Some(sync_node),
-                Some(Executable::Async(_)) => {
-                    panic!(
-                        "Flow cannot handle AsyncNode, if you require to use regular Nodes with AsyncNodes, please use AsyncNode."
-                    );
-                }
-                None => {
-                    current = None;
-                }
+            observer::emit(observers.as_ref(), || FlowEvent::Step {
+                flow: flow.info(),
+                node: node.info(),
+                step,
+                outcome: outcome.as_ref().map(Option::as_deref),
+                duration: started.elapsed(),
+            });
+            // A failing node stops the flow, the error records where it happened
+            let action = outcome.map_err(|source| NodeError::FlowError {
+                node: node.id.clone(),
+                step,
+                action: last_action.clone(),
+                source: Box::new(source),
+            })?;
+            last_action = action.unwrap_or("default".into());
+            tracker.record(handle, &last_action);
+            step += 1;
+            current = self
+                .unknown_action
+                .route(graph, handle, &last_action, shared)?;
+            if let Some(checkpoints) = &self.checkpoints {
+                checkpoints.save(&flow.id, &node.id, &last_action, step, params, shared)?;
             }
         }
-        serde_json::to_value((last_action.to_string(), shared))
-            .expect("Serializing string and HashMap should be doable")
+        if let Some(checkpoints) = &self.checkpoints {
+            checkpoints.clear(&flow.id)?;
+        }
+        // return the final action (since Flow is also just a node)
+        Ok(Some(last_action))
     }
-    fn post(
+}
+
+impl<S> NodeLogic<S> for FlowLogic<S>
+where
+    S: Send + Sync + 'static,
+{
+    fn orchestrate(
         &self,
-        shared: &mut HashMap<String, NodeValue>,
-        _prep_res: NodeValue,
-        exec_res: NodeValue,
-    ) -> Option<String> {
-        let (last_action, shared_post): (String, HashMap<String, NodeValue>) = if let Some(array) =
-            exec_res.as_array()
-        {
-            if array.len() != 2 {
-                log::error!(
-                    "Serialization into array succeeded, but got unexpected length for array: {}! Returning default values",
-                    array.len()
-                );
-                ("default".into(), shared.clone())
-            } else {
-                // happy path
-                let last_action = serde_json::from_value(array[0].clone()).unwrap_or_default();
-                let shared = serde_json::from_value(array[1].clone()).unwrap_or_default();
-                (last_action, shared)
-            }
-        } else {
-            log::error!("Serialization of last action and shared failed! Returning default values");
-            ("default".into(), shared.clone())
-        };
+        node: &NodeCore<S>,
+        params: &HashMap<String, NodeValue>,
+        shared: &mut S,
+    ) -> Option<Result<Option<String>, NodeError>> {
+        let position = Position::start(&self.graph);
+        Some(self.run_flow(node, params, shared, position))
+    }
 
-        // modify the shared state
-        *shared = shared_post;
+    fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+        // `orchestrate` takes over the whole run, so this is never called by `Node::run`
+        Err(NodeError::ExecError(
+            "Flows are run through orchestrate, not exec".into(),
+        ))
+    }
 
-        // return the final action (since Flow is also just a node)
-        Some(last_action)
+    fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = self.graph.validate(self.bridge.is_none());
+        issues.extend(self.unknown_action.validate(&self.graph));
+        issues
+    }
+
+    fn diagram_kind(&self) -> DiagramKind {
+        DiagramKind::Flow(self.graph.diagram())
     }
-    fn clone_box(&self) -> Box<dyn NodeLogic> {
+
+    fn clone_box(&self) -> Box<dyn NodeLogic<S>> {
         Box::new((*self).clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns `None` (the `"default"` action), or fails in `exec` if `fails`
+    #[derive(Clone)]
+    struct Step {
+        fails: bool,
+    }
+
+    impl NodeLogic for Step {
+        fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+            if self.fails {
+                return Err(NodeError::ExecError("down".into()));
+            }
+            Ok(NodeValue::Null)
+        }
+
+        fn clone_box(&self) -> Box<dyn NodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    fn step(id: &str, fails: bool) -> Node {
+        Node::new(Step { fails }).with_id(id)
+    }
+
+    #[test]
+    fn a_nested_failure_records_where_it_happened() {
+        let inner = Flow::new(step("fetch", false).next(Executable::Sync(step("parse", true))))
+            .with_id("load");
+        let outer = Flow::new(step("start", false).next(Executable::Sync((*inner).clone())));
+        let error = outer.run(&mut HashMap::new()).unwrap_err();
+
+        let NodeError::FlowError {
+            node, step, source, ..
+        } = &error
+        else {
+            panic!("expected a flow error, got {:?}", error);
+        };
+        assert_eq!((node.as_str(), *step), ("load", 1));
+        assert!(matches!(
+            source.as_ref(),
+            NodeError::FlowError { node, step: 1, source, .. }
+                if node == "parse" && matches!(source.as_ref(), NodeError::ExecError(_))
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Node \"load\" at step 1 (reached through action \"default\") failed: \
+             Node \"parse\" at step 1 (reached through action \"default\") failed: \
+             Error occurred during exec: down"
+        );
+    }
+
+    #[test]
+    fn nodes_share_a_typed_state() {
+        #[derive(Clone, Default)]
+        struct Counter {
+            count: usize,
+        }
+
+        #[derive(Clone)]
+        struct Increment;
+
+        impl NodeLogic<Counter> for Increment {
+            fn post(
+                &self,
+                counter: &mut Counter,
+                _prep_res: NodeValue,
+                _exec_res: NodeValue,
+            ) -> Result<Option<String>, NodeError> {
+                counter.count += 1;
+                Ok(None)
+            }
+
+            fn clone_box(&self) -> Box<dyn NodeLogic<Counter>> {
+                Box::new((*self).clone())
+            }
+        }
+
+        let flow = Flow::new(Node::new(Increment).next(Executable::Sync(
+            Node::new(Increment).next(Executable::Sync(Node::new(Increment))),
+        )));
+        let mut counter = Counter::default();
+        flow.run(&mut counter).unwrap();
+        assert_eq!(counter.count, 3);
+    }
+}
@@ -0,0 +1,139 @@
+use crate::core::error::NodeError;
+use crate::core::sync_impl::node::{Node, NodeLogic};
+use crate::core::sync_impl::{NodeValue, SharedStore};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+type PrepFn<S> =
+    dyn Fn(&HashMap<String, NodeValue>, &S) -> Result<NodeValue, NodeError> + Send + Sync;
+type ExecFn = dyn Fn(NodeValue) -> Result<NodeValue, NodeError> + Send + Sync;
+type PostFn<S> =
+    dyn Fn(&mut S, NodeValue, NodeValue) -> Result<Option<String>, NodeError> + Send + Sync;
+
+/// ------- FnLogic -------------------------------------------------------------
+/// A `NodeLogic` made of closures, for the steps which don't deserve their own struct.
+/// The phases which are not set keep the default behaviour of `NodeLogic`.
+/// Like any logic, it can be batched: `new_batch_node(FnLogic::new().exec(...))`.
+pub struct FnLogic<S = SharedStore> {
+    prep: Option<Arc<PrepFn<S>>>,
+    exec: Option<Arc<ExecFn>>,
+    post: Option<Arc<PostFn<S>>>,
+}
+
+// The closures are shared between clones
+impl<S> Clone for FnLogic<S> {
+    fn clone(&self) -> Self {
+        FnLogic {
+            prep: self.prep.clone(),
+            exec: self.exec.clone(),
+            post: self.post.clone(),
+        }
+    }
+}
+
+impl<S> Default for FnLogic<S> {
+    fn default() -> Self {
+        FnLogic {
+            prep: None,
+            exec: None,
+            post: None,
+        }
+    }
+}
+
+impl<S: 'static> FnLogic<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn prep<F>(mut self, prep: F) -> Self
+    where
+        F: Fn(&HashMap<String, NodeValue>, &S) -> Result<NodeValue, NodeError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.prep = Some(Arc::new(prep));
+        self
+    }
+
+    pub fn exec<F>(mut self, exec: F) -> Self
+    where
+        F: Fn(NodeValue) -> Result<NodeValue, NodeError> + Send + Sync + 'static,
+    {
+        self.exec = Some(Arc::new(exec));
+        self
+    }
+
+    pub fn post<F>(mut self, post: F) -> Self
+    where
+        F: Fn(&mut S, NodeValue, NodeValue) -> Result<Option<String>, NodeError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.post = Some(Arc::new(post));
+        self
+    }
+
+    /// The node running this logic
+    pub fn build(self) -> Node<S> {
+        Node::new(self)
+    }
+}
+
+impl<S: 'static> NodeLogic<S> for FnLogic<S> {
+    fn prep(
+        &self,
+        params: &HashMap<String, NodeValue>,
+        shared: &S,
+    ) -> Result<NodeValue, NodeError> {
+        match &self.prep {
+            Some(prep) => prep(params, shared),
+            None => Ok(NodeValue::default()),
+        }
+    }
+
+    fn exec(&self, input: NodeValue) -> Result<NodeValue, NodeError> {
+        match &self.exec {
+            Some(exec) => exec(input),
+            None => Ok(NodeValue::default()),
+        }
+    }
+
+    fn post(
+        &self,
+        shared: &mut S,
+        prep_res: NodeValue,
+        exec_res: NodeValue,
+    ) -> Result<Option<String>, NodeError> {
+        match &self.post {
+            Some(post) => post(shared, prep_res, exec_res),
+            None => Ok(None),
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn NodeLogic<S>> {
+        Box::new((*self).clone())
+    }
+}
+
+impl<S: 'static> Node<S> {
+    /// A node running the given closures, see `FnLogic` to only set some of them.
+    /// Annotating the shared state (`|shared: &mut SharedStore, _, exec_res|`) tells which one
+    /// the node works on.
+    pub fn from_fns<P, E, Q>(prep: P, exec: E, post: Q) -> Node<S>
+    where
+        P: Fn(&HashMap<String, NodeValue>, &S) -> Result<NodeValue, NodeError>
+            + Send
+            + Sync
+            + 'static,
+        E: Fn(NodeValue) -> Result<NodeValue, NodeError> + Send + Sync + 'static,
+        Q: Fn(&mut S, NodeValue, NodeValue) -> Result<Option<String>, NodeError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        FnLogic::new().prep(prep).exec(exec).post(post).build()
+    }
+}
@@ -1,12 +1,20 @@
 pub mod batch_flow;
 pub mod batch_node;
+pub mod blocking_bridge;
 pub mod flow;
+pub mod fn_node;
 pub mod node;
 
 /// The Alias for serde_json::Value since I use it a lot
 pub type NodeValue = serde_json::Value;
 
+/// The default shared state of nodes and flows, a map of JSON values.
+/// Nodes, flows and batches are generic over their shared state `S` (defaulting to this map),
+/// so any struct can be used instead to get compile-time checked fields.
+pub type SharedStore = HashMap<String, NodeValue>;
+
 use std::any::Any;
+use std::collections::HashMap;
 
 /// A helper trait that just provides the `as_any` method.
 /// Needed for convenient downcasting of `FlowLogic` among other things
@@ -14,6 +22,8 @@ use std::any::Any;
 pub trait AsAny {
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
+    /// The name of the concrete type, used to label nodes in diagrams
+    fn type_name(&self) -> &'static str;
 }
 
 impl<T: 'static> AsAny for T {
@@ -23,4 +33,7 @@ impl<T: 'static> AsAny for T {
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<T>()
+    }
 }
@@ -1,30 +1,71 @@
-use crate::core::Executable;
+use crate::core::diagram::{DiagramKind, short_type_name};
+use crate::core::error::NodeError;
+use crate::core::graph::{ANY_ACTION, Guard, ValidationIssue};
+use crate::core::observer::{self, FlowEvent, NodeInfo, Observers};
+use crate::core::retry::RetryPolicy;
+use crate::core::spans;
 use crate::core::sync_impl::AsAny;
-use crate::core::sync_impl::NodeValue;
+use crate::core::sync_impl::{NodeValue, SharedStore};
+use crate::core::{Executable, refuse_reserved_action};
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
 
 /// ------ Base Node Logic -------------------------------------------------------
 /// Defines the fundamental logic that is common to any "Node" of the system
-#[derive(Clone)]
-pub struct Node {
-    pub data: NodeCore,
-    pub behaviour: Box<dyn NodeLogic>,
+/// `S` is the shared state the node reads in `prep` and writes in `post`.
+pub struct Node<S = SharedStore> {
+    pub data: NodeCore<S>,
+    pub behaviour: Box<dyn NodeLogic<S>>,
 }
 
-impl Node {
-    pub fn new<L: NodeLogic + 'static>(behaviour: L) -> Self {
+impl<S: 'static> Clone for Node<S> {
+    fn clone(&self) -> Self {
+        Self {
+            data: self.data.clone(),
+            behaviour: self.behaviour.clone(),
+        }
+    }
+}
+
+impl<S: 'static> Node<S> {
+    pub fn new<L: NodeLogic<S> + 'static>(behaviour: L) -> Self {
         Node {
-            data: NodeCore::default(),
+            data: NodeCore::named(short_type_name(behaviour.type_name())),
             behaviour: Box::new(behaviour),
         }
     }
+    /// Replaces the generated id (`node-<n>`), which should be unique within a flow (see
+    /// `Flow::find`). Checkpointed flows and suspended runs need ids set this way.
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.data.id = id.to_string();
+        self
+    }
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.data.name = name.to_string();
+        self
+    }
+    pub fn with_description(mut self, description: &str) -> Self {
+        self.data.description = Some(description.to_string());
+        self
+    }
     pub fn set_params(&mut self, params: HashMap<String, NodeValue>) {
         self.data.params = params;
     }
-    pub fn next(self, node: Executable) -> Self {
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.data.retry = retry;
+        self
+    }
+    /// Declares every action the node can return, so flows can check each one has a successor
+    pub fn with_actions(mut self, actions: &[&str]) -> Self {
+        self.data.actions = actions.iter().map(|action| action.to_string()).collect();
+        self
+    }
+    pub fn next(self, node: impl Into<Executable<S>>) -> Self {
         self.next_on(node, "default")
     }
-    pub fn next_on(mut self, node: Executable, action: &str) -> Self {
+    pub fn next_on(mut self, node: impl Into<Executable<S>>, action: &str) -> Self {
         if self.data.successors.contains_key(action) {
             log::warn!(
                 "Warning: Action {} was found in successors, Overwriting key {}.",
@@ -32,58 +73,474 @@ impl Node {
                 &action
             );
         }
-        self.data.successors.insert(action.to_string(), node);
+        self.data.successors.insert(action.to_string(), node.into());
+        self
+    }
+    /// The wildcard successor, reached for any action the node has no successor for
+    pub fn next_on_any(self, node: impl Into<Executable<S>>) -> Self {
+        self.next_on(node, ANY_ACTION)
+    }
+    /// Routes to `node` when `predicate` holds on the shared state once `post` ran, whatever
+    /// the action. Guards are checked in the order they were added, before the action's
+    /// successor, so routing decisions can live in the graph rather than in `post`.
+    pub fn next_when<P>(self, predicate: P, node: impl Into<Executable<S>>) -> Self
+    where
+        P: Fn(&S) -> bool + Send + Sync + 'static,
+    {
+        self.guard(None, predicate, node)
+    }
+    /// Routes to `node` when the node returned `action` and `predicate` holds
+    pub fn next_on_when<P>(self, predicate: P, node: impl Into<Executable<S>>, action: &str) -> Self
+    where
+        P: Fn(&S) -> bool + Send + Sync + 'static,
+    {
+        self.guard(Some(action.to_string()), predicate, node)
+    }
+    fn guard<P>(
+        mut self,
+        action: Option<String>,
+        predicate: P,
+        node: impl Into<Executable<S>>,
+    ) -> Self
+    where
+        P: Fn(&S) -> bool + Send + Sync + 'static,
+    {
+        self.data.guards.push(Guard {
+            action,
+            predicate: Arc::new(predicate),
+            node: node.into(),
+        });
         self
     }
 
-    pub fn run(&self, shared: &mut HashMap<String, NodeValue>) -> Option<String> {
-        let p = self.behaviour.prep(&self.data.params, shared);
-        let e = self.behaviour.exec(p.clone());
-        self.behaviour.post(shared, p, e)
+    /// Runs `prep` -> `exec` -> `post`, stopping at the first phase that fails.
+    pub fn run(&self, shared: &mut S) -> Result<Option<String>, NodeError> {
+        self.run_with_params(shared, &self.data.params)
     }
 
     pub fn run_with_params(
         &self,
-        shared: &mut HashMap<String, NodeValue>,
+        shared: &mut S,
+        param: &HashMap<String, NodeValue>,
+    ) -> Result<Option<String>, NodeError> {
+        // Inside an observed flow, the node reports its lifecycle to the flow's observers
+        let observers = observer::current().map(|observers| observers.at(&self.data));
+        observer::scoped_sync(observers.clone(), || {
+            self.lifecycle(shared, param, observers.as_ref())
+        })
+    }
+
+    fn lifecycle(
+        &self,
+        shared: &mut S,
+        param: &HashMap<String, NodeValue>,
+        observers: Option<&Observers>,
+    ) -> Result<Option<String>, NodeError> {
+        if let Some(outcome) = self.behaviour.orchestrate(&self.data, param, shared) {
+            return outcome;
+        }
+        let span = spans::node(&self.data);
+        let action = span.in_scope(|| self.run_phases(shared, param, observers));
+        span.record(&action);
+        action
+    }
+
+    fn run_phases(
+        &self,
+        shared: &mut S,
         param: &HashMap<String, NodeValue>,
-    ) -> Option<String> {
-        let p = self.behaviour.prep(param, shared);
-        let e = self.behaviour.exec(p.clone());
-        self.behaviour.post(shared, p, e)
+        observers: Option<&Observers>,
+    ) -> Result<Option<String>, NodeError> {
+        let node = self.data.info();
+
+        observer::emit(observers, || FlowEvent::PrepStart {
+            node,
+            params: param,
+        });
+        let started = Instant::now();
+        let p = spans::prep().in_scope(|| self.behaviour.prep(param, shared));
+        observer::emit(observers, || FlowEvent::PrepEnd {
+            node,
+            outcome: p.as_ref(),
+            duration: started.elapsed(),
+        });
+
+        let p = p?;
+        let e = self.exec_with_retry(&p, observers)?;
+
+        observer::emit(observers, || FlowEvent::PostStart { node, exec_res: &e });
+        let started = Instant::now();
+        let action = spans::post().in_scope(|| self.behaviour.post(shared, p, e));
+        let action = refuse_reserved_action(action);
+        observer::emit(observers, || FlowEvent::PostEnd {
+            node,
+            outcome: action.as_ref().map(Option::as_deref),
+            duration: started.elapsed(),
+        });
+        action
+    }
+
+    /// Calls `exec` according to the node's `RetryPolicy`, handing the last error to
+    /// `exec_fallback` once no attempts are left.
+    fn exec_with_retry(
+        &self,
+        prep_res: &NodeValue,
+        observers: Option<&Observers>,
+    ) -> Result<NodeValue, NodeError> {
+        let retry = &self.data.retry;
+        let node = self.data.info();
+        let mut attempt = 1;
+        loop {
+            observer::emit(observers, || FlowEvent::ExecStart {
+                node,
+                input: prep_res,
+                attempt,
+            });
+            let started = Instant::now();
+            let exec_res = spans::exec(attempt).in_scope(|| self.behaviour.exec(prep_res.clone()));
+            observer::emit(observers, || FlowEvent::ExecEnd {
+                node,
+                attempt,
+                outcome: exec_res.as_ref(),
+                duration: started.elapsed(),
+            });
+            match exec_res {
+                Ok(exec_res) => return Ok(exec_res),
+                Err(error) if retry.should_retry(attempt, &error) => {
+                    let delay = retry.delay(attempt);
+                    log::warn!(
+                        "Attempt {}/{} of exec failed for node {}: {}. Retrying in {:?}.",
+                        attempt,
+                        retry.max_attempts(),
+                        self.data,
+                        error,
+                        delay
+                    );
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(error) => return self.behaviour.exec_fallback(prep_res.clone(), error),
+            }
+        }
     }
 }
 
-#[derive(Default, Clone)]
-pub struct NodeCore {
+/// Hands out the ids of the nodes which were not given one
+static NEXT_NODE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether `id` was generated (`node-<n>`). Generated ids depend on how many nodes the process
+/// created before, so they don't identify a node from one process to the next.
+pub(crate) fn is_generated_id(id: &str) -> bool {
+    id.strip_prefix("node-")
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+pub struct NodeCore<S = SharedStore> {
+    /// Identifies the node in logs, errors and diagrams, kept by clones.
+    /// Generated (`node-<n>`) unless set with `with_id`.
+    pub id: String,
+    /// A human readable name, the type of the node's logic by default
+    pub name: String,
+    pub description: Option<String>,
     pub params: HashMap<String, NodeValue>,
-    pub successors: HashMap<String, Executable>,
+    pub successors: HashMap<String, Executable<S>>,
+    /// The successors reached when a predicate on the shared state holds, see `Node::next_when`
+    pub guards: Vec<Guard<S>>,
+    pub retry: RetryPolicy,
+    /// The actions the node declared it can return (empty if it didn't), see `validate`
+    pub actions: Vec<String>,
+}
+
+impl<S> NodeCore<S> {
+    pub fn named(name: String) -> Self {
+        NodeCore {
+            name,
+            ..Self::default()
+        }
+    }
+
+    /// How observers see the node
+    pub fn info(&self) -> NodeInfo<'_> {
+        NodeInfo {
+            id: &self.id,
+            name: &self.name,
+        }
+    }
+}
+
+/// `"name" (id)`, or just the name if it is the id
+impl<S> std::fmt::Display for NodeCore<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.name == self.id {
+            write!(f, "\"{}\"", self.id)
+        } else {
+            write!(f, "\"{}\" ({})", self.name, self.id)
+        }
+    }
 }
 
-pub trait NodeLogic: AsAny + Send + Sync + 'static {
+// Implemented by hand, deriving would require `S: Default + Clone`
+impl<S> Default for NodeCore<S> {
+    fn default() -> Self {
+        let id = format!("node-{}", NEXT_NODE_ID.fetch_add(1, Ordering::Relaxed));
+        NodeCore {
+            name: id.clone(),
+            id,
+            description: None,
+            params: HashMap::new(),
+            successors: HashMap::new(),
+            guards: Vec::new(),
+            retry: RetryPolicy::default(),
+            actions: Vec::new(),
+        }
+    }
+}
+
+impl<S: 'static> Clone for NodeCore<S> {
+    fn clone(&self) -> Self {
+        NodeCore {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            description: self.description.clone(),
+            params: self.params.clone(),
+            successors: self.successors.clone(),
+            guards: self.guards.clone(),
+            retry: self.retry.clone(),
+            actions: self.actions.clone(),
+        }
+    }
+}
+
+pub trait NodeLogic<S = SharedStore>: AsAny + Send + Sync + 'static {
     fn prep(
         &self,
         _params: &HashMap<String, NodeValue>,
-        _shared: &HashMap<String, NodeValue>,
-    ) -> NodeValue {
-        NodeValue::default()
+        _shared: &S,
+    ) -> Result<NodeValue, NodeError> {
+        Ok(NodeValue::default())
     }
-    fn exec(&self, _input: NodeValue) -> NodeValue {
-        NodeValue::default()
+    fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+        Ok(NodeValue::default())
+    }
+    /// Called with the last error once `exec` ran out of attempts.
+    /// Returning `Ok` lets the node carry on to `post` as if `exec` had succeeded.
+    fn exec_fallback(
+        &self,
+        _prep_res: NodeValue,
+        error: NodeError,
+    ) -> Result<NodeValue, NodeError> {
+        Err(error)
     }
     fn post(
         &self,
-        _shared: &mut HashMap<String, NodeValue>,
+        _shared: &mut S,
         _prep_res: NodeValue,
         _exec_res: NodeValue,
-    ) -> Option<String> {
+    ) -> Result<Option<String>, NodeError> {
+        Ok(None)
+    }
+
+    /// Flows drive their nodes on the shared state directly, in place of `prep` -> `exec` ->
+    /// `post` (which could only hand the shared state to `exec` as a `NodeValue`).
+    /// Regular nodes keep the default, `None`, which runs the lifecycle.
+    /// `node` is the node running this logic, which flows report their events as.
+    fn orchestrate(
+        &self,
+        _node: &NodeCore<S>,
+        _params: &HashMap<String, NodeValue>,
+        _shared: &mut S,
+    ) -> Option<Result<Option<String>, NodeError>> {
         None
     }
 
-    fn clone_box(&self) -> Box<dyn NodeLogic>;
+    /// The problems found in the graphs the node runs (flows override this, see `Flow::validate`)
+    fn validate(&self) -> Vec<ValidationIssue> {
+        Vec::new()
+    }
+
+    /// How the node is drawn by `to_dot`/`to_mermaid` (flows and batches override this)
+    fn diagram_kind(&self) -> DiagramKind {
+        DiagramKind::Node
+    }
+
+    fn clone_box(&self) -> Box<dyn NodeLogic<S>>;
 }
 
-impl Clone for Box<dyn NodeLogic> {
+impl<S: 'static> Clone for Box<dyn NodeLogic<S>> {
     fn clone(&self) -> Self {
         self.clone_box()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Fails in `prep`, counting the phases which ran after it
+    #[derive(Clone, Default)]
+    struct FailingPrep {
+        later_phases: Arc<AtomicUsize>,
+    }
+
+    impl NodeLogic for FailingPrep {
+        fn prep(
+            &self,
+            _params: &HashMap<String, NodeValue>,
+            _shared: &HashMap<String, NodeValue>,
+        ) -> Result<NodeValue, NodeError> {
+            Err(NodeError::PrepError("no input".into()))
+        }
+
+        fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+            self.later_phases.fetch_add(1, Ordering::SeqCst);
+            Ok(NodeValue::Null)
+        }
+
+        fn post(
+            &self,
+            _shared: &mut HashMap<String, NodeValue>,
+            _prep_res: NodeValue,
+            _exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            self.later_phases.fetch_add(1, Ordering::SeqCst);
+            Ok(None)
+        }
+
+        fn clone_box(&self) -> Box<dyn NodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    #[test]
+    fn a_failing_phase_stops_the_lifecycle() {
+        let logic = FailingPrep::default();
+        let later_phases = logic.later_phases.clone();
+        let outcome = Node::new(logic).run(&mut HashMap::new());
+        assert!(matches!(outcome, Err(NodeError::PrepError(_))));
+        assert_eq!(later_phases.load(Ordering::SeqCst), 0);
+    }
+
+    /// Fails in `exec` until its last allowed attempt, counting the attempts. Its fallback
+    /// recovers with `"fallback"` if `recovers`.
+    #[derive(Clone, Default)]
+    struct Flaky {
+        attempts: Arc<AtomicUsize>,
+        succeeds_at: usize,
+        recovers: bool,
+    }
+
+    impl NodeLogic for Flaky {
+        fn exec(&self, _input: NodeValue) -> Result<NodeValue, NodeError> {
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst) + 1;
+            if attempt < self.succeeds_at {
+                return Err(NodeError::ExecError(format!("attempt {} failed", attempt)));
+            }
+            Ok(NodeValue::from(attempt))
+        }
+
+        fn exec_fallback(
+            &self,
+            _prep_res: NodeValue,
+            error: NodeError,
+        ) -> Result<NodeValue, NodeError> {
+            if self.recovers {
+                Ok(NodeValue::from("fallback"))
+            } else {
+                Err(error)
+            }
+        }
+
+        fn post(
+            &self,
+            shared: &mut HashMap<String, NodeValue>,
+            _prep_res: NodeValue,
+            exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            shared.insert("exec_res".into(), exec_res);
+            Ok(None)
+        }
+
+        fn clone_box(&self) -> Box<dyn NodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    #[test]
+    fn exec_is_retried_until_it_succeeds() {
+        let logic = Flaky {
+            succeeds_at: 3,
+            ..Default::default()
+        };
+        let attempts = logic.attempts.clone();
+        let mut shared = HashMap::new();
+        Node::new(logic)
+            .with_retry(RetryPolicy::new(3))
+            .run(&mut shared)
+            .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(shared["exec_res"], NodeValue::from(3));
+    }
+
+    #[test]
+    fn the_fallback_has_the_last_word() {
+        let recovering = Flaky {
+            succeeds_at: usize::MAX,
+            recovers: true,
+            ..Default::default()
+        };
+        let mut shared = HashMap::new();
+        Node::new(recovering)
+            .with_retry(RetryPolicy::new(2))
+            .run(&mut shared)
+            .unwrap();
+        assert_eq!(shared["exec_res"], NodeValue::from("fallback"));
+
+        let failing = Flaky {
+            succeeds_at: usize::MAX,
+            ..Default::default()
+        };
+        let attempts = failing.attempts.clone();
+        let outcome = Node::new(failing)
+            .with_retry(RetryPolicy::new(2))
+            .run(&mut HashMap::new());
+        assert!(
+            matches!(outcome, Err(NodeError::ExecError(message)) if message == "attempt 2 failed")
+        );
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    /// Returns `action` from `post`
+    #[derive(Clone)]
+    struct Returns(&'static str);
+
+    impl NodeLogic for Returns {
+        fn post(
+            &self,
+            _shared: &mut HashMap<String, NodeValue>,
+            _prep_res: NodeValue,
+            _exec_res: NodeValue,
+        ) -> Result<Option<String>, NodeError> {
+            Ok(Some(self.0.to_string()))
+        }
+
+        fn clone_box(&self) -> Box<dyn NodeLogic> {
+            Box::new((*self).clone())
+        }
+    }
+
+    #[test]
+    fn reserved_actions_are_refused() {
+        for action in crate::core::RESERVED_ACTIONS {
+            let outcome = Node::new(Returns(action)).run(&mut HashMap::new());
+            assert!(
+                matches!(outcome, Err(NodeError::PostError(_))),
+                "{}",
+                action
+            );
+        }
+        let outcome = Node::new(Returns("done")).run(&mut HashMap::new());
+        assert_eq!(outcome.unwrap(), Some("done".into()));
+    }
+}
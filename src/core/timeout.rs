@@ -0,0 +1,120 @@
+use crate::core::error::NodeError;
+use std::future::Future;
+use std::time::Duration;
+
+/// The time limits of an `AsyncNode` (a synchronous `exec` can't be interrupted, so `Node`s have
+/// none).
+/// `exec` is limited per attempt (so a timed-out attempt can be retried), `run` limits the whole
+/// lifecycle, retries included.
+/// When a limit is hit the node fails with a `NodeError::TimeoutError`, unless an `on_timeout`
+/// action is set, in which case the node returns that action so the flow can route it.
+/// Hitting a limit drops the phase's future: the work it handed to another thread (through
+/// `spawn_blocking`...) is detached rather than stopped, and runs to its end unobserved.
+#[derive(Clone, Debug, Default)]
+pub struct TimeoutPolicy {
+    run: Option<Duration>,
+    prep: Option<Duration>,
+    exec: Option<Duration>,
+    post: Option<Duration>,
+    on_timeout: Option<String>,
+}
+
+impl TimeoutPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the whole run (prep, every exec attempt and post)
+    pub fn with_run(mut self, limit: Duration) -> Self {
+        self.run = Some(limit);
+        self
+    }
+
+    pub fn with_prep(mut self, limit: Duration) -> Self {
+        self.prep = Some(limit);
+        self
+    }
+
+    /// Limits each attempt of exec
+    pub fn with_exec(mut self, limit: Duration) -> Self {
+        self.exec = Some(limit);
+        self
+    }
+
+    pub fn with_post(mut self, limit: Duration) -> Self {
+        self.post = Some(limit);
+        self
+    }
+
+    /// Return `action` instead of failing when the node times out
+    pub fn on_timeout(mut self, action: &str) -> Self {
+        self.on_timeout = Some(action.to_string());
+        self
+    }
+
+    pub fn run(&self) -> Option<Duration> {
+        self.run
+    }
+
+    pub fn prep(&self) -> Option<Duration> {
+        self.prep
+    }
+
+    pub fn exec(&self) -> Option<Duration> {
+        self.exec
+    }
+
+    pub fn post(&self) -> Option<Duration> {
+        self.post
+    }
+
+    pub fn timeout_action(&self) -> Option<&str> {
+        self.on_timeout.as_deref()
+    }
+}
+
+/// Awaits `fut`, failing with a `NodeError::TimeoutError` if it takes longer than `limit`.
+pub(crate) async fn within<T, F>(
+    limit: Option<Duration>,
+    what: &str,
+    fut: F,
+) -> Result<T, NodeError>
+where
+    F: Future<Output = Result<T, NodeError>>,
+{
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, fut).await.map_err(|_| {
+            NodeError::TimeoutError(format!("{} did not complete within {:?}", what, limit))
+        })?,
+        None => fut.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn within_hands_back_the_outcome() {
+        let limit = Some(Duration::from_millis(200));
+        assert_eq!(within(limit, "exec", async { Ok(1) }).await.unwrap(), 1);
+        let failed = within(None, "exec", async {
+            Err::<(), _>(NodeError::ExecError("down".into()))
+        })
+        .await;
+        assert!(matches!(failed, Err(NodeError::ExecError(_))));
+    }
+
+    #[tokio::test]
+    async fn within_gives_up_at_the_limit() {
+        let outcome = within(Some(Duration::from_millis(20)), "exec", async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        })
+        .await;
+        match outcome {
+            Err(NodeError::TimeoutError(message)) => assert!(message.starts_with("exec")),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+    }
+}
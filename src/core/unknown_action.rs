@@ -0,0 +1,187 @@
+use crate::core::error::NodeError;
+use crate::core::graph::{Graph, NodeHandle, ValidationIssue};
+
+/// What a flow does when a node returns an action which none of its edges (wildcard included)
+/// match. A node without any successor is the end of the flow, whatever the policy.
+/// Unless the policy is `Error`, a warning lists the actions the node has successors for.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum UnknownActionPolicy {
+    /// Ends the run, the flow returning the unmatched action
+    #[default]
+    End,
+    /// Fails the run with `NodeError::UnknownAction`
+    Error,
+    /// Goes on at the node of the flow with this id
+    Fallback(String),
+}
+
+impl UnknownActionPolicy {
+    /// Where the flow goes once `handle` returned `action` and left `shared`
+    pub(crate) fn route<S>(
+        &self,
+        graph: &Graph<S>,
+        handle: NodeHandle,
+        action: &str,
+        shared: &S,
+    ) -> Result<Option<NodeHandle>, NodeError> {
+        if let Some(next) = graph.route(handle, action, shared) {
+            return Ok(Some(next));
+        }
+        if graph.successors(handle).is_empty() && graph.guards(handle).is_empty() {
+            return Ok(None);
+        }
+
+        let node = graph.node(handle).data();
+        let available = graph.actions(handle);
+        if *self != UnknownActionPolicy::Error {
+            log::warn!(
+                "Warning: Node {} returned action \"{}\" which has no successor, available actions: {}.",
+                node,
+                action,
+                list_actions(&available)
+            );
+        }
+        match self {
+            UnknownActionPolicy::End => Ok(None),
+            UnknownActionPolicy::Error => Err(NodeError::UnknownAction {
+                node: node.id.clone(),
+                action: action.to_string(),
+                available,
+            }),
+            UnknownActionPolicy::Fallback(id) => match graph.find(id) {
+                Some(fallback) => Ok(Some(fallback)),
+                None => Err(NodeError::InvalidGraph(vec![
+                    ValidationIssue::MissingFallback { id: id.clone() },
+                ])),
+            },
+        }
+    }
+
+    /// The fallback node must be part of the flow
+    pub(crate) fn validate<S>(&self, graph: &Graph<S>) -> Option<ValidationIssue> {
+        match self {
+            UnknownActionPolicy::Fallback(id) if graph.find(id).is_none() => {
+                Some(ValidationIssue::MissingFallback { id: id.clone() })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// `"a", "b"`, or `none`
+pub(crate) fn list_actions(actions: &[String]) -> String {
+    if actions.is_empty() {
+        return "none".to_string();
+    }
+    actions
+        .iter()
+        .map(|action| format!("\"{}\"", action))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::graph::{ANY_ACTION, GraphBuilder};
+    use crate::core::sync_impl::SharedStore;
+    use crate::core::sync_impl::fn_node::FnLogic;
+    use crate::core::sync_impl::node::Node;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn node(id: &str) -> Node {
+        FnLogic::new().build().with_id(id)
+    }
+
+    fn score(score: u64) -> SharedStore {
+        SharedStore::from([("score".to_string(), json!(score))])
+    }
+
+    /// `review` -> `fast` (on "approve" with a high score) | `publish` (on "approve") |
+    /// `triage` (on any other action, if `wildcard`), and an `oops` node off the graph's edges
+    fn review_graph(wildcard: bool) -> (Arc<Graph>, NodeHandle) {
+        let mut builder = GraphBuilder::new();
+        let review = builder.add(node("review").into());
+        let fast = builder.add(node("fast").into());
+        let publish = builder.add(node("publish").into());
+        builder.add(node("oops").into());
+        builder.connect_on_when(
+            review,
+            "approve",
+            Arc::new(|shared: &SharedStore| shared["score"].as_u64() > Some(5)),
+            fast,
+        );
+        builder.connect_on(review, "approve", publish);
+        if wildcard {
+            let triage = builder.add(node("triage").into());
+            builder.connect_on(review, ANY_ACTION, triage);
+        }
+        (builder.build(review), review)
+    }
+
+    fn routed_to(
+        policy: &UnknownActionPolicy,
+        graph: &Graph,
+        review: NodeHandle,
+        action: &str,
+        shared: &SharedStore,
+    ) -> Option<String> {
+        policy
+            .route(graph, review, action, shared)
+            .unwrap()
+            .map(|next| graph.node(next).id().to_string())
+    }
+
+    #[test]
+    fn guards_come_before_successors_and_the_wildcard_last() {
+        let (graph, review) = review_graph(true);
+        let policy = UnknownActionPolicy::Fallback("oops".into());
+        let fast = routed_to(&policy, &graph, review, "approve", &score(9));
+        assert_eq!(fast.as_deref(), Some("fast"));
+        let publish = routed_to(&policy, &graph, review, "approve", &score(1));
+        assert_eq!(publish.as_deref(), Some("publish"));
+        let triage = routed_to(&policy, &graph, review, "reject", &score(9));
+        assert_eq!(triage.as_deref(), Some("triage"));
+    }
+
+    #[test]
+    fn the_policy_handles_what_no_edge_matches() {
+        let (graph, review) = review_graph(false);
+        let shared = score(9);
+        assert_eq!(
+            routed_to(&UnknownActionPolicy::End, &graph, review, "reject", &shared),
+            None
+        );
+        let fallback = UnknownActionPolicy::Fallback("oops".into());
+        assert_eq!(
+            routed_to(&fallback, &graph, review, "reject", &shared).as_deref(),
+            Some("oops")
+        );
+        match UnknownActionPolicy::Error.route(&graph, review, "reject", &shared) {
+            Err(NodeError::UnknownAction {
+                node,
+                action,
+                available,
+            }) => {
+                assert_eq!((node.as_str(), action.as_str()), ("review", "reject"));
+                assert_eq!(available, vec!["approve".to_string()]);
+            }
+            _ => panic!("expected an unknown action error"),
+        }
+        let missing = UnknownActionPolicy::Fallback("missing".into());
+        assert!(matches!(
+            missing.route(&graph, review, "reject", &shared),
+            Err(NodeError::InvalidGraph(_))
+        ));
+        assert!(missing.validate(&graph).is_some());
+    }
+
+    #[test]
+    fn a_node_without_successors_ends_the_flow() {
+        let (graph, _) = review_graph(false);
+        let publish = graph.find("publish").unwrap();
+        let outcome = UnknownActionPolicy::Error.route(&graph, publish, "anything", &score(1));
+        assert_eq!(outcome.unwrap(), None);
+    }
+}
@@ -0,0 +1,210 @@
+use crate::core::Executable;
+use crate::core::async_impl::async_batch_flow::AsyncBatchFlow;
+use crate::core::async_impl::async_flow::AsyncFlow;
+use crate::core::async_impl::async_node::AsyncNode;
+use crate::core::async_impl::async_parallel_batch_flow::AsyncParallelBatchFlow;
+use crate::core::sync_impl::SharedStore;
+use crate::core::sync_impl::batch_flow::BatchFlow;
+use crate::core::sync_impl::flow::Flow;
+use crate::core::sync_impl::node::Node;
+use std::ops::{Shr, Sub};
+
+/// Nodes wired with the operators, so graphs read like their diagram:
+/// ```
+/// use orichalcum::core::sync_impl::flow::Flow;
+/// use orichalcum::core::sync_impl::fn_node::FnLogic;
+/// use orichalcum::core::sync_impl::node::Node;
+///
+/// let step = |id: &str| -> Node { FnLogic::new().build().with_id(id) };
+///
+/// let flow = Flow::new(step("load") >> (step("review") - "approve") >> step("publish"));
+/// assert!(flow.find("publish").is_some());
+///
+/// let checked = (step("check") - "ok") >> step("save");
+/// let flow = Flow::new((checked.branch() - "retry") >> step("fix"));
+/// assert!(flow.find("save").is_some() && flow.find("fix").is_some());
+/// ```
+/// `a >> b` makes `b` the successor of `a` for the `"default"` action, `(a - "action") >> b` for
+/// `"action"`. Each `>>` wires onto the last node of the chain, and a chain turns into its first
+/// node (an `Executable`) wherever one is expected.
+/// `branch` goes back to the first node to wire its other actions.
+/// `-` binds tighter than `>>` anyway, the parentheses around `a - "action"` are the ones
+/// Clippy's `precedence` lint asks for.
+///
+/// Like `next`/`next_on`, the operators take the nodes they wire, so a chain is a tree: a node
+/// can't be reached twice and loops (`think >> act >> think`) can't be written. Cycles are built
+/// with a `GraphBuilder` (see `Flow::from_graph`).
+pub struct Chain<S = SharedStore> {
+    start: Executable<S>,
+    // The actions leading from `start` to the last node of the chain
+    path: Vec<String>,
+    // The action written (`- "action"`) before the node which will follow it
+    pending: Option<String>,
+}
+
+impl<S> Chain<S> {
+    pub fn new(start: impl Into<Executable<S>>) -> Self {
+        Chain {
+            start: start.into(),
+            path: Vec::new(),
+            pending: None,
+        }
+    }
+
+    /// Goes back to the first node of the chain, the next `>>` wiring onto it. An action
+    /// written before `branch` is dropped, write it after (`(chain.branch() - "action") >> b`).
+    pub fn branch(mut self) -> Self {
+        if let Some(pending) = self.pending.take() {
+            log::warn!(
+                "Warning: Action {} is not followed by a node, dropping it to branch.",
+                pending
+            );
+        }
+        self.path.clear();
+        self
+    }
+
+    /// Makes `next` the successor of the last node of the chain, for the pending action
+    fn wire(mut self, next: Chain<S>) -> Self {
+        let action = self.pending.take().unwrap_or_else(|| "default".to_string());
+        let mut last = &mut self.start;
+        for step in &self.path {
+            last = last
+                .successors_mut()
+                .get_mut(step)
+                .expect("the actions of the chain's path lead to its nodes");
+        }
+        if last.successors().contains_key(&action) {
+            log::warn!(
+                "Warning: Action {} was found in successors, Overwriting key {}.",
+                &action,
+                &action
+            );
+        }
+        last.successors_mut().insert(action.clone(), next.start);
+
+        self.path.push(action);
+        self.path.extend(next.path);
+        self.pending = next.pending;
+        self
+    }
+}
+
+impl<S> From<Chain<S>> for Executable<S> {
+    fn from(chain: Chain<S>) -> Self {
+        if let Some(action) = chain.pending {
+            panic!(
+                "The chain ends with action \"{}\" but no node follows it (`- \"{}\" >> node`)",
+                action, action
+            );
+        }
+        chain.start
+    }
+}
+
+impl<S, R: Into<Chain<S>>> Shr<R> for Chain<S> {
+    type Output = Chain<S>;
+
+    fn shr(self, next: R) -> Chain<S> {
+        self.wire(next.into())
+    }
+}
+
+impl<S> Sub<&str> for Chain<S> {
+    type Output = Chain<S>;
+
+    fn sub(mut self, action: &str) -> Chain<S> {
+        if let Some(pending) = &self.pending {
+            log::warn!(
+                "Warning: Action {} is not followed by a node, Overwriting it with {}.",
+                pending,
+                action
+            );
+        }
+        self.pending = Some(action.to_string());
+        self
+    }
+}
+
+/// The nodes (and flows) start chains, and can be wired into them
+macro_rules! chainable {
+    ($($node:ident),* $(,)?) => {$(
+        impl<S> From<$node<S>> for Chain<S> {
+            fn from(node: $node<S>) -> Self {
+                Chain::new(node)
+            }
+        }
+
+        impl<S, R: Into<Chain<S>>> Shr<R> for $node<S> {
+            type Output = Chain<S>;
+
+            fn shr(self, next: R) -> Chain<S> {
+                Chain::new(self) >> next
+            }
+        }
+
+        impl<S> Sub<&str> for $node<S> {
+            type Output = Chain<S>;
+
+            fn sub(self, action: &str) -> Chain<S> {
+                Chain::new(self) - action
+            }
+        }
+    )*};
+}
+
+chainable!(
+    Executable,
+    Node,
+    AsyncNode,
+    Flow,
+    AsyncFlow,
+    BatchFlow,
+    AsyncBatchFlow,
+    AsyncParallelBatchFlow,
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::graph::Graph;
+    use crate::core::sync_impl::fn_node::FnLogic;
+
+    fn step(id: &str) -> Node {
+        FnLogic::new().build().with_id(id)
+    }
+
+    #[test]
+    fn operators_wire_onto_the_last_node() {
+        let graph = Graph::from_start((step("a") >> (step("b") - "x") >> step("c")).into());
+        let [a, b, c] = ["a", "b", "c"].map(|id| graph.find(id).unwrap());
+        assert_eq!(graph.successor(a, "default"), Some(b));
+        assert_eq!(graph.successor(b, "x"), Some(c));
+        assert_eq!(graph.successors(c).len(), 0);
+    }
+
+    #[test]
+    fn branch_wires_the_first_node() {
+        let checked = (step("check") - "ok") >> step("save") >> step("log");
+        let chain = (checked.branch() - "retry") >> step("fix");
+        let graph = Graph::from_start(chain.into());
+        let [check, save, fix] = ["check", "save", "fix"].map(|id| graph.find(id).unwrap());
+        assert_eq!(graph.successor(check, "ok"), Some(save));
+        assert_eq!(graph.successor(check, "retry"), Some(fix));
+    }
+
+    #[test]
+    #[should_panic(expected = "no node follows it")]
+    fn a_dangling_action_panics() {
+        let _: Executable = (step("a") - "x").into();
+    }
+
+    #[test]
+    fn branch_drops_a_pending_action() {
+        let chain = (step("a") - "x").branch() >> step("b");
+        let graph = Graph::from_start(chain.into());
+        let [a, b] = ["a", "b"].map(|id| graph.find(id).unwrap());
+        assert_eq!(graph.successor(a, "default"), Some(b));
+        assert_eq!(graph.successor(a, "x"), None);
+    }
+}
@@ -1,5 +1,5 @@
 // The main modules
-mod core;
+pub mod core;
 
 // LLM feature
 #[cfg(feature = "llm")]
@@ -8,3 +8,15 @@ pub mod llm;
 // Re-export commonly used types for convenience
 #[cfg(feature = "llm")]
 pub use llm::{Client, error::LLMError};
+
+// Macros feature
+#[cfg(feature = "macros")]
+pub use orichalcum_macros::{async_node_logic, node_logic};
+
+/// What the code generated by the macros refers to
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub mod __private {
+    pub use async_trait::async_trait;
+    pub use serde_json;
+}